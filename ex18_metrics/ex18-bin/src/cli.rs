@@ -1,4 +1,11 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{Args, Parser, Subcommand};
+
+use ex18_server::rate_limit::Rate;
+use ex18_shared::codec::CodecKind;
+use ex18_shared::compression::CompressionKind;
 
 #[derive(Parser)]
 #[command(about, long_about)]
@@ -12,7 +19,124 @@ pub struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::upper_case_acronyms, clippy::large_enum_variant)]
 pub enum Modes {
-    CLIENT,
-    SERVER,
+    CLIENT {
+        /// PEM file with the CA certificate the server certificate must be signed by, enables TLS
+        #[arg(long, conflicts_with = "tls_pin")]
+        tls_ca: Option<PathBuf>,
+        /// SHA-256 fingerprint of the server certificate in hex, enables TLS
+        #[arg(long)]
+        tls_pin: Option<String>,
+        /// Name the server certificate must be issued for, defaults to the server address
+        #[arg(long)]
+        tls_server_name: Option<String>,
+        /// Encoding of the messages: bincode, json or msgpack
+        #[arg(long, default_value_t = CodecKind::Bincode)]
+        codec: CodecKind,
+        /// Compression of large frames: zstd or deflate
+        #[arg(long)]
+        compression: Option<CompressionKind>,
+    },
+    SERVER {
+        /// Largest frame in bytes a client may send before logging in
+        #[arg(long)]
+        max_login_frame_size: Option<u32>,
+        /// Largest frame in bytes a logged in client may send
+        #[arg(long)]
+        max_frame_size: Option<u32>,
+        /// PEM file with the server certificate chain, enables TLS
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM file with the private key of the server certificate
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Seconds to let sessions and web requests finish after SIGINT or SIGTERM
+        #[arg(long, default_value_t = 10)]
+        shutdown_grace: u64,
+        /// Messages per second and burst a user may send, e.g. 5/20
+        #[arg(long)]
+        user_message_rate: Option<Rate>,
+        /// Messages per second and burst all sessions from one IP address may send
+        #[arg(long)]
+        ip_message_rate: Option<Rate>,
+        /// Bytes per second and burst a user may send
+        #[arg(long)]
+        user_byte_rate: Option<Rate>,
+        /// Bytes per second and burst all sessions from one IP address may send
+        #[arg(long)]
+        ip_byte_rate: Option<Rate>,
+        /// Login and signup attempts per second and burst from one IP address
+        #[arg(long)]
+        login_rate: Option<Rate>,
+        /// Failed logins in a row that lock the IP address out, ten times as many lock the username out
+        #[arg(long)]
+        lockout_after: Option<u32>,
+        /// Seconds a lockout lasts
+        #[arg(long)]
+        lockout: Option<u64>,
+        /// Directory of the uploads passing through the server, created if missing
+        #[arg(long)]
+        upload_dir: Option<PathBuf>,
+        /// Largest file in bytes a user may upload
+        #[arg(long)]
+        max_upload_size: Option<u64>,
+        /// Bytes a user may have in unfinished uploads at once
+        #[arg(long)]
+        max_uploads_in_flight: Option<u64>,
+        /// Messages queued for slow sessions, sessions further behind miss the oldest ones
+        #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        broadcast_queue: Option<usize>,
+        /// Times a session may fall behind the queue within the lag window before it is disconnected
+        #[arg(long)]
+        max_lags: Option<usize>,
+        /// Seconds in which falling behind the queue is counted
+        #[arg(long)]
+        lag_window: Option<u64>,
+        /// Replay the stored messages a session missed after falling behind
+        #[arg(long)]
+        lag_catch_up: bool,
+        /// KiB of memory Argon2id uses to hash a password
+        #[arg(long)]
+        password_memory: Option<u32>,
+        /// Argon2id iterations to hash a password
+        #[arg(long)]
+        password_iterations: Option<u32>,
+        /// Argon2id lanes to hash a password
+        #[arg(long)]
+        password_parallelism: Option<u32>,
+        /// Fewest characters a new password may have
+        #[arg(long)]
+        password_min_length: Option<usize>,
+        /// File with passwords nobody may choose, one per line, replaces the built-in list
+        #[arg(long)]
+        password_deny_list: Option<PathBuf>,
+        #[command(flatten)]
+        database: DatabaseArgs,
+    },
+    /// Database schema of the server, which migrates it to the latest version on start
+    MIGRATE {
+        #[command(flatten)]
+        database: DatabaseArgs,
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Args)]
+pub struct DatabaseArgs {
+    /// Database of the server, sqlite://<file> or postgres://<user>:<password>@<host>/<database>,
+    /// sqlite://server.db by default
+    #[arg(long)]
+    pub database_url: Option<String>,
+    /// TOML file with the database_url setting, the command line option takes precedence
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum MigrateAction {
+    /// Lists the migrations with the time they were applied, or as pending
+    STATUS,
 }
@@ -1,23 +1,39 @@
+use std::fs::read_to_string;
+use std::io;
 use std::io::Write;
 use std::net::{IpAddr, SocketAddr};
 use std::process::exit;
 use std::str::FromStr;
 use std::string::ToString;
+use std::time::Duration;
 
 use anyhow::{Context, Error};
 use clap::Parser;
 use log::LevelFilter::Info;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use rocket::tokio;
+use serde_derive::Deserialize;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::select;
+use tokio::sync::oneshot;
 use tokio::sync::watch::Sender;
+use tokio::time::{timeout_at, Instant};
 
-use ex18_client::client::Client;
-use ex18_server::server::Server;
+use ex18_client::client::{Client, ConnectionOptions};
+use ex18_server::migrations::{database_status, MigrationState};
+use ex18_server::passwords::{PasswordCost, PasswordOptions, PasswordPolicy};
+use ex18_server::rate_limit::RateLimits;
+use ex18_server::server::{FrameLimits, Server};
+use ex18_server::store::DEFAULT_DATABASE_URL;
+use ex18_server::subscription::BroadcastLimits;
+use ex18_server::transfers::TransferLimits;
 use ex18_server::web::serve_web;
 use ex18_shared::message::Message;
+use ex18_shared::tls::{
+    certificate_fingerprint, server_acceptor, ClientTls, ServerTrust, TlsAcceptor,
+};
 
-use crate::cli::{Cli, Modes};
+use crate::cli::{Cli, DatabaseArgs, MigrateAction, Modes};
 
 mod cli;
 
@@ -25,6 +41,12 @@ const DEFAULT_HOST: &str = "0.0.0.0";
 const DEFAULT_PORT: u16 = 11111;
 const DEFAULT_PORT_WEB: u16 = 8080;
 
+/// Settings of the `--config` file
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    database_url: Option<String>,
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::builder()
@@ -50,12 +72,141 @@ async fn main() {
         let socket_addr =
             get_socket_addr(&address, port).context(format!("Invalid address {}", address))?;
         match cli_mode {
-            Modes::CLIENT => client(&socket_addr).await,
-            Modes::SERVER => {
+            Modes::CLIENT {
+                tls_ca,
+                tls_pin,
+                tls_server_name,
+                codec,
+                compression,
+            } => {
+                let trust = match (&tls_ca, &tls_pin) {
+                    (Some(ca_path), _) => Some(ServerTrust::CaFile(ca_path)),
+                    (None, Some(pin)) => Some(ServerTrust::Pin(pin)),
+                    (None, None) => None,
+                };
+                let tls = match trust {
+                    Some(trust) => {
+                        let server_name = tls_server_name.unwrap_or(address.clone());
+                        Some(ClientTls::new(trust, &server_name).context("Invalid TLS settings")?)
+                    }
+                    None => None,
+                };
+                let options = ConnectionOptions {
+                    tls,
+                    codec,
+                    compression,
+                };
+                client(&socket_addr, options).await
+            }
+            Modes::SERVER {
+                max_login_frame_size,
+                max_frame_size,
+                tls_cert,
+                tls_key,
+                shutdown_grace,
+                user_message_rate,
+                ip_message_rate,
+                user_byte_rate,
+                ip_byte_rate,
+                login_rate,
+                lockout_after,
+                lockout,
+                upload_dir,
+                max_upload_size,
+                max_uploads_in_flight,
+                broadcast_queue,
+                max_lags,
+                lag_window,
+                lag_catch_up,
+                password_memory,
+                password_iterations,
+                password_parallelism,
+                password_min_length,
+                password_deny_list,
+                database,
+            } => {
                 let socket_addr_web = get_socket_addr(&address, web_port)
                     .context(format!("Invalid address {}", address))?;
-                server(socket_addr, socket_addr_web).await
+                let default_limits = FrameLimits::default();
+                let frame_limits = FrameLimits {
+                    pre_login: max_login_frame_size.unwrap_or(default_limits.pre_login),
+                    post_login: max_frame_size.unwrap_or(default_limits.post_login),
+                };
+                let default_rates = RateLimits::default();
+                let rate_limits = RateLimits {
+                    user_messages: user_message_rate.unwrap_or(default_rates.user_messages),
+                    ip_messages: ip_message_rate.unwrap_or(default_rates.ip_messages),
+                    user_bytes: user_byte_rate.unwrap_or(default_rates.user_bytes),
+                    ip_bytes: ip_byte_rate.unwrap_or(default_rates.ip_bytes),
+                    ip_logins: login_rate.unwrap_or(default_rates.ip_logins),
+                    lockout_after: lockout_after.unwrap_or(default_rates.lockout_after),
+                    lockout: lockout
+                        .map(Duration::from_secs)
+                        .unwrap_or(default_rates.lockout),
+                };
+                let default_transfers = TransferLimits::default();
+                let transfer_limits = TransferLimits {
+                    upload_dir: upload_dir.unwrap_or(default_transfers.upload_dir),
+                    max_size: max_upload_size.unwrap_or(default_transfers.max_size),
+                    max_in_flight: max_uploads_in_flight.unwrap_or(default_transfers.max_in_flight),
+                };
+                let default_broadcast = BroadcastLimits::default();
+                let broadcast_limits = BroadcastLimits {
+                    capacity: broadcast_queue.unwrap_or(default_broadcast.capacity),
+                    max_lags: max_lags.unwrap_or(default_broadcast.max_lags),
+                    lag_window: lag_window
+                        .map(Duration::from_secs)
+                        .unwrap_or(default_broadcast.lag_window),
+                    catch_up: lag_catch_up,
+                };
+                let default_cost = PasswordCost::default();
+                let default_policy = PasswordPolicy::default();
+                let passwords = PasswordOptions {
+                    cost: PasswordCost {
+                        memory_kib: password_memory.unwrap_or(default_cost.memory_kib),
+                        iterations: password_iterations.unwrap_or(default_cost.iterations),
+                        parallelism: password_parallelism.unwrap_or(default_cost.parallelism),
+                    },
+                    policy: PasswordPolicy {
+                        min_length: password_min_length.unwrap_or(default_policy.min_length),
+                        deny_list: match password_deny_list {
+                            Some(path) => PasswordPolicy::read_deny_list(&path)
+                                .context(format!("Cannot read {}", path.display()))?,
+                            None => default_policy.deny_list,
+                        },
+                    },
+                };
+                let database_url = database_url(database)?;
+                let tls_acceptor = match (tls_cert, tls_key) {
+                    (Some(cert_path), Some(key_path)) => {
+                        let fingerprint = certificate_fingerprint(&cert_path)
+                            .context(format!("Invalid certificate {}", cert_path.display()))?;
+                        info!("TLS enabled, certificate fingerprint {}", fingerprint);
+                        Some(
+                            server_acceptor(&cert_path, &key_path)
+                                .context("Invalid TLS certificate or key")?,
+                        )
+                    }
+                    _ => None,
+                };
+                server(
+                    socket_addr,
+                    socket_addr_web,
+                    frame_limits,
+                    rate_limits,
+                    broadcast_limits,
+                    transfer_limits,
+                    passwords,
+                    database_url,
+                    tls_acceptor,
+                    Duration::from_secs(shutdown_grace),
+                )
+                .await
             }
+            Modes::MIGRATE {
+                database,
+                action: MigrateAction::STATUS,
+            } => migration_status(&database_url(database)?).await,
         }
     };
     if let Err(err) = exec_fn(cli.mode).await {
@@ -64,36 +215,145 @@ async fn main() {
     }
 }
 
+/// The command line option, or the setting of the config file, or the default
+fn database_url(database: DatabaseArgs) -> Result<String, Error> {
+    let config = match &database.config {
+        Some(path) => {
+            let content =
+                read_to_string(path).context(format!("Cannot read {}", path.display()))?;
+            toml::from_str(&content).context(format!("Invalid config file {}", path.display()))?
+        }
+        None => ConfigFile::default(),
+    };
+    Ok(database
+        .database_url
+        .or(config.database_url)
+        .unwrap_or(DEFAULT_DATABASE_URL.to_string()))
+}
+
 fn get_socket_addr(ip_addr_str: &str, port: u16) -> Result<SocketAddr, Error> {
     let ip_addr = IpAddr::from_str(ip_addr_str)?;
     Ok(SocketAddr::new(ip_addr, port))
 }
 
-async fn server(chat_listen_addr: SocketAddr, web_listen_addr: SocketAddr) -> Result<(), Error> {
+#[allow(clippy::too_many_arguments)]
+async fn server(
+    chat_listen_addr: SocketAddr,
+    web_listen_addr: SocketAddr,
+    frame_limits: FrameLimits,
+    rate_limits: RateLimits,
+    broadcast_limits: BroadcastLimits,
+    transfer_limits: TransferLimits,
+    passwords: PasswordOptions,
+    database_url: String,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown_grace: Duration,
+) -> Result<(), Error> {
+    let server = Server::new(
+        chat_listen_addr,
+        frame_limits,
+        rate_limits,
+        broadcast_limits,
+        transfer_limits,
+        passwords,
+        database_url,
+        tls_acceptor,
+    )
+    .await?;
+    let hub = server.hub();
     tokio::spawn(async move {
-        return Server::new(chat_listen_addr)
-            .await?
+        server
             .listen()
             .await
-            .context(format!("Listening on address {} failed", chat_listen_addr));
+            .context(format!("Listening on address {} failed", chat_listen_addr))
     });
-    let web_server_handle = tokio::spawn(async move {
-        return serve_web(web_listen_addr).await.context("Web server error");
+    let (web_shutdown_tx, web_shutdown_rx) = oneshot::channel();
+    let web_hub = hub.clone();
+    let mut web_server_handle = tokio::spawn(async move {
+        let web_shutdown = async {
+            let _ = web_shutdown_rx.await;
+        };
+        serve_web(web_listen_addr, web_hub, shutdown_grace, web_shutdown)
+            .await
+            .context("Web server error")
     });
 
-    tokio::try_join!(web_server_handle)
-        .map(|_| ())
-        .context("Web server failed")
+    select! {
+        signal = shutdown_signal() => signal.context("Cannot listen for signals")?,
+        web_server = &mut web_server_handle => {
+            return web_server.context("Web server failed")?;
+        }
+    }
+
+    // Chat sessions go first, they use both the web server and the database
+    info!("Shutting down, waiting up to {:?}", shutdown_grace);
+    let deadline = Instant::now() + shutdown_grace;
+    hub.shut_down();
+    if timeout_at(deadline, hub.sessions_ended()).await.is_err() {
+        warn!("Chat sessions did not end within the grace period");
+    }
+    let _ = web_shutdown_tx.send(());
+    match timeout_at(deadline, web_server_handle).await {
+        Ok(web_server) => web_server.context("Web server failed")??,
+        Err(_) => warn!("Web server did not stop within the grace period"),
+    }
+    if timeout_at(deadline, hub.close_database()).await.is_err() {
+        warn!("Database queries did not finish within the grace period");
+    }
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Completes on SIGINT, or SIGTERM on unix
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        select! {
+            ctrl_c = tokio::signal::ctrl_c() => ctrl_c,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+async fn migration_status(database_url: &str) -> Result<(), Error> {
+    let status = database_status(database_url)
+        .await
+        .map_err(|err| Error::msg(format!("Cannot read the database schema: {}", err)))?;
+    for migration_status in status {
+        let state = match migration_status.state {
+            MigrationState::Applied(applied_at) => chrono::DateTime::from_timestamp(applied_at, 0)
+                .map(|time| {
+                    format!(
+                        "applied {}",
+                        time.with_timezone(&chrono::Local)
+                            .format("%Y-%m-%d %H:%M:%S")
+                    )
+                })
+                .unwrap_or("applied".to_string()),
+            MigrationState::Unrecorded => "applied, recorded at the next start".to_string(),
+            MigrationState::Pending => "pending".to_string(),
+        };
+        println!(
+            "{:>3} {:<30} {}",
+            migration_status.migration.version, migration_status.migration.description, state
+        );
+    }
+    Ok(())
 }
 
-async fn client(socket_addr: &SocketAddr) -> Result<(), Error> {
+async fn client(socket_addr: &SocketAddr, options: ConnectionOptions) -> Result<(), Error> {
     let (tx, rx) = tokio::sync::watch::channel(None);
 
     tokio::spawn(async {
         client_stdin_reader(tx).await.unwrap();
     });
 
-    let mut client = Client::new(socket_addr, rx).await?;
+    let mut client = Client::new(socket_addr, rx, options).await?;
     client.process_messages().await?;
     Ok(())
 }
@@ -1,48 +1,104 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::info;
+use chrono::{DateTime, Local};
+use log::{info, warn};
 use rocket::tokio;
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio::sync::watch::Receiver;
 use tokio::{fs, select};
 
-use ex18_shared::message::Message;
-use ex18_shared::message_tcp_stream::{MessageTcpStream, MessageTcpStreamError};
+use ex18_shared::codec::CodecKind;
+use ex18_shared::compression::CompressionKind;
+use ex18_shared::message::{Envelope, Message};
+use ex18_shared::message_tcp_stream::{BoxedStream, MessageTcpStream, MessageTcpStreamError};
+use ex18_shared::tls::ClientTls;
+use ex18_shared::transfer::{
+    IncomingTransfer, OutgoingTransfer, Transfer, TransferError, TransferKind,
+};
 
 use crate::client::ClientError::{
     ConnectError, IllegalArgumentError, IncorrectTransmitByteCountError,
 };
 
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
 pub struct Client {
-    message_stream: MessageTcpStream<Message>,
+    socket_addr: SocketAddr,
+    options: ConnectionOptions,
+    message_stream: MessageTcpStream<Message, BoxedStream>,
     stdin_input_rx: Receiver<Option<Message>>,
+    /// Login and password of the last successful login, used to log in again after a reconnect
+    credentials: Option<(String, String)>,
+    uploads: VecDeque<Upload>,
+    downloads: HashMap<String, IncomingTransfer>,
+}
+
+/// How the client talks to the server, kept to reconnect the same way
+#[derive(Clone, Default)]
+pub struct ConnectionOptions {
+    pub tls: Option<ClientTls>,
+    pub codec: CodecKind,
+    pub compression: Option<CompressionKind>,
+}
+
+struct Upload {
+    transfer: OutgoingTransfer,
+    /// Offset the server confirmed to have stored, `None` until it confirms the transfer start
+    acknowledged: Option<u64>,
+    state: UploadState,
+}
+
+#[derive(PartialEq)]
+enum UploadState {
+    Sending,
+    /// Waiting for the server to tell where to continue after a reconnect
+    AwaitingResume,
+    /// Everything is sent, waiting for the server to confirm the checksum
+    AwaitingEnd,
 }
 
 impl Client {
     pub async fn new(
         socket_addr: &SocketAddr,
         stdin_input_rx: Receiver<Option<Message>>,
+        options: ConnectionOptions,
     ) -> Result<Client, ClientError> {
         fs::create_dir_all("files").await?;
         fs::create_dir_all("images").await?;
         info!("Connecting to {}", socket_addr);
         Ok(Client {
-            message_stream: MessageTcpStream::from_tcp_stream(
-                TcpStream::connect(socket_addr)
-                    .await
-                    .map_err(|_| ConnectError(socket_addr.clone()))?,
-            )?,
+            socket_addr: *socket_addr,
+            message_stream: Client::connect(socket_addr, &options).await?,
+            options,
             stdin_input_rx,
+            credentials: None,
+            uploads: VecDeque::new(),
+            downloads: HashMap::new(),
         })
     }
 
+    /// Processes messages until the user quits. Reconnects if the connection to the server drops.
     pub async fn process_messages(&mut self) -> Result<(), ClientError> {
+        loop {
+            match self.process_messages_until_disconnected().await {
+                Err(ClientError::TcpStreamError(err)) if Client::is_connection_lost(&err) => {
+                    eprintln!("Connection lost: {}", err);
+                    self.reconnect().await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn process_messages_until_disconnected(&mut self) -> Result<(), ClientError> {
         loop {
             select! {
                 stdin_event = self.stdin_input_rx.changed() => {
@@ -52,7 +108,23 @@ impl Client {
                     let message_ref = self.stdin_input_rx.borrow_and_update();
                     match message_ref.deref() {
                         Some(Message::Quit) => {return Ok(());},
+                        Some(Message::Upload(kind, path)) => {
+                            let (kind, path) = (*kind, path.clone());
+                            drop(message_ref);
+                            self.start_upload(kind, &path).await;
+                        },
                         Some(message) => {
+                            match message {
+                                Message::Login(login, password) | Message::Signup(login, password) => {
+                                    self.credentials = Some((login.clone(), password.clone()));
+                                }
+                                Message::Passwd(password) => {
+                                    if let Some(credentials) = self.credentials.as_mut() {
+                                        credentials.1 = password.clone();
+                                    }
+                                }
+                                _ => {}
+                            }
                             self.message_stream.send_message(message).await?;
                         },
                         None => {},
@@ -61,53 +133,364 @@ impl Client {
                 server_event = self.message_stream.read_next_message() => {
                     match server_event {
                         Ok(Some(message)) => {
-                            Client::process_message(&message)?;
+                            self.process_message(message).await?;
                         }
                         Err(err) => { return Err(ClientError::from(err));}
                         _ => {}
                     }
                 }
+                // Sends one frame of an upload per iteration so that chat traffic stays interleaved
+                _ = async {}, if self.uploads.iter().any(|upload| upload.state == UploadState::Sending) => {
+                    self.send_next_upload_frame().await?;
+                }
+            }
+        }
+    }
+
+    async fn connect(
+        socket_addr: &SocketAddr,
+        options: &ConnectionOptions,
+    ) -> Result<MessageTcpStream<Message, BoxedStream>, ClientError> {
+        let tcp_stream = TcpStream::connect(socket_addr)
+            .await
+            .map_err(|_| ConnectError(*socket_addr))?;
+        let stream: BoxedStream = match &options.tls {
+            Some(tls) => Box::new(tls.connect(tcp_stream).await?),
+            None => Box::new(tcp_stream),
+        };
+        let mut message_stream = MessageTcpStream::from_stream(stream)?;
+        let mut features = vec![options.codec.feature()];
+        features.extend(options.compression.map(|compression| compression.feature()));
+        message_stream.handshake_as_client(&features).await?;
+        if message_stream.codec() != options.codec {
+            warn!(
+                "Server does not support codec {}, using {}",
+                options.codec,
+                message_stream.codec()
+            );
+        }
+        if message_stream.compression() != options.compression {
+            warn!("Server does not support compression, sending frames uncompressed");
+        }
+        Ok(message_stream)
+    }
+
+    fn is_connection_lost(err: &MessageTcpStreamError) -> bool {
+        matches!(
+            err,
+            MessageTcpStreamError::IOError(_)
+                | MessageTcpStreamError::ConnectionClosed
+                | MessageTcpStreamError::IncorrectTransmitByteCountError(_, _)
+        )
+    }
+
+    async fn reconnect(&mut self) -> Result<(), ClientError> {
+        let mut delay = RECONNECT_DELAY;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            info!("Reconnecting to {}, attempt {}", self.socket_addr, attempt);
+            match Client::connect(&self.socket_addr, &self.options).await {
+                Ok(message_stream) => {
+                    self.message_stream = message_stream;
+                    return self.restore_session().await;
+                }
+                Err(err) => {
+                    eprintln!("Reconnect failed: {}", err);
+                    delay *= 2;
+                }
             }
         }
+        Err(ConnectError(self.socket_addr))
     }
 
-    fn process_message(message: &Message) -> Result<(), ClientError> {
+    /// Logs in again and asks the server to resume unfinished transfers
+    async fn restore_session(&mut self) -> Result<(), ClientError> {
+        let Some((login, password)) = self.credentials.clone() else {
+            return Ok(());
+        };
+        self.message_stream
+            .send_message(&Message::Login(login, password))
+            .await?;
+        for upload in self.uploads.iter_mut() {
+            if upload.acknowledged.is_none() {
+                upload.transfer.restart().await?;
+                upload.state = UploadState::Sending;
+            } else {
+                upload.state = UploadState::AwaitingResume;
+                self.message_stream
+                    .send_message(&Message::Transfer(Transfer::ResumeUpload {
+                        id: upload.transfer.id().to_string(),
+                    }))
+                    .await?;
+            }
+        }
+        for (id, download) in self.downloads.iter() {
+            self.message_stream
+                .send_message(&Message::Transfer(Transfer::ResumeDownload {
+                    id: id.clone(),
+                    offset: download.received(),
+                }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn process_message(&mut self, message: Message) -> Result<(), ClientError> {
         match message {
-            Message::File(_, _) | Message::Image(_) => Client::save_file(message),
+            Message::File(_, _) | Message::Image(_) => Client::save_file(&message, None),
+            Message::Transfer(transfer) => {
+                self.process_transfer(transfer, None).await;
+                Ok(())
+            }
             Message::Text(ref text) => {
                 println!("{}", text);
                 Ok(())
             }
+            Message::Envelope(envelope) => self.process_envelope(envelope).await,
+            Message::Mention(envelope) => {
+                Client::process_mention(&envelope);
+                Ok(())
+            }
             _ => Err(IllegalArgumentError("Unknown message type".to_string())),
         }
     }
 
-    fn save_file(message: &Message) -> Result<(), ClientError> {
-        let path_str = match message {
-            Message::File(file_path, _) => Ok(format!(
-                "files/{}",
-                Client::get_file_name_from_path(file_path)?
-            )),
-            Message::Image(_) => {
+    /// Rings the terminal bell and shows the message in reverse video
+    fn process_mention(envelope: &Envelope) {
+        let Message::Text(ref text) = *envelope.message else {
+            return;
+        };
+        let place = match &envelope.room {
+            Some(room) => format!(" in #{}", room),
+            None => String::new(),
+        };
+        println!(
+            "\x07\x1b[7m[{}] {} mentioned you{}: {} ({})\x1b[0m",
+            Client::format_sent_at(envelope),
+            envelope.sender,
+            place,
+            text,
+            envelope.short_id()
+        );
+    }
+
+    fn format_sent_at(envelope: &Envelope) -> String {
+        DateTime::from_timestamp(envelope.sent_at, 0)
+            .map(|sent_at| sent_at.with_timezone(&Local).format("%H:%M").to_string())
+            .unwrap_or_default()
+    }
+
+    /// Shows or saves a message another user sent, attributed to them
+    async fn process_envelope(&mut self, envelope: Envelope) -> Result<(), ClientError> {
+        let sender = envelope.sender.as_str();
+        let sent_at = Client::format_sent_at(&envelope);
+        let id = envelope.short_id();
+        match *envelope.message {
+            Message::Text(ref text) => {
+                match envelope.room {
+                    Some(_) => println!("[{}] {}: {} ({})", sent_at, sender, text, id),
+                    None => println!("[{}] {} (private): {} ({})", sent_at, sender, text, id),
+                }
+                Ok(())
+            }
+            Message::Edit(_, ref text) => {
+                println!("[{}] {}: {} ({}, edited)", sent_at, sender, text, id);
+                Ok(())
+            }
+            Message::Delete(_) => {
+                println!("[{}] {} deleted message {}", sent_at, sender, id);
+                Ok(())
+            }
+            Message::File(_, _) | Message::Image(_) => {
+                Client::save_file(&envelope.message, Some(sender))
+            }
+            Message::Transfer(transfer) => {
+                self.process_transfer(transfer, Some(sender)).await;
+                Ok(())
+            }
+            _ => Err(IllegalArgumentError("Unknown message type".to_string())),
+        }
+    }
+
+    async fn start_upload(&mut self, kind: TransferKind, path: &str) {
+        match OutgoingTransfer::open(path, kind).await {
+            Ok(transfer) => self.uploads.push_back(Upload {
+                transfer,
+                acknowledged: None,
+                state: UploadState::Sending,
+            }),
+            Err(err) => eprintln!("Cannot send {}: {}", path, err),
+        }
+    }
+
+    async fn send_next_upload_frame(&mut self) -> Result<(), ClientError> {
+        let Some(position) = self
+            .uploads
+            .iter()
+            .position(|upload| upload.state == UploadState::Sending)
+        else {
+            return Ok(());
+        };
+        let mut upload = self.uploads.remove(position).unwrap();
+        let transfer = match upload.transfer.next_message().await {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => {
+                upload.state = UploadState::AwaitingEnd;
+                self.uploads.push_back(upload);
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("Cannot send {}: {}", upload.transfer.name(), err);
+                Transfer::Abort {
+                    id: upload.transfer.id().to_string(),
+                    reason: err.to_string(),
+                }
+            }
+        };
+        let is_abort = matches!(transfer, Transfer::Abort { .. });
+        self.message_stream
+            .send_message(&Message::Transfer(transfer))
+            .await?;
+        if !is_abort {
+            self.uploads.push_back(upload);
+        }
+        Ok(())
+    }
+
+    /// Handles server's answers to our uploads and stores incoming chunks on disk.
+    /// Problems with a single transfer are reported and the transfer is dropped,
+    /// they don't end the session.
+    async fn process_transfer(&mut self, transfer: Transfer, sender: Option<&str>) {
+        if let Some(position) = self
+            .uploads
+            .iter()
+            .position(|upload| upload.transfer.id() == transfer.id())
+        {
+            self.process_upload_reply(position, transfer).await;
+            return;
+        }
+        match transfer {
+            Transfer::Start {
+                id,
+                kind,
+                name,
+                size,
+            } => {
+                let path = match Client::get_download_path(kind, &name, sender) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                };
+                match IncomingTransfer::create(path, kind, &name, size).await {
+                    Ok(download) => {
+                        self.downloads.insert(id, download);
+                    }
+                    Err(err) => eprintln!("Cannot receive {}: {}", name, err),
+                }
+            }
+            Transfer::Chunk { id, offset, data } => {
+                if let Some(download) = self.downloads.get_mut(&id) {
+                    if let Err(err) = download.write_chunk(offset, &data).await {
+                        eprintln!("Cannot receive {}: {}", download.name(), err);
+                        if let Some(download) = self.downloads.remove(&id) {
+                            download.abort().await;
+                        }
+                    }
+                }
+            }
+            Transfer::End { id, sha256 } => {
+                if let Some(download) = self.downloads.remove(&id) {
+                    let name = download.name().to_string();
+                    match download.finish(&sha256).await {
+                        Ok(path) => println!("Received {}", path.display()),
+                        Err(err) => eprintln!("Cannot receive {}: {}", name, err),
+                    }
+                }
+            }
+            Transfer::Abort { id, reason } => {
+                if let Some(download) = self.downloads.remove(&id) {
+                    eprintln!("Transfer of {} aborted: {}", download.name(), reason);
+                    download.abort().await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn process_upload_reply(&mut self, position: usize, transfer: Transfer) {
+        let upload = &mut self.uploads[position];
+        match transfer {
+            Transfer::Ack { offset, .. } => {
+                upload.acknowledged = Some(offset);
+                if upload.state == UploadState::AwaitingResume {
+                    match upload.transfer.resume_from(offset).await {
+                        Ok(()) => upload.state = UploadState::Sending,
+                        Err(err) => {
+                            eprintln!("Cannot resume {}: {}", upload.transfer.name(), err);
+                            self.uploads.remove(position);
+                        }
+                    }
+                }
+            }
+            Transfer::End { .. } => {
+                println!("Sent {}", upload.transfer.name());
+                self.uploads.remove(position);
+            }
+            Transfer::Abort { reason, .. } => {
+                eprintln!("Sending {} failed: {}", upload.transfer.name(), reason);
+                self.uploads.remove(position);
+            }
+            _ => {}
+        }
+    }
+
+    /// Files are saved to `files/<sender>/<name>`, images to `images/<sender>/<millis>`
+    fn get_download_path(
+        kind: TransferKind,
+        name: &str,
+        sender: Option<&str>,
+    ) -> Result<PathBuf, ClientError> {
+        let mut path = PathBuf::from(match kind {
+            TransferKind::File => "files",
+            TransferKind::Image => "images",
+        });
+        if let Some(sender) = sender {
+            path.push(Client::get_file_name_from_path(sender)?);
+            std::fs::create_dir_all(&path)?;
+        }
+        match kind {
+            TransferKind::File => path.push(Client::get_file_name_from_path(name)?),
+            TransferKind::Image => {
                 let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                Ok(format!("images/{}", duration.as_millis()))
-            }
-            _ => Err(IllegalArgumentError(
-                "Cannot save this message type as file".to_string(),
-            )),
-        }?;
-        let content = match message {
-            Message::File(_, vec) => Ok(vec),
-            Message::Image(vec) => Ok(vec),
-            _ => Err(IllegalArgumentError(
-                "Cannot save this message type as file".to_string(),
-            )),
-        }?;
+                path.push(duration.as_millis().to_string());
+            }
+        }
+        Ok(path)
+    }
+
+    fn save_file(message: &Message, sender: Option<&str>) -> Result<(), ClientError> {
+        let (path, content) = match message {
+            Message::File(file_path, vec) => (
+                Client::get_download_path(TransferKind::File, file_path, sender)?,
+                vec,
+            ),
+            Message::Image(vec) => (
+                Client::get_download_path(TransferKind::Image, "", sender)?,
+                vec,
+            ),
+            _ => {
+                return Err(IllegalArgumentError(
+                    "Cannot save this message type as file".to_string(),
+                ))
+            }
+        };
         let mut file = File::options()
             .write(true)
             .truncate(true)
             .create(true)
-            .open(path_str)?;
+            .open(path)?;
         let bytes_written = file.write(content)?;
         if bytes_written == content.len() {
             Ok(())
@@ -139,6 +522,8 @@ pub enum ClientError {
     IOError(#[from] std::io::Error),
     #[error(transparent)]
     TcpStreamError(#[from] MessageTcpStreamError),
+    #[error(transparent)]
+    TransferError(#[from] TransferError),
     #[error("Invalid filesystem path {0}")]
     InvalidFsPathError(Box<Path>),
     #[error("{0}")]
@@ -0,0 +1,51 @@
+use std::future::Future;
+
+use rocket::tokio::io::{AsyncRead, AsyncWrite};
+
+use ex18_shared::message::Message;
+use ex18_shared::message_tcp_stream::MessageTcpStream;
+
+use crate::server::ServerError;
+
+/// Connection of a single chat client, the TCP protocol or a browser WebSocket
+pub(crate) trait ChatConnection: Send {
+    /// Waits for the next message from the client. Must be cancel safe.
+    fn read_next_message(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<Message>, ServerError>> + Send;
+
+    fn send_message(
+        &mut self,
+        message: &Message,
+    ) -> impl Future<Output = Result<(), ServerError>> + Send;
+
+    /// Sets the largest message the client is allowed to send
+    fn set_max_frame_size(&mut self, max_frame_size: u32);
+
+    /// Bytes compression saved since the last call, see `MessageTcpStream`
+    fn take_compression_savings(&mut self) -> u64 {
+        0
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ChatConnection for MessageTcpStream<Message, S> {
+    async fn read_next_message(&mut self) -> Result<Option<Message>, ServerError> {
+        MessageTcpStream::read_next_message(self)
+            .await
+            .map_err(ServerError::from)
+    }
+
+    async fn send_message(&mut self, message: &Message) -> Result<(), ServerError> {
+        MessageTcpStream::send_message(self, message)
+            .await
+            .map_err(ServerError::from)
+    }
+
+    fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        MessageTcpStream::set_max_frame_size(self, max_frame_size)
+    }
+
+    fn take_compression_savings(&mut self) -> u64 {
+        MessageTcpStream::take_compression_savings(self)
+    }
+}
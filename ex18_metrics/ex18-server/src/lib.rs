@@ -1,5 +1,14 @@
+mod connection;
 mod metrics;
+pub mod migrations;
+pub mod passwords;
+mod presence;
+pub mod rate_limit;
 pub mod server;
+pub mod store;
+pub mod subscription;
+pub mod transfers;
 mod users;
 pub mod web;
+mod web_socket;
 mod web_user;
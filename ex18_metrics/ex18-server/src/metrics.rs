@@ -2,7 +2,10 @@ use std::error::Error;
 use std::sync::OnceLock;
 use std::time::Duration;
 
-use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
 
 static INSTANCE: OnceLock<Metrics> = OnceLock::new();
 
@@ -12,6 +15,10 @@ pub struct Metrics {
     chat_messages_count: IntCounter,
     connected_users_count: IntGauge,
     sql_query_duration_histo: Histogram,
+    compression_saved_bytes: IntCounter,
+    throttled_count: IntCounterVec,
+    lagged_messages_count: IntCounter,
+    slow_sessions_count: IntCounter,
 }
 
 impl Metrics {
@@ -37,6 +44,29 @@ impl Metrics {
                     "How many ms sql queries took",
                 ))
                 .unwrap(),
+                compression_saved_bytes: IntCounter::new(
+                    "compression_saved_bytes",
+                    "Bytes not transmitted thanks to frame compression, sent and received",
+                )
+                .unwrap(),
+                throttled_count: IntCounterVec::new(
+                    Opts::new(
+                        "throttled_events",
+                        "Messages and logins refused or slowed down by rate limits",
+                    ),
+                    &["limit"],
+                )
+                .unwrap(),
+                lagged_messages_count: IntCounter::new(
+                    "lagged_messages",
+                    "Broadcast messages sessions missed because they fell behind",
+                )
+                .unwrap(),
+                slow_sessions_count: IntCounter::new(
+                    "slow_sessions_disconnected",
+                    "Sessions disconnected for falling behind the broadcast queue too often",
+                )
+                .unwrap(),
             };
             instance
                 .registry
@@ -51,6 +81,22 @@ impl Metrics {
                 .register(Box::new(instance.sql_query_duration_histo.clone()))
                 .unwrap();
             instance
+                .registry
+                .register(Box::new(instance.compression_saved_bytes.clone()))
+                .unwrap();
+            instance
+                .registry
+                .register(Box::new(instance.throttled_count.clone()))
+                .unwrap();
+            instance
+                .registry
+                .register(Box::new(instance.lagged_messages_count.clone()))
+                .unwrap();
+            instance
+                .registry
+                .register(Box::new(instance.slow_sessions_count.clone()))
+                .unwrap();
+            instance
         })
     }
 
@@ -71,6 +117,22 @@ impl Metrics {
             .observe(dur.as_secs_f64() * 100f64)
     }
 
+    pub fn track_compression_savings(&self, bytes: u64) {
+        self.compression_saved_bytes.inc_by(bytes)
+    }
+
+    pub fn track_throttled(&self, limit: &str) {
+        self.throttled_count.with_label_values(&[limit]).inc()
+    }
+
+    pub fn track_lagged(&self, missed: u64) {
+        self.lagged_messages_count.inc_by(missed)
+    }
+
+    pub fn track_slow_session_disconnected(&self) {
+        self.slow_sessions_count.inc()
+    }
+
     pub fn export(&self) -> Result<String, Box<dyn Error>> {
         let mut buffer = Vec::new();
         let mut families = self.registry.gather();
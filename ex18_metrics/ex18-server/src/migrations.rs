@@ -0,0 +1,630 @@
+use log::info;
+use thiserror::Error;
+
+use crate::store::{open_store, Store};
+
+/// Schema change applied once, in the order of versions, and recorded in `schema_version`
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub(crate) sqlite: &'static [&'static str],
+    pub(crate) postgres: &'static [&'static str],
+    /// Tells whether a SQLite database created before versions were recorded already has the change
+    pub(crate) marker: Marker,
+}
+
+pub(crate) enum Marker {
+    Table(&'static str),
+    Column(&'static str, &'static str),
+}
+
+/// Never change a migration released already, add a new one instead
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "users and messages",
+        sqlite: &[
+            r##"
+            create table main.users (
+            id       TEXT            not null
+                constraint users_pk
+                    primary key,
+            name     TEXT,
+            active   INTEGER,
+            admin    INTEGER default 0,
+            password TEXT not null,
+            salt     TEXT not null
+        );
+        "##,
+            "create unique index uq_users_name ON users (name);",
+            r##"
+            create table main.user_messages (
+            id              TEXT    not null
+                constraint user_messages_pk
+                    primary key,
+            author_id       TEXT    not null,
+            message         TEXT    not null,
+            sent_at_instant INTEGER not null,
+            foreign key (author_id) REFERENCES users (id)
+        );
+        "##,
+            "create index idx_user_messages_author_id on user_messages (author_id);",
+            "create index idx_user_messages_sent_at on user_messages (sent_at_instant desc);",
+        ],
+        postgres: &[
+            r##"
+            create table users (
+            id       TEXT            not null
+                constraint users_pk
+                    primary key,
+            name     TEXT,
+            active   BOOLEAN,
+            admin    BOOLEAN default false,
+            password TEXT not null,
+            salt     TEXT not null
+        );
+        "##,
+            "create unique index uq_users_name ON users (name);",
+            r##"
+            create table user_messages (
+            id              TEXT      not null
+                constraint user_messages_pk
+                    primary key,
+            seq             BIGSERIAL not null,
+            author_id       TEXT      not null,
+            message         TEXT      not null,
+            sent_at_instant BIGINT    not null,
+            foreign key (author_id) REFERENCES users (id)
+        );
+        "##,
+            "create index idx_user_messages_author_id on user_messages (author_id);",
+            "create index idx_user_messages_sent_at on user_messages (sent_at_instant desc);",
+        ],
+        marker: Marker::Table("users"),
+    },
+    Migration {
+        version: 2,
+        description: "rooms",
+        sqlite: &[
+            "alter table user_messages add column room TEXT;",
+            "update user_messages set room = 'general';",
+            r##"
+            create table main.room_members (
+            user_id   TEXT    not null,
+            room      TEXT    not null,
+            joined_at INTEGER not null,
+            primary key (user_id, room),
+            foreign key (user_id) REFERENCES users (id)
+        );
+        "##,
+            "create index idx_room_members_room on room_members (room);",
+            "insert into room_members(user_id, room, joined_at) select id, 'general', cast(strftime('%s', 'now') as INTEGER) from users;",
+        ],
+        postgres: &[
+            "alter table user_messages add column room TEXT;",
+            "update user_messages set room = 'general';",
+            r##"
+            create table room_members (
+            user_id   TEXT      not null,
+            room      TEXT      not null,
+            joined_at BIGINT    not null,
+            seq       BIGSERIAL not null,
+            primary key (user_id, room),
+            foreign key (user_id) REFERENCES users (id)
+        );
+        "##,
+            "create index idx_room_members_room on room_members (room);",
+            "insert into room_members(user_id, room, joined_at) select id, 'general', extract(epoch from now())::bigint from users;",
+        ],
+        marker: Marker::Table("room_members"),
+    },
+    Migration {
+        version: 3,
+        description: "direct messages",
+        sqlite: &[
+            "alter table user_messages add column recipient_id TEXT REFERENCES users (id);",
+            "create index idx_user_messages_recipient_id on user_messages (recipient_id);",
+        ],
+        postgres: &[
+            "alter table user_messages add column recipient_id TEXT REFERENCES users (id);",
+            "create index idx_user_messages_recipient_id on user_messages (recipient_id);",
+        ],
+        marker: Marker::Column("user_messages", "recipient_id"),
+    },
+    Migration {
+        version: 4,
+        description: "last logout of users",
+        sqlite: &["alter table users add column last_logout_at INTEGER;"],
+        postgres: &["alter table users add column last_logout_at BIGINT;"],
+        marker: Marker::Column("users", "last_logout_at"),
+    },
+    Migration {
+        version: 5,
+        description: "bans and mutes",
+        sqlite: &[r##"
+            create table main.sanctions (
+            user_id    TEXT    not null,
+            kind       TEXT    not null,
+            expires_at INTEGER,
+            primary key (user_id, kind),
+            foreign key (user_id) REFERENCES users (id)
+        );
+        "##],
+        postgres: &[r##"
+            create table sanctions (
+            user_id    TEXT   not null,
+            kind       TEXT   not null,
+            expires_at BIGINT,
+            primary key (user_id, kind),
+            foreign key (user_id) REFERENCES users (id)
+        );
+        "##],
+        marker: Marker::Table("sanctions"),
+    },
+    Migration {
+        version: 6,
+        description: "edited messages",
+        sqlite: &["alter table user_messages add column edited_at INTEGER;"],
+        postgres: &["alter table user_messages add column edited_at BIGINT;"],
+        marker: Marker::Column("user_messages", "edited_at"),
+    },
+    Migration {
+        version: 7,
+        description: "full-text search of messages",
+        sqlite: &[
+            "create virtual table user_messages_fts using fts5(message, content='user_messages', content_rowid='rowid');",
+            r##"
+            create trigger user_messages_fts_insert after insert on user_messages begin
+                insert into user_messages_fts(rowid, message) values (new.rowid, new.message);
+            end;
+        "##,
+            r##"
+            create trigger user_messages_fts_delete after delete on user_messages begin
+                insert into user_messages_fts(user_messages_fts, rowid, message) values ('delete', old.rowid, old.message);
+            end;
+        "##,
+            r##"
+            create trigger user_messages_fts_update after update of message on user_messages begin
+                insert into user_messages_fts(user_messages_fts, rowid, message) values ('delete', old.rowid, old.message);
+                insert into user_messages_fts(rowid, message) values (new.rowid, new.message);
+            end;
+        "##,
+            "insert into user_messages_fts(user_messages_fts) values ('rebuild');",
+        ],
+        postgres: &[
+            "alter table user_messages add column search tsvector generated always as (to_tsvector('simple', message)) stored;",
+            "create index idx_user_messages_search on user_messages using gin (search);",
+        ],
+        marker: Marker::Table("user_messages_fts"),
+    },
+    Migration {
+        version: 8,
+        description: "inbox of mentions",
+        sqlite: &[r##"
+            create table main.inbox (
+            user_id    TEXT not null,
+            message_id TEXT not null,
+            primary key (user_id, message_id),
+            foreign key (user_id) REFERENCES users (id),
+            foreign key (message_id) REFERENCES user_messages (id) on delete cascade
+        );
+        "##],
+        postgres: &[r##"
+            create table inbox (
+            user_id    TEXT not null,
+            message_id TEXT not null,
+            primary key (user_id, message_id),
+            foreign key (user_id) REFERENCES users (id),
+            foreign key (message_id) REFERENCES user_messages (id) on delete cascade
+        );
+        "##],
+        marker: Marker::Table("inbox"),
+    },
+    Migration {
+        version: 9,
+        description: "forced password changes",
+        sqlite: &["alter table users add column must_change_password INTEGER default 0;"],
+        postgres: &["alter table users add column must_change_password BOOLEAN default false;"],
+        marker: Marker::Column("users", "must_change_password"),
+    },
+    Migration {
+        version: 10,
+        description: "full-text search keyed on the message id",
+        // VACUUM may renumber the implicit rowid of user_messages the index of version 7 is keyed on
+        sqlite: &[
+            "drop trigger user_messages_fts_insert;",
+            "drop trigger user_messages_fts_delete;",
+            "drop trigger user_messages_fts_update;",
+            "drop table user_messages_fts;",
+            "create virtual table user_messages_fts using fts5(message, id UNINDEXED);",
+            r##"
+            create trigger user_messages_fts_insert after insert on user_messages begin
+                insert into user_messages_fts(message, id) values (new.message, new.id);
+            end;
+        "##,
+            r##"
+            create trigger user_messages_fts_delete after delete on user_messages begin
+                delete from user_messages_fts where id = old.id;
+            end;
+        "##,
+            r##"
+            create trigger user_messages_fts_update after update of message on user_messages begin
+                update user_messages_fts set message = new.message where id = old.id;
+            end;
+        "##,
+            "insert into user_messages_fts(message, id) select message, id from user_messages;",
+        ],
+        postgres: &[],
+        marker: Marker::Column("user_messages_fts", "id"),
+    },
+    Migration {
+        version: 11,
+        description: "stable order of messages",
+        // Numbers the messages like the seq column of PostgreSQL, which VACUUM does not renumber
+        sqlite: &[
+            "alter table user_messages add column seq INTEGER;",
+            "update user_messages set seq = rowid;",
+            "create unique index idx_user_messages_seq on user_messages (seq);",
+        ],
+        postgres: &[],
+        marker: Marker::Column("user_messages", "seq"),
+    },
+];
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error(transparent)]
+    Sql(#[from] sqlx::Error),
+    #[error("Database schema version {0} is newer than version {1} of this server")]
+    TooNew(i64, i64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrationState {
+    /// Applied at the Unix time
+    Applied(i64),
+    /// In a database created before versions were recorded, recorded at the next start
+    Unrecorded,
+    Pending,
+}
+
+pub struct MigrationStatus {
+    pub migration: &'static Migration,
+    pub state: MigrationState,
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Brings the schema up to the latest version, every migration in a transaction of its own.
+/// Returns the version the schema had before, 0 for a new database.
+pub(crate) async fn migrate(store: &dyn Store) -> Result<i64, MigrationError> {
+    let version = match store.recorded_migrations().await? {
+        Some(recorded) => recorded.into_keys().max().unwrap_or(0),
+        None => {
+            let version = store.unrecorded_version().await?;
+            if version > 0 {
+                info!(
+                    "Recording schema version {} of the existing database.",
+                    version
+                );
+            }
+            let applied = MIGRATIONS
+                .iter()
+                .take_while(|migration| migration.version <= version)
+                .count();
+            store.record_migrations(&MIGRATIONS[..applied]).await?;
+            version
+        }
+    };
+    if version > latest_version() {
+        return Err(MigrationError::TooNew(version, latest_version()));
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        info!(
+            "Migrating the database to version {}: {}",
+            migration.version, migration.description
+        );
+        store.apply_migration(migration).await?;
+    }
+    Ok(version)
+}
+
+/// State of every migration in the database, which is not changed
+pub(crate) async fn status(store: &dyn Store) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let (applied, unrecorded_version) = match store.recorded_migrations().await? {
+        Some(recorded) => (recorded, 0),
+        None => (Default::default(), store.unrecorded_version().await?),
+    };
+    let recorded = applied.keys().max().copied().unwrap_or(0);
+    if recorded > latest_version() {
+        return Err(MigrationError::TooNew(recorded, latest_version()));
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            migration,
+            state: match applied.get(&migration.version) {
+                Some(applied_at) => MigrationState::Applied(*applied_at),
+                None if migration.version <= unrecorded_version => MigrationState::Unrecorded,
+                None => MigrationState::Pending,
+            },
+        })
+        .collect())
+}
+
+/// State of every migration in the database at the URL, without migrating it
+pub async fn database_status(database_url: &str) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let store = open_store(database_url, false).await?;
+    let status = status(store.as_ref()).await;
+    store.close().await;
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::{Pool, Row, Sqlite};
+
+    use crate::migrations::{
+        latest_version, migrate, status, MigrationError, MigrationState, MIGRATIONS,
+    };
+    use crate::store::sqlite::SqliteStore;
+
+    /// Schema created by the server before migrations were recorded, up to version 9
+    const UNRECORDED_SCHEMA: &[&str] = &[
+        r##"
+        create table main.users (
+        id       TEXT            not null
+            constraint users_pk
+                primary key,
+        name     TEXT,
+        active   INTEGER,
+        admin    INTEGER default 0,
+        password TEXT not null,
+        salt     TEXT not null,
+        last_logout_at INTEGER,
+        must_change_password INTEGER default 0
+    );
+    "##,
+        "create unique index uq_users_name ON users (name);",
+        r##"
+        create table main.user_messages (
+        id              TEXT    not null
+            constraint user_messages_pk
+                primary key,
+        author_id       TEXT    not null,
+        room            TEXT,
+        recipient_id    TEXT,
+        message         TEXT    not null,
+        sent_at_instant INTEGER not null,
+        edited_at       INTEGER,
+        foreign key (author_id) REFERENCES users (id),
+        foreign key (recipient_id) REFERENCES users (id)
+    );
+    "##,
+        "create index idx_user_messages_author_id on user_messages (author_id);",
+        "create index idx_user_messages_sent_at on user_messages (sent_at_instant desc);",
+        "create index idx_user_messages_recipient_id on user_messages (recipient_id);",
+        "create virtual table user_messages_fts using fts5(message, content='user_messages', content_rowid='rowid');",
+        r##"
+        create trigger user_messages_fts_insert after insert on user_messages begin
+            insert into user_messages_fts(rowid, message) values (new.rowid, new.message);
+        end;
+    "##,
+        r##"
+        create trigger user_messages_fts_delete after delete on user_messages begin
+            insert into user_messages_fts(user_messages_fts, rowid, message) values ('delete', old.rowid, old.message);
+        end;
+    "##,
+        r##"
+        create trigger user_messages_fts_update after update of message on user_messages begin
+            insert into user_messages_fts(user_messages_fts, rowid, message) values ('delete', old.rowid, old.message);
+            insert into user_messages_fts(rowid, message) values (new.rowid, new.message);
+        end;
+    "##,
+        r##"
+        create table main.room_members (
+        user_id   TEXT    not null,
+        room      TEXT    not null,
+        joined_at INTEGER not null,
+        primary key (user_id, room),
+        foreign key (user_id) REFERENCES users (id)
+    );
+    "##,
+        r##"
+        create table main.sanctions (
+        user_id    TEXT    not null,
+        kind       TEXT    not null,
+        expires_at INTEGER,
+        primary key (user_id, kind),
+        foreign key (user_id) REFERENCES users (id)
+    );
+    "##,
+        r##"
+        create table main.inbox (
+        user_id    TEXT not null,
+        message_id TEXT not null,
+        primary key (user_id, message_id),
+        foreign key (user_id) REFERENCES users (id),
+        foreign key (message_id) REFERENCES user_messages (id) on delete cascade
+    );
+    "##,
+    ];
+
+    async fn memory_pool() -> Pool<Sqlite> {
+        // Every connection to :memory: is a database of its own
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    fn store(pool: &Pool<Sqlite>) -> SqliteStore {
+        SqliteStore::new(pool.clone())
+    }
+
+    async fn execute(pool: &Pool<Sqlite>, statements: &[&str]) {
+        for statement in statements {
+            sqlx::query(statement).execute(pool).await.unwrap();
+        }
+    }
+
+    /// Tables with their columns, in the order of names
+    async fn schema(pool: &Pool<Sqlite>) -> Vec<(String, String)> {
+        sqlx::query(
+            "select m.name, p.name from sqlite_master m join pragma_table_info(m.name) p \
+            where m.type = 'table' and m.name not like 'user_messages_fts%' order by 1, 2",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn new_database_is_migrated_once() {
+        let pool = memory_pool().await;
+        assert_eq!(0, migrate(&store(&pool)).await.unwrap());
+        assert_eq!(latest_version(), migrate(&store(&pool)).await.unwrap());
+        let status = status(&store(&pool)).await.unwrap();
+        assert_eq!(MIGRATIONS.len(), status.len());
+        assert!(status
+            .iter()
+            .all(|status| matches!(status.state, MigrationState::Applied(_))));
+    }
+
+    #[tokio::test]
+    async fn unrecorded_database_of_a_released_schema_is_recorded_and_upgraded() {
+        let pool = memory_pool().await;
+        execute(&pool, UNRECORDED_SCHEMA).await;
+        execute(
+            &pool,
+            &[
+                "insert into users(id, name, active, password, salt) values ('u1', 'alice', 1, '', '')",
+                "insert into user_messages(id, author_id, room, message, sent_at_instant) values ('m1', 'u1', 'general', 'hello', 1)",
+                "insert into user_messages(id, author_id, room, message, sent_at_instant) values ('m2', 'u1', 'general', 'hello again', 1)",
+            ],
+        )
+        .await;
+        let states: Vec<MigrationState> = status(&store(&pool))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|status| status.state)
+            .collect();
+        assert!(states[..9]
+            .iter()
+            .all(|state| *state == MigrationState::Unrecorded));
+        assert!(states[9..]
+            .iter()
+            .all(|state| *state == MigrationState::Pending));
+
+        assert_eq!(9, migrate(&store(&pool)).await.unwrap());
+        let recorded: i64 = sqlx::query_scalar("select count(*) from schema_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(MIGRATIONS.len() as i64, recorded);
+        let found: Vec<(String, i64)> = sqlx::query_as(
+            "select m.id, m.seq from user_messages_fts f join user_messages m on m.id = f.id \
+            where user_messages_fts match 'hello' order by m.seq",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(vec![("m1".to_string(), 1), ("m2".to_string(), 2)], found);
+    }
+
+    #[tokio::test]
+    async fn unrecorded_database_of_the_first_schema_is_upgraded() {
+        let first = memory_pool().await;
+        execute(&first, MIGRATIONS[0].sqlite).await;
+        execute(
+            &first,
+            &[
+                "insert into users(id, name, active, password, salt) values ('u1', 'alice', 1, '', '')",
+                "insert into user_messages(id, author_id, message, sent_at_instant) values ('m1', 'u1', 'hello world', 1)",
+            ],
+        )
+        .await;
+        let pending = status(&store(&first)).await.unwrap();
+        assert_eq!(MigrationState::Unrecorded, pending[0].state);
+        assert!(pending[1..]
+            .iter()
+            .all(|status| status.state == MigrationState::Pending));
+
+        assert_eq!(1, migrate(&store(&first)).await.unwrap());
+        let fresh = memory_pool().await;
+        migrate(&store(&fresh)).await.unwrap();
+        assert_eq!(schema(&fresh).await, schema(&first).await);
+        let room: String = sqlx::query_scalar("select room from room_members where user_id = 'u1'")
+            .fetch_one(&first)
+            .await
+            .unwrap();
+        assert_eq!("general", room);
+        let found: String = sqlx::query_scalar(
+            "select m.room from user_messages_fts f join user_messages m on m.id = f.id \
+            where user_messages_fts match 'world'",
+        )
+        .fetch_one(&first)
+        .await
+        .unwrap();
+        assert_eq!("general", found);
+    }
+
+    #[tokio::test]
+    async fn search_index_follows_the_messages_by_id() {
+        let pool = memory_pool().await;
+        migrate(&store(&pool)).await.unwrap();
+        execute(
+            &pool,
+            &[
+                "insert into users(id, name, active, password, salt) values ('u1', 'alice', 1, '', '')",
+                "insert into user_messages(id, author_id, room, message, sent_at_instant) values ('m1', 'u1', 'general', 'hello world', 1)",
+                "insert into user_messages(id, author_id, room, message, sent_at_instant) values ('m2', 'u1', 'general', 'hello there', 2)",
+                "insert into user_messages(id, author_id, room, message, sent_at_instant) values ('m3', 'u1', 'general', 'bye world', 3)",
+                "delete from user_messages where id = 'm1'",
+                "update user_messages set message = 'hello again' where id = 'm3'",
+                "vacuum",
+            ],
+        )
+        .await;
+        let found: Vec<String> = sqlx::query_scalar(
+            "select m.id from user_messages_fts f join user_messages m on m.id = f.id \
+            where user_messages_fts match 'hello' order by m.id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(vec!["m2", "m3"], found);
+        let world: i64 = sqlx::query_scalar(
+            "select count(*) from user_messages_fts where user_messages_fts match 'world'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(0, world);
+    }
+
+    #[tokio::test]
+    async fn database_of_a_newer_server_is_refused() {
+        let pool = memory_pool().await;
+        migrate(&store(&pool)).await.unwrap();
+        sqlx::query("insert into schema_version(version, applied_at) values(?, 0)")
+            .bind(latest_version() + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            migrate(&store(&pool)).await,
+            Err(MigrationError::TooNew(version, _)) if version == latest_version() + 1
+        ));
+        assert!(status(&store(&pool)).await.is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use thiserror::Error;
+
+/// Argon2id cost of new password hashes. Hashes of another cost still verify
+/// and are replaced at the next login.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PasswordCost {
+    /// Memory in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordCost {
+    fn default() -> Self {
+        PasswordCost {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl PasswordCost {
+    pub fn validate(&self) -> Result<(), PasswordError> {
+        self.params().map(|_| ())
+    }
+
+    fn params(&self) -> Result<Params, PasswordError> {
+        Ok(Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            None,
+        )?)
+    }
+
+    fn is_cost_of(&self, hash: &PasswordHash) -> bool {
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() == self.memory_kib
+                    && params.t_cost() == self.iterations
+                    && params.p_cost() == self.parallelism
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Passwords nobody may choose unless the deny-list is replaced
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "passw0rd",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "87654321",
+    "11111111",
+    "00000000",
+    "qwertyui",
+    "qwerty123",
+    "qwertyuiop",
+    "asdfghjk",
+    "iloveyou",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "letmein1",
+    "welcome1",
+    "trustno1",
+    "changeme",
+    "admin123",
+    "administrator",
+    "abc12345",
+    "abcd1234",
+    "superman",
+];
+
+/// What new passwords have to look like, for signups and password changes alike
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    /// Characters, not bytes
+    pub min_length: usize,
+    /// Lowercase passwords nobody may choose, compared ignoring case
+    pub deny_list: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            deny_list: COMMON_PASSWORDS
+                .iter()
+                .map(|password| password.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Reads a deny-list with a password on every line, blank lines are skipped
+    pub fn read_deny_list(path: &Path) -> io::Result<HashSet<String>> {
+        Ok(read_to_string(path)?
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    /// Checks the password the user wants to set
+    pub fn check(&self, username: &str, password: &str) -> Result<(), PasswordError> {
+        let lowercase = password.to_lowercase();
+        if password.chars().count() < self.min_length {
+            Err(PasswordError::TooShort(self.min_length))
+        } else if lowercase == username.to_lowercase() {
+            Err(PasswordError::SameAsUsername)
+        } else if self.deny_list.contains(&lowercase) {
+            Err(PasswordError::Denied)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How passwords are hashed and which ones users may choose
+#[derive(Debug, Clone, Default)]
+pub struct PasswordOptions {
+    pub cost: PasswordCost,
+    pub policy: PasswordPolicy,
+}
+
+/// Outcome of checking a password against a stored hash
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verification {
+    Failed,
+    Verified,
+    /// Verified, but the hash is a legacy SHA-256 digest or of another cost and should be replaced
+    Outdated,
+}
+
+#[derive(Error, Debug)]
+pub enum PasswordError {
+    #[error("Invalid password hashing cost: {0}")]
+    InvalidCost(#[from] argon2::Error),
+    #[error("Password hashing failed: {0}")]
+    Hashing(String),
+    #[error("Password must have at least {0} characters")]
+    TooShort(usize),
+    #[error("Password must not be the same as the username")]
+    SameAsUsername,
+    #[error("Password is too common, choose another one")]
+    Denied,
+}
+
+impl From<argon2::password_hash::Error> for PasswordError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        PasswordError::Hashing(err.to_string())
+    }
+}
+
+/// Hashes the password with Argon2id and a random salt into a PHC string,
+/// e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`
+pub(crate) fn hash_password(password: &str, cost: &PasswordCost) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, cost.params()?);
+    Ok(argon2
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Checks the password against a PHC string, or against a legacy `sha256(password + salt)`
+/// hex digest of earlier versions, which kept the salt separately
+pub(crate) fn verify_password(
+    password: &str,
+    stored: &str,
+    legacy_salt: &str,
+    cost: &PasswordCost,
+) -> Verification {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        let legacy_digest = sha256::digest(format!("{}{}", password, legacy_salt));
+        return match legacy_digest == stored {
+            true => Verification::Outdated,
+            false => Verification::Failed,
+        };
+    };
+    // The algorithm and its parameters come from the hash
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_err()
+    {
+        return Verification::Failed;
+    }
+    match hash.algorithm == argon2::ARGON2ID_IDENT && cost.is_cost_of(&hash) {
+        true => Verification::Verified,
+        false => Verification::Outdated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::passwords::{
+        hash_password, verify_password, PasswordCost, PasswordError, PasswordPolicy, Verification,
+    };
+
+    const COST: PasswordCost = PasswordCost {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn hash_is_an_argon2id_phc_string_of_the_cost() {
+        let hash = hash_password("secret", &COST).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert_ne!(hash, hash_password("secret", &COST).unwrap());
+    }
+
+    #[test]
+    fn argon2id_hash_verifies_only_the_password() {
+        let hash = hash_password("secret", &COST).unwrap();
+        assert_eq!(
+            Verification::Verified,
+            verify_password("secret", &hash, "", &COST)
+        );
+        assert_eq!(
+            Verification::Failed,
+            verify_password("Secret", &hash, "", &COST)
+        );
+    }
+
+    #[test]
+    fn legacy_sha256_digest_verifies_as_outdated() {
+        let salt = "1b4e28ba-2fa1-11d2-883f-0016d3cca427";
+        let digest = sha256::digest(format!("secret{}", salt));
+        assert_eq!(
+            Verification::Outdated,
+            verify_password("secret", &digest, salt, &COST)
+        );
+        assert_eq!(
+            Verification::Failed,
+            verify_password("Secret", &digest, salt, &COST)
+        );
+        assert_eq!(
+            Verification::Failed,
+            verify_password("secret", &digest, "", &COST)
+        );
+    }
+
+    #[test]
+    fn hash_of_another_cost_verifies_as_outdated() {
+        let cheaper = PasswordCost {
+            memory_kib: 32,
+            ..COST
+        };
+        let hash = hash_password("secret", &cheaper).unwrap();
+        assert_eq!(
+            Verification::Outdated,
+            verify_password("secret", &hash, "", &COST)
+        );
+        assert_eq!(
+            Verification::Verified,
+            verify_password("secret", &hash, "", &cheaper)
+        );
+    }
+
+    #[test]
+    fn invalid_cost_is_rejected() {
+        assert!(COST.validate().is_ok());
+        let no_memory = PasswordCost {
+            memory_kib: 1,
+            ..COST
+        };
+        assert!(no_memory.validate().is_err());
+        assert!(hash_password("secret", &no_memory).is_err());
+    }
+
+    #[test]
+    fn policy_accepts_long_uncommon_passwords() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("alice", "correct horse").is_ok());
+        assert!(policy.check("alice", "žluťoučký").is_ok());
+    }
+
+    #[test]
+    fn policy_rejects_short_common_and_username_passwords() {
+        let policy = PasswordPolicy::default();
+        assert!(matches!(
+            policy.check("alice", "žluťouč"),
+            Err(PasswordError::TooShort(8))
+        ));
+        assert!(matches!(
+            policy.check("administrator", "Administrator"),
+            Err(PasswordError::SameAsUsername)
+        ));
+        assert!(matches!(
+            policy.check("alice", "PassWord1"),
+            Err(PasswordError::Denied)
+        ));
+    }
+}
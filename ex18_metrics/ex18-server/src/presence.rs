@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::tokio::sync::mpsc::UnboundedSender;
+use serde_derive::Serialize;
+
+use crate::users::User;
+
+/// Users logged in to the chat, over TCP or WebSocket, keyed by user id
+#[derive(Default)]
+pub struct Presence {
+    online: Mutex<HashMap<String, OnlineUser>>,
+}
+
+struct OnlineUser {
+    name: String,
+    sessions: HashMap<SocketAddr, OnlineSession>,
+}
+
+struct OnlineSession {
+    /// Last time the session received a message from its client
+    last_active: Instant,
+    /// Ends the session with the notice, unlike the broadcast queue it never drops a message
+    disconnect: UnboundedSender<String>,
+}
+
+impl OnlineUser {
+    fn idle(&self) -> Duration {
+        self.sessions
+            .values()
+            .map(|session| session.last_active.elapsed())
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+/// Online user as listed by `.who`
+pub struct OnlineUserView {
+    pub name: String,
+    pub connections: usize,
+    pub idle: Duration,
+}
+
+/// Logged in session as shown in the admin console
+#[derive(Serialize)]
+pub struct SessionView {
+    pub user_name: String,
+    pub socket_addr: String,
+    pub idle_secs: u64,
+}
+
+impl Presence {
+    /// Returns whether this is the first session of the user.
+    /// `disconnect` gets the notice if an admin ends the sessions of the user.
+    pub fn connect(
+        &self,
+        user: &User,
+        socket_addr: SocketAddr,
+        disconnect: UnboundedSender<String>,
+    ) -> bool {
+        let mut online = self.online.lock().unwrap();
+        let online_user = online.entry(user.id.clone()).or_insert_with(|| OnlineUser {
+            name: user.name.clone(),
+            sessions: HashMap::new(),
+        });
+        let session = OnlineSession {
+            last_active: Instant::now(),
+            disconnect,
+        };
+        online_user.sessions.insert(socket_addr, session);
+        online_user.sessions.len() == 1
+    }
+
+    /// Returns whether this was the last session of the user
+    pub fn disconnect(&self, user: &User, socket_addr: SocketAddr) -> bool {
+        let mut online = self.online.lock().unwrap();
+        let Some(online_user) = online.get_mut(&user.id) else {
+            return false;
+        };
+        online_user.sessions.remove(&socket_addr);
+        if online_user.sessions.is_empty() {
+            online.remove(&user.id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Ends every session of the user with the notice
+    pub fn end_sessions(&self, user: &User, notice: &str) {
+        if let Some(online_user) = self.online.lock().unwrap().get(&user.id) {
+            for session in online_user.sessions.values() {
+                // Fails only if the session is ending anyway
+                let _ = session.disconnect.send(notice.to_string());
+            }
+        }
+    }
+
+    /// Marks the session as active right now
+    pub fn touch(&self, user: &User, socket_addr: SocketAddr) {
+        if let Some(session) = self
+            .online
+            .lock()
+            .unwrap()
+            .get_mut(&user.id)
+            .and_then(|online_user| online_user.sessions.get_mut(&socket_addr))
+        {
+            session.last_active = Instant::now();
+        }
+    }
+
+    pub fn is_online(&self, user: &User) -> bool {
+        self.online.lock().unwrap().contains_key(&user.id)
+    }
+
+    /// Names of the online users in alphabetical order
+    pub fn user_names(&self) -> Vec<String> {
+        self.users().into_iter().map(|user| user.name).collect()
+    }
+
+    /// Online users in alphabetical order, idle since the last activity in any of their sessions
+    pub fn users(&self) -> Vec<OnlineUserView> {
+        let mut users: Vec<OnlineUserView> = self
+            .online
+            .lock()
+            .unwrap()
+            .values()
+            .map(|online_user| OnlineUserView {
+                name: online_user.name.clone(),
+                connections: online_user.sessions.len(),
+                idle: online_user.idle(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    /// Every logged in session, by user name
+    pub fn sessions(&self) -> Vec<SessionView> {
+        let mut sessions: Vec<SessionView> = self
+            .online
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|online_user| {
+                online_user
+                    .sessions
+                    .iter()
+                    .map(|(socket_addr, session)| SessionView {
+                        user_name: online_user.name.clone(),
+                        socket_addr: socket_addr.to_string(),
+                        idle_secs: session.last_active.elapsed().as_secs(),
+                    })
+            })
+            .collect();
+        sessions
+            .sort_by(|a, b| (&a.user_name, &a.socket_addr).cmp(&(&b.user_name, &b.socket_addr)));
+        sessions
+    }
+}
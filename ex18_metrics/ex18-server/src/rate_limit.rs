@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::tokio;
+use thiserror::Error;
+
+use ex18_shared::message::Message;
+use ex18_shared::transfer::Transfer;
+
+use crate::metrics::Metrics;
+use crate::users::User;
+
+/// Buckets kept before the full ones are forgotten, a full bucket is the same as a new one
+const MAX_TRACKED: usize = 4096;
+
+/// Locks a username out only after this many times `lockout_after` failures from any addresses,
+/// so that guessing from a single address cannot lock the owner out of their account
+const USERNAME_LOCKOUT_FACTOR: u32 = 10;
+
+/// Failed logins count towards a lockout until there was none for this long,
+/// only then their counter may be forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Sustained rate per second and the burst allowed on top of it, written as `<per_sec>/<burst>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate {}, use <per_sec>/<burst>, e.g. 5/20", s);
+        let (per_sec, burst) = s.split_once('/').ok_or_else(invalid)?;
+        let rate = Rate {
+            per_sec: per_sec.parse().map_err(|_| invalid())?,
+            burst: burst.parse().map_err(|_| invalid())?,
+        };
+        match rate.per_sec > 0.0 && rate.burst >= 1.0 {
+            true => Ok(rate),
+            false => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.per_sec, self.burst)
+    }
+}
+
+/// Limits of what a logged in user, over all of their sessions, and all sessions
+/// from one IP address may send
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub user_messages: Rate,
+    pub ip_messages: Rate,
+    /// Bytes of text, files and upload chunks
+    pub user_bytes: Rate,
+    pub ip_bytes: Rate,
+    /// Login and signup attempts, from the chat and the web app
+    pub ip_logins: Rate,
+    /// Failed logins in a row after which the IP address is locked out,
+    /// the username after ten times as many from any addresses
+    pub lockout_after: u32,
+    pub lockout: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            user_messages: Rate {
+                per_sec: 5.0,
+                burst: 20.0,
+            },
+            ip_messages: Rate {
+                per_sec: 20.0,
+                burst: 60.0,
+            },
+            user_bytes: Rate {
+                per_sec: 1024.0 * 1024.0,
+                burst: 16.0 * 1024.0 * 1024.0,
+            },
+            ip_bytes: Rate {
+                per_sec: 4.0 * 1024.0 * 1024.0,
+                burst: 32.0 * 1024.0 * 1024.0,
+            },
+            ip_logins: Rate {
+                per_sec: 0.2,
+                burst: 5.0,
+            },
+            lockout_after: 5,
+            lockout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Why a message or login was refused, phrased as the reply to the client
+#[derive(Debug, Error)]
+pub enum Throttled {
+    #[error("You are sending messages too fast, please slow down")]
+    Messages,
+    #[error("You are sending too much data, please slow down")]
+    Bytes,
+    #[error("Too many login attempts, please wait a moment")]
+    Logins,
+    #[error("Too many failed logins, try again in {0} seconds")]
+    LockedOut(u64),
+}
+
+impl Throttled {
+    fn metric_label(&self) -> &'static str {
+        match self {
+            Throttled::Messages => "messages",
+            Throttled::Bytes => "bytes",
+            Throttled::Logins => "logins",
+            Throttled::LockedOut(_) => "lockout",
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.refilled_at = now;
+    }
+
+    fn has(&mut self, amount: f64) -> bool {
+        self.refill();
+        self.tokens >= amount
+    }
+
+    /// Takes the tokens even if that leaves the bucket in debt,
+    /// returns how long paying off the debt takes
+    fn take(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+        Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate.per_sec)
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.has(self.rate.burst)
+    }
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+enum Resource {
+    Messages,
+    Bytes,
+    Logins,
+}
+
+/// Whose budget a bucket tracks
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum Subject {
+    User(String),
+    Ip(IpAddr),
+}
+
+#[derive(Default)]
+struct LoginFailures {
+    count: u32,
+    last_failure: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl LoginFailures {
+    fn is_expired(&self, now: Instant) -> bool {
+        let in_window = self
+            .last_failure
+            .is_some_and(|last| now.duration_since(last) < FAILURE_WINDOW);
+        let locked = self.locked_until.is_some_and(|until| until > now);
+        !in_window && !locked
+    }
+}
+
+/// Token buckets of every user and IP address, shared by all sessions and the web app
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(Subject, Resource), TokenBucket>>,
+    /// Failed logins by IP address and by attempted username
+    failures: Mutex<HashMap<Subject, LoginFailures>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            buckets: Mutex::default(),
+            failures: Mutex::default(),
+        }
+    }
+
+    /// Takes the message from the budgets of the user and of the IP address. Upload chunks
+    /// are never refused, as that would break the upload, their sender is slowed down instead.
+    pub async fn admit(&self, user: &User, ip: IpAddr, message: &Message) -> Result<(), Throttled> {
+        let subjects = [Subject::User(user.id.clone()), Subject::Ip(ip)];
+        let bytes = payload_size(message) as f64;
+        if let Message::Transfer(Transfer::Chunk { .. }) = message {
+            let delay = {
+                let mut buckets = self.buckets.lock().unwrap();
+                self.take(&mut buckets, &subjects, Resource::Bytes, bytes)
+            };
+            if !delay.is_zero() {
+                Metrics::instance().track_throttled(Throttled::Bytes.metric_label());
+                tokio::time::sleep(delay).await;
+            }
+            return Ok(());
+        }
+        // Only the upload start counts as a message, the other frames are part of the upload
+        let messages = match message {
+            Message::Transfer(Transfer::Start { .. }) => 1.0,
+            Message::Transfer(_) => 0.0,
+            _ => 1.0,
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let throttled = if !self.has(&mut buckets, &subjects, Resource::Messages, messages) {
+            Throttled::Messages
+        } else if !self.has(&mut buckets, &subjects, Resource::Bytes, bytes) {
+            Throttled::Bytes
+        } else {
+            self.take(&mut buckets, &subjects, Resource::Messages, messages);
+            self.take(&mut buckets, &subjects, Resource::Bytes, bytes);
+            return Ok(());
+        };
+        Metrics::instance().track_throttled(throttled.metric_label());
+        Err(throttled)
+    }
+
+    /// Takes a login or signup attempt from the budget of the IP address,
+    /// unless the IP address or the username is locked out
+    pub fn admit_login(&self, ip: IpAddr, username: &str) -> Result<(), Throttled> {
+        let result = self.check_login(ip, username);
+        if let Err(throttled) = &result {
+            Metrics::instance().track_throttled(throttled.metric_label());
+        }
+        result
+    }
+
+    fn check_login(&self, ip: IpAddr, username: &str) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let locked_until = [Subject::Ip(ip), Subject::User(username.to_string())]
+            .iter()
+            .filter_map(|subject| failures.get(subject)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .max();
+        if let Some(locked_until) = locked_until {
+            let remaining = locked_until.duration_since(now).as_secs_f64().ceil();
+            return Err(Throttled::LockedOut(remaining as u64));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, &Subject::Ip(ip), Resource::Logins);
+        if !bucket.has(1.0) {
+            return Err(Throttled::Logins);
+        }
+        bucket.take(1.0);
+        Ok(())
+    }
+
+    /// Counts the failure against the IP address and the username, locking each out
+    /// once it has failed too many times in a row
+    pub fn record_login_failure(&self, ip: IpAddr, username: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let lockout_after = self.limits.lockout_after;
+        let subjects = [
+            (Subject::Ip(ip), lockout_after),
+            (
+                Subject::User(username.to_string()),
+                lockout_after.saturating_mul(USERNAME_LOCKOUT_FACTOR),
+            ),
+        ];
+        for (subject, lockout_after) in subjects {
+            let entry = failures.entry(subject).or_default();
+            if entry.is_expired(now) {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last_failure = Some(now);
+            if entry.count >= lockout_after {
+                entry.count = 0;
+                entry.locked_until = Some(now + self.limits.lockout);
+            }
+        }
+        // Counters still in their window are kept, so that failures for other usernames
+        // cannot reset them. The login rate per IP address bounds how many there are.
+        if failures.len() > MAX_TRACKED {
+            failures.retain(|_, entry| !entry.is_expired(now));
+        }
+    }
+
+    pub fn record_login_success(&self, ip: IpAddr, username: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.remove(&Subject::Ip(ip));
+        failures.remove(&Subject::User(username.to_string()));
+    }
+
+    fn has(
+        &self,
+        buckets: &mut HashMap<(Subject, Resource), TokenBucket>,
+        subjects: &[Subject],
+        resource: Resource,
+        amount: f64,
+    ) -> bool {
+        subjects
+            .iter()
+            .all(|subject| self.bucket(buckets, subject, resource).has(amount))
+    }
+
+    /// Returns how long the sender has to wait for the most indebted of the buckets
+    fn take(
+        &self,
+        buckets: &mut HashMap<(Subject, Resource), TokenBucket>,
+        subjects: &[Subject],
+        resource: Resource,
+        amount: f64,
+    ) -> Duration {
+        subjects
+            .iter()
+            .map(|subject| self.bucket(buckets, subject, resource).take(amount))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn bucket<'b>(
+        &self,
+        buckets: &'b mut HashMap<(Subject, Resource), TokenBucket>,
+        subject: &Subject,
+        resource: Resource,
+    ) -> &'b mut TokenBucket {
+        if buckets.len() > MAX_TRACKED {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+        let rate = match (subject, resource) {
+            (Subject::User(_), Resource::Messages) => self.limits.user_messages,
+            (Subject::Ip(_), Resource::Messages) => self.limits.ip_messages,
+            (Subject::User(_), Resource::Bytes) => self.limits.user_bytes,
+            (Subject::Ip(_), Resource::Bytes) => self.limits.ip_bytes,
+            (_, Resource::Logins) => self.limits.ip_logins,
+        };
+        buckets
+            .entry((subject.clone(), resource))
+            .or_insert_with(|| TokenBucket::new(rate))
+    }
+}
+
+/// Bytes of user content the message carries
+fn payload_size(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::File(name, data) => name.len() + data.len(),
+        Message::Image(data) => data.len(),
+        Message::DirectMessage(_, text) | Message::Edit(_, text) => text.len(),
+        Message::Transfer(Transfer::Chunk { data, .. }) => data.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use rocket::tokio;
+
+    use ex18_shared::message::Message;
+    use ex18_shared::transfer::Transfer;
+
+    use crate::rate_limit::{
+        Rate, RateLimiter, RateLimits, Throttled, TokenBucket, MAX_TRACKED, USERNAME_LOCKOUT_FACTOR,
+    };
+    use crate::users::User;
+
+    const ALICE_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const MALLORY_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn rate(per_sec: f64, burst: f64) -> Rate {
+        Rate { per_sec, burst }
+    }
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            user_messages: rate(1.0, 2.0),
+            ip_messages: rate(1.0, 3.0),
+            user_bytes: rate(1000.0, 100.0),
+            ip_bytes: rate(1000.0, 1000.0),
+            ip_logins: rate(1.0, 100.0),
+            lockout_after: 3,
+            lockout: Duration::from_millis(100),
+        }
+    }
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: id.to_string(),
+            is_active: true,
+            is_admin: false,
+            must_change_password: false,
+        }
+    }
+
+    fn chunk(size: usize) -> Message {
+        Message::Transfer(Transfer::Chunk {
+            id: "upload".to_string(),
+            offset: 0,
+            data: vec![0; size],
+        })
+    }
+
+    #[test]
+    fn rate_is_parsed_as_per_sec_and_burst() {
+        assert_eq!(Ok(rate(0.5, 20.0)), "0.5/20".parse());
+        assert!("5".parse::<Rate>().is_err());
+        assert!("0/20".parse::<Rate>().is_err());
+        assert!("5/0.5".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(rate(10.0, 5.0));
+        bucket.take(5.0);
+        assert!(!bucket.has(1.0));
+        bucket.refilled_at -= Duration::from_millis(200);
+        assert!(bucket.has(2.0));
+        assert!(!bucket.has(3.0));
+        bucket.refilled_at -= Duration::from_secs(10);
+        assert!(bucket.is_full());
+        assert!(!bucket.has(6.0));
+    }
+
+    #[test]
+    fn bucket_in_debt_takes_time_to_pay_off() {
+        let mut bucket = TokenBucket::new(rate(10.0, 5.0));
+        assert_eq!(Duration::ZERO, bucket.take(5.0));
+        let delay = bucket.take(10.0);
+        assert!(delay > Duration::from_millis(900) && delay <= Duration::from_secs(1));
+        bucket.refilled_at -= Duration::from_millis(500);
+        assert!(!bucket.has(0.0));
+    }
+
+    #[tokio::test]
+    async fn messages_over_the_user_budget_are_refused() {
+        let limiter = RateLimiter::new(limits());
+        let text = Message::Text("hi".to_string());
+        assert!(limiter.admit(&user("alice"), ALICE_IP, &text).await.is_ok());
+        assert!(limiter.admit(&user("alice"), ALICE_IP, &text).await.is_ok());
+        assert!(matches!(
+            limiter.admit(&user("alice"), ALICE_IP, &text).await,
+            Err(Throttled::Messages)
+        ));
+        // Another user has a budget of its own, but shares the one of the IP address
+        assert!(limiter.admit(&user("bob"), ALICE_IP, &text).await.is_ok());
+        assert!(matches!(
+            limiter.admit(&user("bob"), ALICE_IP, &text).await,
+            Err(Throttled::Messages)
+        ));
+        assert!(limiter.admit(&user("bob"), MALLORY_IP, &text).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn bytes_over_the_user_budget_are_refused() {
+        let limiter = RateLimiter::new(limits());
+        let alice = user("alice");
+        let long = Message::Text("x".repeat(101));
+        assert!(matches!(
+            limiter.admit(&alice, ALICE_IP, &long).await,
+            Err(Throttled::Bytes)
+        ));
+        // A refused message takes nothing from the budget
+        let text = Message::Text("x".repeat(100));
+        assert!(limiter.admit(&alice, ALICE_IP, &text).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn chunks_over_the_budget_are_delayed_rather_than_refused() {
+        let limiter = RateLimiter::new(limits());
+        let alice = user("alice");
+        let started = Instant::now();
+        assert!(limiter.admit(&alice, ALICE_IP, &chunk(100)).await.is_ok());
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(limiter.admit(&alice, ALICE_IP, &chunk(100)).await.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn failed_logins_lock_the_ip_address_out_until_the_lockout_expires() {
+        let limiter = RateLimiter::new(limits());
+        for _ in 0..3 {
+            assert!(limiter.admit_login(MALLORY_IP, "alice").is_ok());
+            limiter.record_login_failure(MALLORY_IP, "alice");
+        }
+        assert!(matches!(
+            limiter.admit_login(MALLORY_IP, "bob"),
+            Err(Throttled::LockedOut(1))
+        ));
+        // The account can still log in from another address
+        assert!(limiter.admit_login(ALICE_IP, "alice").is_ok());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(limiter.admit_login(MALLORY_IP, "alice").is_ok());
+    }
+
+    #[test]
+    fn username_is_locked_out_only_after_many_more_failures() {
+        let limiter = RateLimiter::new(limits());
+        let failures = 3 * USERNAME_LOCKOUT_FACTOR;
+        for attempt in 0..failures {
+            let ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, attempt as u8));
+            limiter.record_login_failure(ip, "alice");
+            if attempt + 1 < failures {
+                assert!(limiter.admit_login(ALICE_IP, "alice").is_ok());
+            }
+        }
+        assert!(matches!(
+            limiter.admit_login(ALICE_IP, "alice"),
+            Err(Throttled::LockedOut(_))
+        ));
+        assert!(limiter.admit_login(ALICE_IP, "bob").is_ok());
+    }
+
+    #[test]
+    fn failures_of_other_usernames_do_not_reset_the_count() {
+        let limiter = RateLimiter::new(limits());
+        let failures = 3 * USERNAME_LOCKOUT_FACTOR;
+        for attempt in 0..failures - 1 {
+            let ip = IpAddr::V4(Ipv4Addr::new(198, 51, 100, attempt as u8));
+            limiter.record_login_failure(ip, "alice");
+        }
+        for attempt in 0..MAX_TRACKED {
+            let ip = IpAddr::V4(Ipv4Addr::new(203, 0, (attempt / 256) as u8, attempt as u8));
+            limiter.record_login_failure(ip, &format!("junk{}", attempt));
+        }
+        limiter.record_login_failure(MALLORY_IP, "alice");
+        assert!(matches!(
+            limiter.admit_login(ALICE_IP, "alice"),
+            Err(Throttled::LockedOut(_))
+        ));
+    }
+
+    #[test]
+    fn successful_login_resets_the_failures() {
+        let limiter = RateLimiter::new(limits());
+        limiter.record_login_failure(ALICE_IP, "alice");
+        limiter.record_login_failure(ALICE_IP, "alice");
+        limiter.record_login_success(ALICE_IP, "alice");
+        limiter.record_login_failure(ALICE_IP, "alice");
+        assert!(limiter.admit_login(ALICE_IP, "alice").is_ok());
+    }
+
+    #[test]
+    fn login_attempts_over_the_ip_budget_are_refused() {
+        let limiter = RateLimiter::new(RateLimits {
+            ip_logins: rate(1.0, 2.0),
+            ..limits()
+        });
+        assert!(limiter.admit_login(ALICE_IP, "alice").is_ok());
+        assert!(limiter.admit_login(ALICE_IP, "alice").is_ok());
+        assert!(matches!(
+            limiter.admit_login(ALICE_IP, "alice"),
+            Err(Throttled::Logins)
+        ));
+        assert!(limiter.admit_login(MALLORY_IP, "alice").is_ok());
+    }
+}
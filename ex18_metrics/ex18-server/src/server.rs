@@ -1,45 +1,154 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{error, info};
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use rocket::futures::future::select_all;
 use rocket::tokio;
+use rocket_ws::result::Error as WebSocketError;
 use thiserror::Error;
 use tokio::net::TcpListener;
 use tokio::select;
 use tokio::sync::broadcast::{channel, Sender};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use uuid::Uuid;
 
+use crate::connection::ChatConnection;
 use crate::metrics::Metrics;
-use ex18_shared::message::Message;
-use ex18_shared::message_tcp_stream::{MessageTcpStream, MessageTcpStreamError};
+use crate::passwords::PasswordOptions;
+use crate::presence::{Presence, SessionView};
+use crate::rate_limit::{RateLimiter, RateLimits};
+use crate::subscription::{BroadcastLimits, Received, Subscription, SubscriptionError};
+use ex18_shared::codec::{CodecError, CodecKind};
+use ex18_shared::compression::CompressionKind;
+use ex18_shared::message::{
+    mentioned_names, short_id, Envelope, HistoryRange, Message, Moderation, HISTORY_PAGE_SIZE,
+};
+use ex18_shared::message_tcp_stream::{
+    BoxedStream, MessageTcpStream, MessageTcpStreamError, DEFAULT_MAX_FRAME_SIZE,
+};
+use ex18_shared::tls::TlsAcceptor;
+use ex18_shared::transfer::Transfer;
 
 use crate::server::ServerError::AddressInUseError;
-use crate::users::{User, UserError, UserService};
+use crate::transfers::{ServedTransfer, TransferLimits, TransferStore, TransferStoreError};
+use crate::users::{
+    unix_timestamp, HistoryCursor, HistoryEntry, MessageFilter, Sanction, SanctionKind,
+    StoredMessage, User, UserError, UserService,
+};
 
-const CAPACITY: usize = 20;
-const ECONNRESET: i32 = 54;
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_HISTORY_PAGE_SIZE: u32 = 100;
+const SEARCH_PAGE_SIZE: u32 = 10;
+const NO_ROOM_REPLY: &str = "Join a room first with .join <room>";
+const PASSWORD_CHANGE_REPLY: &str =
+    "You have to change your password first with .passwd <new password> <new password>";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Optional protocol features this server supports
+const FEATURES: &[&str] = &[
+    CodecKind::Bincode.feature(),
+    CodecKind::Json.feature(),
+    CodecKind::MessagePack.feature(),
+    CompressionKind::Zstd.feature(),
+    CompressionKind::Deflate.feature(),
+];
 
 pub struct Server {
     listener: TcpListener,
+    hub: ChatHub,
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+/// Connects chat sessions with each other, no matter whether they come
+/// from the TCP listener or from the WebSocket endpoint of the web server
+#[derive(Clone)]
+pub struct ChatHub {
     broadcaster: Sender<Arc<BroadcastMessage>>,
+    broadcast_limits: BroadcastLimits,
+    frame_limits: FrameLimits,
+    presence: Arc<Presence>,
+    rate_limiter: Arc<RateLimiter>,
+    shutdown: watch::Sender<bool>,
+    active_sessions: watch::Sender<usize>,
+}
+
+/// Largest frames (in bytes) a client may send before and after it logs in
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    pub pre_login: u32,
+    pub post_login: u32,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            pre_login: 8 * 1024,
+            post_login: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BroadcastMessage {
+    /// Session the message comes from, `None` for notices of the server itself
+    from_addr: Option<SocketAddr>,
+    /// Unix timestamp, sessions that fall behind catch up on the messages stored since then
+    sent_at: i64,
+    audience: Audience,
+    message: Message,
+    mention: Option<Mention>,
 }
 
+/// Sent to the sessions of the mentioned users instead of the broadcast message.
+/// Those not in its audience get the notice without the text instead.
 #[derive(Debug)]
-struct BroadcastMessage {
-    from_addr: SocketAddr,
+struct Mention {
+    user_ids: Vec<String>,
     message: Message,
+    notice: Message,
+}
+
+/// Sessions a broadcast message is meant for
+#[derive(Debug)]
+enum Audience {
+    /// Members of the room
+    Room(String),
+    /// Every session of the user with this id
+    User(String),
+    /// Every logged in session
+    Everyone,
 }
 
 impl Server {
-    pub async fn new(socket_addr: SocketAddr) -> Result<Server, ServerError> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        socket_addr: SocketAddr,
+        frame_limits: FrameLimits,
+        rate_limits: RateLimits,
+        broadcast_limits: BroadcastLimits,
+        transfer_limits: TransferLimits,
+        passwords: PasswordOptions,
+        database_url: String,
+        tls_acceptor: Option<TlsAcceptor>,
+    ) -> Result<Server, ServerError> {
         info!("Listening on {}", socket_addr);
 
+        passwords.cost.validate().map_err(UserError::from)?;
+        UserService::configure(passwords, database_url);
+        UserService::init().await?;
+        TransferStore::configure(transfer_limits);
         tokio::task::spawn_blocking(|| {
             UserService::instance();
             Metrics::instance();
+            TransferStore::init()
         })
         .await
-        .map_err(|err| ServerError::GeneralError(err.to_string()))?;
+        .map_err(|err| ServerError::GeneralError(err.to_string()))??;
 
         let listener = TcpListener::bind(socket_addr)
             .await
@@ -47,97 +156,386 @@ impl Server {
 
         Ok(Server {
             listener,
-            broadcaster: channel(CAPACITY).0,
+            hub: ChatHub {
+                broadcaster: channel(broadcast_limits.capacity).0,
+                broadcast_limits,
+                frame_limits,
+                presence: Arc::default(),
+                rate_limiter: Arc::new(RateLimiter::new(rate_limits)),
+                shutdown: watch::Sender::new(false),
+                active_sessions: watch::Sender::new(0),
+            },
+            tls_acceptor,
         })
     }
 
+    /// Handle for the web server to bridge WebSocket clients into this chat
+    pub fn hub(&self) -> ChatHub {
+        self.hub.clone()
+    }
+
+    /// Accepts clients until the hub is shut down
     pub async fn listen(&self) -> Result<(), ServerError> {
+        let mut shutdown = self.hub.shutdown.subscribe();
         loop {
-            let (tcp_stream, socket_addr) = self.listener.accept().await?;
-            let broadcaster = self.broadcaster.clone();
-            let message_tcp_stream = MessageTcpStream::<Message>::from_tcp_stream(tcp_stream)?;
-            let mut session = UserSession {
-                logged_user: None,
-                socket_addr,
-                tcp_stream: message_tcp_stream,
-                user_service: UserService::instance(),
-                broadcaster,
+            let (tcp_stream, socket_addr) = select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = shutting_down(&mut shutdown) => {
+                    info!("No longer accepting chat clients");
+                    return Ok(());
+                }
             };
+            let hub = self.hub.clone();
+            let frame_limits = self.hub.frame_limits;
+            let tls_acceptor = self.tls_acceptor.clone();
 
             tokio::spawn(async move {
-                Metrics::instance().track_user_connected();
-                match session.run().await {
-                    Err(ServerError::TcpStreamError(MessageTcpStreamError::IOError(err)))
-                        if err.raw_os_error() == Some(ECONNRESET) =>
-                    {
-                        info!("Client {} disconnected", socket_addr);
-                    }
-                    Err(err) => {
-                        error!("{}", err);
+                // The TLS handshake counts towards the handshake timeout as well
+                let connect = async {
+                    let stream: BoxedStream = match tls_acceptor {
+                        Some(tls_acceptor) => Box::new(tls_acceptor.accept(tcp_stream).await?),
+                        None => Box::new(tcp_stream),
+                    };
+                    let mut message_tcp_stream =
+                        MessageTcpStream::<Message, _>::from_stream(stream)?;
+                    message_tcp_stream.set_max_frame_size(frame_limits.pre_login);
+                    message_tcp_stream.handshake_as_server(FEATURES).await?;
+                    debug!(
+                        "Client {} uses codec {} and compression {:?}",
+                        socket_addr,
+                        message_tcp_stream.codec(),
+                        message_tcp_stream.compression()
+                    );
+                    Ok::<_, MessageTcpStreamError>(message_tcp_stream)
+                };
+                match timeout(HANDSHAKE_TIMEOUT, connect).await {
+                    Ok(Ok(message_tcp_stream)) => {
+                        hub.run_session(socket_addr, message_tcp_stream, None).await
                     }
-                    _ => {}
+                    Ok(Err(err)) => info!("Handshake with {} failed: {}", socket_addr, err),
+                    Err(_) => info!("Handshake with {} timed out", socket_addr),
                 }
-                Metrics::instance().track_user_disconnected();
             });
         }
     }
 }
 
-struct UserSession<'a> {
+impl ChatHub {
+    pub fn frame_limits(&self) -> FrameLimits {
+        self.frame_limits
+    }
+
+    pub(crate) fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Names of the users logged in to the chat
+    pub fn online_user_names(&self) -> Vec<String> {
+        self.presence.user_names()
+    }
+
+    /// Logged in sessions, for the admin console
+    pub(crate) fn live_sessions(&self) -> Vec<SessionView> {
+        self.presence.sessions()
+    }
+
+    /// Stops accepting clients and ends every session with a notice to its client
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits until every session has ended, sessions end only after their database writes
+    pub async fn sessions_ended(&self) {
+        let _ = self
+            .active_sessions
+            .subscribe()
+            .wait_for(|count| *count == 0)
+            .await;
+    }
+
+    /// Kicks, bans or mutes the user, or lifts a ban or mute. Returns the outcome for the admin.
+    pub async fn moderate(
+        &self,
+        user: &User,
+        moderation: &Moderation,
+    ) -> Result<String, ServerError> {
+        let user_service = UserService::instance();
+        let outcome = match *moderation {
+            Moderation::Kick => {
+                self.notify_user(user, "You have been kicked".to_string(), true);
+                format!("{} has been kicked", user.name)
+            }
+            Moderation::Ban(duration) => {
+                let ban = user_service
+                    .impose_sanction(user, SanctionKind::Ban, duration)
+                    .await?;
+                let until = format_sanction(&ban);
+                self.notify_user(user, format!("You have been banned {}", until), true);
+                format!("{} has been banned {}", user.name, until)
+            }
+            Moderation::Mute(duration) => {
+                let mute = user_service
+                    .impose_sanction(user, SanctionKind::Mute, duration)
+                    .await?;
+                let until = format_sanction(&mute);
+                self.notify_user(user, format!("You have been muted {}", until), false);
+                format!("{} has been muted {}", user.name, until)
+            }
+            Moderation::Unban => match user_service.lift_sanction(user, SanctionKind::Ban).await? {
+                true => format!("{} is no longer banned", user.name),
+                false => format!("{} is not banned", user.name),
+            },
+            Moderation::Unmute => match user_service.lift_sanction(user, SanctionKind::Mute).await?
+            {
+                true => {
+                    self.notify_user(user, "You are no longer muted".to_string(), false);
+                    format!("{} is no longer muted", user.name)
+                }
+                false => format!("{} is not muted", user.name),
+            },
+        };
+        info!("{}", outcome);
+        Ok(outcome)
+    }
+
+    /// Sends the notice to every session of the user, ending the sessions if `disconnect` is set
+    pub fn notify_user(&self, user: &User, notice: String, disconnect: bool) {
+        if disconnect {
+            self.presence.end_sessions(user, &notice);
+            return;
+        }
+        // Fails only if no session is listening
+        let _ = self.broadcaster.send(Arc::new(BroadcastMessage {
+            from_addr: None,
+            sent_at: unix_timestamp(),
+            audience: Audience::User(user.id.clone()),
+            message: Message::Text(notice),
+            mention: None,
+        }));
+    }
+
+    /// Waits for database queries in flight and closes the database
+    pub async fn close_database(&self) {
+        UserService::instance().close().await
+    }
+
+    /// Serves a connected client until it disconnects or the hub shuts down.
+    /// Clients authenticated by other means, e.g. the web login cookie, pass their `user`.
+    pub(crate) async fn run_session<C: ChatConnection>(
+        &self,
+        socket_addr: SocketAddr,
+        connection: C,
+        user: Option<User>,
+    ) {
+        let _active = ActiveSession::new(&self.active_sessions);
+        let (disconnect_tx, disconnect_rx) = unbounded_channel();
+        let mut session = UserSession {
+            logged_user: None,
+            password_change_user: None,
+            socket_addr,
+            connection,
+            user_service: UserService::instance(),
+            hub: self,
+            presence: &self.presence,
+            broadcaster: self.broadcaster.clone(),
+            shutdown: self.shutdown.subscribe(),
+            disconnect_tx,
+            disconnect_rx,
+            frame_limits: self.frame_limits,
+            history_cursor: None,
+            last_broadcast_at: unix_timestamp(),
+            caught_up: HashSet::new(),
+            search: None,
+            rooms: Vec::new(),
+            transfer_rooms: HashMap::new(),
+            served_transfers: Vec::new(),
+            served_downloads: HashSet::new(),
+        };
+        session
+            .connection
+            .set_max_frame_size(self.frame_limits.pre_login);
+        Metrics::instance().track_user_connected();
+        match session.run(user).await {
+            Err(err) if err.is_disconnect() => {
+                info!("Client {} disconnected", socket_addr);
+            }
+            Err(
+                err @ (ServerError::TcpStreamError(MessageTcpStreamError::FrameTooLarge(_, _))
+                | ServerError::MessageTooLarge(_, _)
+                | ServerError::SubscriptionError(SubscriptionError::TooSlow(_, _))),
+            ) => {
+                warn!("Closing connection to {}: {}", socket_addr, err);
+            }
+            Err(ServerError::WebSocketError(err))
+                if matches!(*err, WebSocketError::Capacity(_)) =>
+            {
+                warn!("Closing connection to {}: {}", socket_addr, err);
+            }
+            Err(err) => {
+                error!("{}", err);
+            }
+            _ => {}
+        }
+        if let Some(user) = &session.logged_user {
+            if self.presence.disconnect(user, socket_addr) {
+                // Fails only if no other session is listening
+                let notice = Message::Text(format!("{} left the chat", user.name));
+                let _ = session.broadcast(Audience::Everyone, notice);
+            }
+            if let Err(err) = session.user_service.record_logout(user).await {
+                error!("Cannot record logout of {}: {}", user.name, err);
+            }
+        }
+        Metrics::instance()
+            .track_compression_savings(session.connection.take_compression_savings());
+        Metrics::instance().track_user_disconnected();
+    }
+}
+
+/// Counts a session as active until dropped, even if the session future is cancelled
+struct ActiveSession<'a>(&'a watch::Sender<usize>);
+
+impl<'a> ActiveSession<'a> {
+    fn new(active_sessions: &'a watch::Sender<usize>) -> ActiveSession<'a> {
+        active_sessions.send_modify(|count| *count += 1);
+        ActiveSession(active_sessions)
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+struct UserSession<'a, C: ChatConnection> {
     socket_addr: SocketAddr,
-    tcp_stream: MessageTcpStream<Message>,
+    connection: C,
     broadcaster: Sender<Arc<BroadcastMessage>>,
+    shutdown: watch::Receiver<bool>,
+    /// Registered with the presence, so that admins can end the session
+    disconnect_tx: UnboundedSender<String>,
+    disconnect_rx: UnboundedReceiver<String>,
     user_service: &'a UserService,
+    hub: &'a ChatHub,
+    presence: &'a Presence,
     logged_user: Option<User>,
+    /// User who authenticated but has to change their password before logging in
+    password_change_user: Option<User>,
+    frame_limits: FrameLimits,
+    /// Oldest message replayed so far, `.history` continues before it
+    history_cursor: Option<HistoryCursor>,
+    /// When the last broadcast message was sent, missed messages are caught up on since then
+    last_broadcast_at: i64,
+    /// Messages caught up on from the database, skipped if they still come from the broadcast
+    caught_up: HashSet<String>,
+    /// Text of the last `.search` and how many of its results were sent, `.search` alone continues it
+    search: Option<(String, u32)>,
+    /// Rooms of the user, the most recently joined first. Messages go to the first one.
+    rooms: Vec<String>,
+    /// Rooms of the uploads started in this session, in case the user switches rooms meanwhile
+    transfer_rooms: HashMap<String, String>,
+    /// Downloads sent from the transfer store at the pace of the client, only `Start`
+    /// frames come from the broadcast, which would drop chunks for clients falling behind
+    served_transfers: Vec<ServedTransfer>,
+    served_downloads: HashSet<String>,
 }
 
-impl<'a> UserSession<'a> {
-    pub async fn run(&mut self) -> Result<(), ServerError> {
-        let mut broadcast_sub = self.broadcaster.subscribe();
-        self.send_text_reply("Welcome! Please login with .login <username> <password>")
-            .await?;
+impl<'a, C: ChatConnection> UserSession<'a, C> {
+    pub async fn run(&mut self, user: Option<User>) -> Result<(), ServerError> {
+        let mut broadcast_sub =
+            Subscription::new(self.broadcaster.subscribe(), self.hub.broadcast_limits);
+        let mut shutdown = self.shutdown.clone();
+        match user {
+            Some(user) => match revoked_access(self.user_service, &user).await? {
+                Some(notice) => return self.send_text_reply(&notice).await,
+                None => self.log_in(user).await?,
+            },
+            None => {
+                self.send_text_reply("Welcome! Please login with .login <username> <password>")
+                    .await?
+            }
+        }
         loop {
+            Metrics::instance()
+                .track_compression_savings(self.connection.take_compression_savings());
             select! {
                 broadcast_msg_try = broadcast_sub.recv() => {
-                    let msg = broadcast_msg_try.unwrap();
-                    if self.socket_addr != msg.from_addr && self.logged_user.is_some() {
-                        self.tcp_stream.send_message(&msg.message).await?;
+                    let msg = match broadcast_msg_try {
+                        Ok(Received::Message(msg)) => msg,
+                        Ok(Received::Lagged(missed)) => {
+                            self.catch_up(missed).await?;
+                            continue;
+                        }
+                        Err(err @ SubscriptionError::TooSlow(_, _)) => {
+                            Metrics::instance().track_slow_session_disconnected();
+                            self.send_text_reply("Your connection is too slow to keep up with the chat, bye!").await?;
+                            return Err(err.into());
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    self.last_broadcast_at = msg.sent_at;
+                    let mention = self.mention_of(&msg);
+                    if msg.from_addr != Some(self.socket_addr)
+                        && (mention.is_some() || self.is_in_audience(&msg.audience))
+                        && !self.is_served_download(&msg.message)
+                        && !self.is_caught_up(&msg.message)
+                    {
+                        self.connection.send_message(mention.unwrap_or(&msg.message)).await?;
+                        if let Some(id) = transfer_started(&msg.message) {
+                            if let Err(err) = self.serve_download(id, 0).await {
+                                info!("Cannot serve transfer {}: {}", id, err);
+                            }
+                        }
                     }
                 }
-                stream_msg_try = self.tcp_stream.read_next_message() => {
+                _ = shutting_down(&mut shutdown) => {
+                    return self.send_text_reply("Server is shutting down, bye!").await;
+                }
+                Some(notice) = self.disconnect_rx.recv() => {
+                    info!("Disconnecting {} on behalf of an admin", self.socket_addr);
+                    return self.send_text_reply(&notice).await;
+                }
+                index = Self::next_served_transfer_ready(&mut self.served_transfers),
+                    if !self.served_transfers.is_empty() => {
+                    self.send_served_frame(index).await?;
+                }
+                stream_msg_try = self.connection.read_next_message() => {
                     match stream_msg_try {
-                        Err(stream_err) => { return Err(ServerError::from(stream_err)); }
+                        Err(stream_err) => { return Err(stream_err); }
                         Ok(Some(msg)) if self.logged_user.is_some() => {
+                            let user = self.logged_user.as_ref().unwrap();
+                            if let Some(notice) = revoked_access(self.user_service, user).await? {
+                                info!("Disconnecting {}: {}", self.socket_addr, notice);
+                                return self.send_text_reply(&notice).await;
+                            }
                             self.process_message_from_authenticated_client(msg).await?
                         },
+                        Ok(Some(msg)) if self.password_change_user.is_some() => {
+                            self.change_required_password(msg).await?
+                        },
                         Ok(Some(Message::Signup(login, passwd))) => {
+                            if let Err(throttled) = self.hub.rate_limiter().admit_login(self.socket_addr.ip(), &login) {
+                                self.send_text_reply(&throttled.to_string()).await?;
+                                continue;
+                            }
                             match self.user_service.signup(&login, &passwd).await {
                                 Ok(user) => {
-                                    self.logged_user = Some(user);
-                                    self.send_text_reply(&format!("Welcome, {}", login)).await?;
+                                    self.log_in(user).await?;
                                 },
                                 Err(UserError::UserAlreadyExists(_)) => {
                                     self.send_text_reply(&format!("Username {} already exists!", login)).await?;
                                 }
+                                Err(UserError::Password(err)) => {
+                                    self.send_text_reply(&err.to_string()).await?;
+                                }
                                 Err(err) => {
                                     error!("{}", err);
                                 }
                             }
                         },
                         Ok(Some(Message::Login(login, passwd))) => {
-                            match self.user_service.authenticate(&login, &passwd).await {
-                                Ok(user) => {
-                                    self.logged_user = Some(user);
-                                    self.send_text_reply(&format!("Welcome, {}", login)).await?;
-                                },
-                                Err(UserError::AuthenticationFailed) => {
-                                    self.send_text_reply("Authentication failure").await?
-                                }
-                                Err(err) => {
-                                    error!("{}", err);
-                                    self.send_text_reply("Server error").await?
-                                }
-                            }
+                            self.authenticate(&login, &passwd).await?;
                         }
                         Ok(Some(_)) => {
                             self.send_text_reply("Permission denied, login first using .login <username> <password>").await?;
@@ -149,42 +547,974 @@ impl<'a> UserSession<'a> {
         }
     }
 
+    /// Logs the user in unless the IP address or the username is locked out after failed attempts
+    async fn authenticate(&mut self, login: &str, passwd: &str) -> Result<(), ServerError> {
+        let ip = self.socket_addr.ip();
+        let rate_limiter = self.hub.rate_limiter();
+        if let Err(throttled) = rate_limiter.admit_login(ip, login) {
+            return self.send_text_reply(&throttled.to_string()).await;
+        }
+        match self.user_service.authenticate(login, passwd).await {
+            Ok(user) => {
+                rate_limiter.record_login_success(ip, login);
+                self.log_in(user).await
+            }
+            Err(UserError::AuthenticationFailed) => {
+                rate_limiter.record_login_failure(ip, login);
+                self.send_text_reply("Authentication failure").await
+            }
+            Err(UserError::Banned(ban)) => {
+                self.send_text_reply(&format!("You are banned {}", format_sanction(&ban)))
+                    .await
+            }
+            Err(err) => {
+                error!("{}", err);
+                self.send_text_reply("Server error").await
+            }
+        }
+    }
+
+    /// Welcomes the user and replays the messages sent since their last logout,
+    /// or the latest ones if they have never logged out. Users who have to change
+    /// their password are only let in once they do.
+    async fn log_in(&mut self, user: User) -> Result<(), ServerError> {
+        if user.must_change_password {
+            self.password_change_user = Some(user);
+            return self.send_text_reply(PASSWORD_CHANGE_REPLY).await;
+        }
+        self.rooms = self.user_service.get_user_rooms(&user).await?;
+        if self
+            .presence
+            .connect(&user, self.socket_addr, self.disconnect_tx.clone())
+        {
+            let notice = Message::Text(format!("{} joined the chat", user.name));
+            self.broadcast(Audience::Everyone, notice)?;
+        }
+        let welcome = format!("Welcome, {}", user.name);
+        self.logged_user = Some(user);
+        self.connection
+            .set_max_frame_size(self.frame_limits.post_login);
+        self.send_text_reply(&welcome).await?;
+        let user = self.logged_user.as_ref().unwrap();
+        let range = match self.user_service.get_last_logout(user).await? {
+            Some(_) => HistoryRange::SinceLogout,
+            None => HistoryRange::Older(HISTORY_PAGE_SIZE),
+        };
+        self.replay_history(range, true).await?;
+        self.deliver_inbox().await
+    }
+
+    /// Accepts nothing but `.passwd` from a user who has to change their password,
+    /// logs them in once the new password is set
+    async fn change_required_password(&mut self, message: Message) -> Result<(), ServerError> {
+        let Message::Passwd(new_passwd) = message else {
+            return self.send_text_reply(PASSWORD_CHANGE_REPLY).await;
+        };
+        let mut user = self.password_change_user.take().unwrap();
+        match self.user_service.change_password(&user, &new_passwd).await {
+            Ok(()) => {
+                user.must_change_password = false;
+                self.send_text_reply("Password updated successfully")
+                    .await?;
+                self.log_in(user).await
+            }
+            Err(UserError::Password(err)) => {
+                self.password_change_user = Some(user);
+                self.send_text_reply(&err.to_string()).await
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Tells the user how many broadcast messages they missed after falling behind
+    /// and replays the stored ones if the server catches up sessions
+    async fn catch_up(&mut self, missed: u64) -> Result<(), ServerError> {
+        let Some(user) = self.logged_user.as_ref() else {
+            return Ok(());
+        };
+        warn!(
+            "{} fell behind and missed {} messages",
+            self.socket_addr, missed
+        );
+        Metrics::instance().track_lagged(missed);
+        let notice = format!(
+            "Your connection is too slow, you missed {} messages",
+            missed
+        );
+        if !self.hub.broadcast_limits.catch_up {
+            return self.send_text_reply(&notice).await;
+        }
+        // Messages sent in the same second as the last one received may be repeated
+        let entries = self
+            .user_service
+            .get_history(
+                user,
+                None,
+                Some(self.last_broadcast_at),
+                MAX_HISTORY_PAGE_SIZE,
+            )
+            .await?;
+        if entries.is_empty() {
+            return self.send_text_reply(&notice).await;
+        }
+        self.caught_up = entries.iter().map(|entry| entry.id.clone()).collect();
+        self.send_text_reply(&format!("{}, {} stored since then:", notice, entries.len()))
+            .await?;
+        for entry in entries.iter().rev() {
+            self.send_text_reply(&format_history_entry(entry)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the mentions of the user made while they were offline
+    async fn deliver_inbox(&mut self) -> Result<(), ServerError> {
+        let user = self.logged_user.as_ref().unwrap();
+        let entries = self.user_service.take_inbox(user).await?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.send_text_reply(&format!(
+            "You were mentioned {} times while away:",
+            entries.len()
+        ))
+        .await?;
+        for entry in entries {
+            // The user may have left the room since
+            if !entry
+                .room
+                .as_ref()
+                .is_some_and(|room| self.rooms.contains(room))
+            {
+                let room = entry.room.unwrap_or_default();
+                let notice = mention_notice(&entry.author_name, &room);
+                self.send_text_reply(&notice).await?;
+                continue;
+            }
+            let envelope = Envelope {
+                id: entry.id,
+                sender: entry.author_name,
+                sent_at: entry.sent_at_instant,
+                room: entry.room,
+                message: Box::new(Message::Text(entry.message)),
+            };
+            self.connection
+                .send_message(&Message::Mention(envelope))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the history as text, oldest first. Repeated `Older` requests page further back.
+    async fn replay_history(
+        &mut self,
+        range: HistoryRange,
+        on_login: bool,
+    ) -> Result<(), ServerError> {
+        let user = self.logged_user.as_ref().unwrap();
+        let first_page = self.history_cursor.is_none();
+        let entries = match range {
+            HistoryRange::Older(count) => {
+                self.user_service
+                    .get_history(
+                        user,
+                        self.history_cursor.as_ref(),
+                        None,
+                        count.min(MAX_HISTORY_PAGE_SIZE),
+                    )
+                    .await?
+            }
+            HistoryRange::SinceLogout => match self.user_service.get_last_logout(user).await? {
+                Some(last_logout) => {
+                    self.user_service
+                        .get_history(user, None, Some(last_logout), MAX_HISTORY_PAGE_SIZE)
+                        .await?
+                }
+                None => return self.send_text_reply("You have not logged out yet").await,
+            },
+        };
+        let Some(oldest) = entries.last() else {
+            return match on_login {
+                true => Ok(()),
+                false => self.send_text_reply("No more messages").await,
+            };
+        };
+        self.history_cursor = Some(oldest.cursor());
+        let header = match range {
+            HistoryRange::SinceLogout => {
+                format!("{} messages since you logged out:", entries.len())
+            }
+            HistoryRange::Older(_) if first_page => format!("Last {} messages:", entries.len()),
+            HistoryRange::Older(_) => format!("{} earlier messages:", entries.len()),
+        };
+        self.send_text_reply(&header).await?;
+        for entry in entries.iter().rev() {
+            self.send_text_reply(&format_history_entry(entry)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a page of the messages the user can read that match the text, the best matches first
+    async fn search_messages(&mut self, text: Option<String>) -> Result<(), ServerError> {
+        let (text, offset) = match (text, self.search.take()) {
+            (Some(text), _) => (text, 0),
+            (None, Some(search)) => search,
+            (None, None) => return self.send_text_reply("Use .search <words>").await,
+        };
+        let user = self.logged_user.as_ref().unwrap();
+        let filter = MessageFilter {
+            text: Some(&text),
+            reader: Some(user),
+            ..MessageFilter::default()
+        };
+        let entries = self
+            .user_service
+            .search_messages(&filter, SEARCH_PAGE_SIZE, offset)
+            .await?;
+        if entries.is_empty() {
+            let reply = match offset {
+                0 => format!("No messages match {}", text),
+                _ => format!("No more messages match {}", text),
+            };
+            return self.send_text_reply(&reply).await;
+        }
+        let header = format!(
+            "Results {}-{} for {}:",
+            offset + 1,
+            offset + entries.len() as u32,
+            text
+        );
+        self.send_text_reply(&header).await?;
+        for entry in &entries {
+            self.send_text_reply(&format_history_entry(entry)).await?;
+        }
+        if entries.len() as u32 == SEARCH_PAGE_SIZE {
+            self.send_text_reply("Type .search for more results")
+                .await?;
+        }
+        self.search = Some((text, offset + entries.len() as u32));
+        Ok(())
+    }
+
     async fn process_message_from_authenticated_client(
         &mut self,
         message: Message,
     ) -> Result<(), ServerError> {
         let user = self.logged_user.as_ref().unwrap();
+        self.presence.touch(user, self.socket_addr);
+        if let Err(throttled) = self
+            .hub
+            .rate_limiter()
+            .admit(user, self.socket_addr.ip(), &message)
+            .await
+        {
+            return match message {
+                // Tells the client to give up the upload, it would keep sending chunks otherwise
+                Message::Transfer(Transfer::Start { id, .. }) => {
+                    let reply = Transfer::Abort {
+                        id,
+                        reason: throttled.to_string(),
+                    };
+                    self.connection
+                        .send_message(&Message::Transfer(reply))
+                        .await
+                }
+                _ => self.send_text_reply(&throttled.to_string()).await,
+            };
+        }
+        if is_speech(&message) {
+            if let Some(mute) = self
+                .user_service
+                .get_sanction(user, SanctionKind::Mute)
+                .await?
+            {
+                return self
+                    .send_text_reply(&format!("You are muted {}", format_sanction(&mute)))
+                    .await;
+            }
+        }
         match message {
             Message::Login(_, _) | Message::Signup(_, _) => {
                 self.send_text_reply("Already logged in!").await
             }
             Message::Passwd(new_passwd) => {
-                self.user_service.change_password(user, &new_passwd).await?;
-                self.send_text_reply("Password updated successfully").await
+                match self.user_service.change_password(user, &new_passwd).await {
+                    Ok(()) => self.send_text_reply("Password updated successfully").await,
+                    Err(UserError::Password(err)) => self.send_text_reply(&err.to_string()).await,
+                    Err(err) => Err(err.into()),
+                }
             }
+            Message::Join(room) => self.join_room(room).await,
+            Message::Leave(room) => self.leave_room(room).await,
+            Message::Rooms => self.list_rooms().await,
+            Message::Who => self.list_online_users().await,
+            Message::History(range) => self.replay_history(range, false).await,
+            Message::Search(text) => self.search_messages(text).await,
+            Message::DirectMessage(recipient_name, text) => {
+                self.send_direct_message(&recipient_name, text).await
+            }
+            Message::Edit(id, text) => self.edit_message(&id, text).await,
+            Message::Delete(id) => self.delete_message(&id).await,
+            Message::Moderate(user_name, moderation) => {
+                self.moderate(&user_name, &moderation).await
+            }
+            // Files and images only pass through the transfer store, which limits their size
+            Message::File(_, _) | Message::Image(_) => {
+                self.send_text_reply("Unsupported message, upload files as transfers")
+                    .await
+            }
+            Message::Upload(_, _) | Message::Envelope(_) | Message::Mention(_) | Message::Quit => {
+                self.send_text_reply("Unsupported message").await
+            }
+            Message::Transfer(transfer) => self.process_transfer(transfer).await,
             _ => {
+                let Some(room) = self.rooms.first() else {
+                    return self.send_text_reply(NO_ROOM_REPLY).await;
+                };
                 Metrics::instance().track_message_sent();
-                self.user_service.save_user_message(user, &message).await?;
-                self.broadcaster
-                    .send(Arc::new(BroadcastMessage {
-                        from_addr: self.socket_addr.clone(),
-                        message,
-                    }))
-                    .map(|_| ())
-                    .map_err(|err| ServerError::GeneralError(err.to_string()))
+                let envelope = self.envelope(Some(room.clone()), message);
+                self.user_service
+                    .save_user_message(user, None, &envelope)
+                    .await?;
+                let audience = Audience::Room(room.clone());
+                let mention = match envelope.message.as_ref() {
+                    Message::Text(text) => self.mention(&envelope, text).await?,
+                    _ => None,
+                };
+                self.send_broadcast(audience, Message::Envelope(envelope), mention)
+            }
+        }
+    }
+
+    /// Mention of the existing users named with `@name` in the room message, other than
+    /// the author. Mentions of offline users are kept in their inbox.
+    async fn mention(
+        &mut self,
+        envelope: &Envelope,
+        text: &str,
+    ) -> Result<Option<Mention>, ServerError> {
+        let author = self.logged_user.as_ref().unwrap();
+        let mut user_ids = Vec::new();
+        for name in mentioned_names(text) {
+            let user = match self.user_service.find_user_by_name(name).await {
+                Ok(user) if user.id != author.id => user,
+                Ok(_) | Err(UserError::NoSuchUser(_)) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if !self.presence.is_online(&user) {
+                self.user_service.add_to_inbox(&user, &envelope.id).await?;
+            }
+            user_ids.push(user.id);
+        }
+        if user_ids.is_empty() {
+            return Ok(None);
+        }
+        let message = Message::Mention(Envelope {
+            id: envelope.id.clone(),
+            sender: envelope.sender.clone(),
+            sent_at: envelope.sent_at,
+            room: envelope.room.clone(),
+            message: Box::new(Message::Text(text.to_string())),
+        });
+        let room = envelope.room.as_deref().unwrap_or_default();
+        let notice = Message::Text(mention_notice(&envelope.sender, room));
+        Ok(Some(Mention {
+            user_ids,
+            message,
+            notice,
+        }))
+    }
+
+    async fn send_direct_message(
+        &mut self,
+        recipient_name: &str,
+        text: String,
+    ) -> Result<(), ServerError> {
+        let user = self.logged_user.as_ref().unwrap();
+        let recipient = match self.user_service.find_user_by_name(recipient_name).await {
+            Ok(recipient) => recipient,
+            Err(UserError::NoSuchUser(_)) => {
+                return self
+                    .send_text_reply(&format!("There is no user {}", recipient_name))
+                    .await;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Metrics::instance().track_message_sent();
+        let envelope = self.envelope(None, Message::Text(text));
+        self.user_service
+            .save_user_message(user, Some(&recipient), &envelope)
+            .await?;
+        let online = self.presence.is_online(&recipient);
+        self.broadcast(Audience::User(recipient.id), Message::Envelope(envelope))?;
+        if !online {
+            self.send_text_reply(&format!("{} is not online", recipient_name))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Authors can edit their own messages
+    async fn edit_message(&mut self, id: &str, text: String) -> Result<(), ServerError> {
+        let Some(stored) = self.find_message(id).await? else {
+            return Ok(());
+        };
+        let user = self.logged_user.as_ref().unwrap();
+        if stored.author_id != user.id {
+            return self
+                .send_text_reply("You can only edit your own messages")
+                .await;
+        }
+        self.user_service.edit_message(&stored.id, &text).await?;
+        let reply = format!("Message {} edited", short_id(&stored.id));
+        self.broadcast_change(&stored, Message::Edit(stored.id.clone(), text))?;
+        self.send_text_reply(&reply).await
+    }
+
+    /// Authors can delete their own messages, admins any message
+    async fn delete_message(&mut self, id: &str) -> Result<(), ServerError> {
+        let Some(stored) = self.find_message(id).await? else {
+            return Ok(());
+        };
+        let user = self.logged_user.as_ref().unwrap();
+        if stored.author_id != user.id && !user.is_admin {
+            return self
+                .send_text_reply("You can only delete your own messages")
+                .await;
+        }
+        self.user_service.delete_message(&stored.id).await?;
+        let reply = format!("Message {} deleted", short_id(&stored.id));
+        self.broadcast_change(&stored, Message::Delete(stored.id.clone()))?;
+        self.send_text_reply(&reply).await
+    }
+
+    /// Replies if there is no single message with the id prefix
+    async fn find_message(&mut self, id: &str) -> Result<Option<StoredMessage>, ServerError> {
+        match self.user_service.find_message(id).await {
+            Ok(stored) => Ok(Some(stored)),
+            Err(err @ (UserError::NoSuchMessage(_) | UserError::AmbiguousMessageId(_))) => {
+                self.send_text_reply(&err.to_string()).await?;
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Tells whoever got the stored message about its edit or deletion
+    fn broadcast_change(&self, stored: &StoredMessage, change: Message) -> Result<(), ServerError> {
+        let audience = match (&stored.room, &stored.recipient_id) {
+            (_, Some(recipient_id)) => Audience::User(recipient_id.clone()),
+            (Some(room), None) => Audience::Room(room.clone()),
+            (None, None) => return Ok(()),
+        };
+        let envelope = Envelope {
+            id: stored.id.clone(),
+            ..self.envelope(stored.room.clone(), change)
+        };
+        self.broadcast(audience, Message::Envelope(envelope))
+    }
+
+    async fn moderate(
+        &mut self,
+        user_name: &str,
+        moderation: &Moderation,
+    ) -> Result<(), ServerError> {
+        let user = self.logged_user.as_ref().unwrap();
+        if !user.is_admin {
+            return self
+                .send_text_reply("Only admins can kick, ban or mute users")
+                .await;
+        }
+        let target = match self.user_service.find_user_by_name(user_name).await {
+            Ok(target) => target,
+            Err(UserError::NoSuchUser(_)) => {
+                return self
+                    .send_text_reply(&format!("There is no user {}", user_name))
+                    .await;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if target.id == user.id {
+            return self
+                .send_text_reply("You cannot kick, ban or mute yourself")
+                .await;
+        }
+        let outcome = self.hub.moderate(&target, moderation).await?;
+        self.send_text_reply(&outcome).await
+    }
+
+    async fn join_room(&mut self, room: String) -> Result<(), ServerError> {
+        if !is_valid_room_name(&room) {
+            return self
+                .send_text_reply(&format!(
+                    "Room names have 1 to {} letters, digits, '-' or '_'",
+                    MAX_ROOM_NAME_LEN
+                ))
+                .await;
+        }
+        let user = self.logged_user.as_ref().unwrap();
+        self.user_service.join_room(user, &room).await?;
+        let reply = if self.rooms.contains(&room) {
+            format!("Switched to room {}", room)
+        } else {
+            format!("Joined room {}", room)
+        };
+        self.rooms.retain(|joined| *joined != room);
+        self.rooms.insert(0, room);
+        self.send_text_reply(&reply).await
+    }
+
+    async fn leave_room(&mut self, room: String) -> Result<(), ServerError> {
+        let user = self.logged_user.as_ref().unwrap();
+        if !self.user_service.leave_room(user, &room).await? && !self.rooms.contains(&room) {
+            return self
+                .send_text_reply(&format!("You are not in room {}", room))
+                .await;
+        }
+        self.rooms.retain(|joined| *joined != room);
+        let reply = match self.rooms.first() {
+            Some(current) => format!("Left room {}, talking in {}", room, current),
+            None => format!("Left room {}. {}", room, NO_ROOM_REPLY),
+        };
+        self.send_text_reply(&reply).await
+    }
+
+    async fn list_rooms(&mut self) -> Result<(), ServerError> {
+        let rooms = self.user_service.get_rooms().await?;
+        let mut reply = "Rooms (* current, + joined):".to_string();
+        for room in rooms {
+            let mark = match self.rooms.iter().position(|joined| *joined == room.name) {
+                Some(0) => '*',
+                Some(_) => '+',
+                None => ' ',
+            };
+            let members = if room.members == 1 {
+                "member"
+            } else {
+                "members"
+            };
+            reply.push_str(&format!(
+                "\n{} {} ({} {})",
+                mark, room.name, room.members, members
+            ));
+        }
+        self.send_text_reply(&reply).await
+    }
+
+    async fn list_online_users(&mut self) -> Result<(), ServerError> {
+        let mut reply = "Online users:".to_string();
+        for user in self.presence.users() {
+            let connections = match user.connections {
+                1 => "1 connection".to_string(),
+                connections => format!("{} connections", connections),
+            };
+            reply.push_str(&format!(
+                "\n{} ({}, idle {})",
+                user.name,
+                connections,
+                format_idle(user.idle)
+            ));
+        }
+        self.send_text_reply(&reply).await
+    }
+
+    /// Room the frames of an upload go to, the current room for uploads resumed after a reconnect
+    fn transfer_room(&self, id: &str) -> Option<String> {
+        self.transfer_rooms.get(id).or(self.rooms.first()).cloned()
+    }
+
+    async fn process_transfer(&mut self, transfer: Transfer) -> Result<(), ServerError> {
+        let store = TransferStore::instance();
+        let id = transfer.id().to_string();
+        if let Transfer::Start { .. } = transfer {
+            let Some(room) = self.rooms.first() else {
+                let reply = Transfer::Abort {
+                    id,
+                    reason: NO_ROOM_REPLY.to_string(),
+                };
+                return self
+                    .connection
+                    .send_message(&Message::Transfer(reply))
+                    .await;
+            };
+            self.transfer_rooms.insert(id.clone(), room.clone());
+        }
+        if let Transfer::ResumeDownload { offset, .. } = transfer {
+            let Err(err) = self.serve_download(&id, offset).await else {
+                return Ok(());
+            };
+            info!("Cannot serve transfer {}: {}", id, err);
+            let reply = Transfer::Abort {
+                id,
+                reason: err.to_string(),
+            };
+            return self
+                .connection
+                .send_message(&Message::Transfer(reply))
+                .await;
+        }
+        let user = self.logged_user.as_ref().unwrap();
+        let result = match transfer {
+            Transfer::Start {
+                kind,
+                ref name,
+                size,
+                ..
+            } => match store
+                .start(user, &self.transfer_rooms[&id], &id, kind, name, size)
+                .await
+            {
+                Ok(None) => {
+                    Metrics::instance().track_message_sent();
+                    let room = &self.transfer_rooms[&id];
+                    let envelope = self.envelope(Some(room.clone()), Message::Transfer(transfer));
+                    self.user_service
+                        .save_user_message(user, None, &envelope)
+                        .await?;
+                    self.broadcast(Audience::Room(room.clone()), Message::Envelope(envelope))?;
+                    Ok(0)
+                }
+                Ok(Some(stored)) => Ok(stored),
+                Err(err) => Err(err),
+            },
+            // Receivers read the chunks from the transfer store
+            Transfer::Chunk {
+                offset, ref data, ..
+            } => match store.write_chunk(user, &id, offset, data).await {
+                Ok(()) => store.stored_offset(user, &id),
+                Err(err) => Err(err),
+            },
+            Transfer::End { ref sha256, .. } => match store.finish(user, &id, sha256).await {
+                Ok(newly_finished) => {
+                    let confirmation = Transfer::End {
+                        id: id.clone(),
+                        sha256: sha256.clone(),
+                    };
+                    if newly_finished {
+                        self.broadcast_transfer(transfer)?;
+                    }
+                    self.transfer_rooms.remove(&id);
+                    return self
+                        .connection
+                        .send_message(&Message::Transfer(confirmation))
+                        .await;
+                }
+                Err(err) => Err(err),
+            },
+            Transfer::Abort { .. } => {
+                if store.abort(user, &id).await.is_ok() {
+                    self.broadcast_transfer(transfer)?;
+                }
+                self.transfer_rooms.remove(&id);
+                return Ok(());
+            }
+            Transfer::ResumeUpload { .. } => store.stored_offset(user, &id),
+            Transfer::Ack { .. } | Transfer::ResumeDownload { .. } => {
+                return self.send_text_reply("Unsupported message").await;
+            }
+        };
+        let reply = match result {
+            Ok(offset) => Transfer::Ack { id, offset },
+            Err(err) => {
+                info!("Transfer {} from {} failed: {}", id, self.socket_addr, err);
+                // The client gives up on the upload, so it is dropped unless another user uploads it
+                if store.abort(user, &id).await.is_ok() {
+                    self.broadcast_transfer(Transfer::Abort {
+                        id: id.clone(),
+                        reason: err.to_string(),
+                    })?;
+                }
+                self.transfer_rooms.remove(&id);
+                Transfer::Abort {
+                    id,
+                    reason: err.to_string(),
+                }
+            }
+        };
+        self.connection
+            .send_message(&Message::Transfer(reply))
+            .await
+    }
+
+    /// Sends the upload to the client from the transfer store, starting after the first `offset` bytes
+    async fn serve_download(&mut self, id: &str, offset: u64) -> Result<(), TransferStoreError> {
+        let served = TransferStore::instance()
+            .serve(id, offset, &self.rooms)
+            .await?;
+        self.served_downloads.insert(id.to_string());
+        self.served_transfers.push(served);
+        Ok(())
+    }
+
+    /// Waits until one of the served transfers has something to send and returns its index
+    async fn next_served_transfer_ready(served_transfers: &mut [ServedTransfer]) -> usize {
+        let ready = served_transfers
+            .iter_mut()
+            .map(|served| Box::pin(served.ready()));
+        select_all(ready).await.1
+    }
+
+    async fn send_served_frame(&mut self, index: usize) -> Result<(), ServerError> {
+        let mut served = self.served_transfers.swap_remove(index);
+        let transfer = match served.next_message().await {
+            Ok(transfer) => transfer,
+            Err(err) => {
+                info!("Cannot serve transfer {}: {}", served.id(), err);
+                Some(Transfer::Abort {
+                    id: served.id().to_string(),
+                    reason: err.to_string(),
+                })
+            }
+        };
+        let finished = served.is_finished() || matches!(transfer, Some(Transfer::Abort { .. }));
+        if let Some(transfer) = transfer {
+            self.connection
+                .send_message(&Message::Transfer(transfer))
+                .await?;
+        }
+        if finished {
+            self.served_downloads.remove(served.id());
+        } else {
+            self.served_transfers.push(served);
+        }
+        Ok(())
+    }
+
+    /// Whether the frame belongs to a download sent from the transfer store, which sends
+    /// the `End` or `Abort` frame itself once the client has all chunks
+    fn is_served_download(&self, message: &Message) -> bool {
+        match message {
+            Message::Transfer(transfer) => self.served_downloads.contains(transfer.id()),
+            Message::Envelope(envelope) => self.is_served_download(&envelope.message),
+            _ => false,
+        }
+    }
+
+    /// Whether the message was already replayed by `catch_up`, only checked once per message
+    fn is_caught_up(&mut self, message: &Message) -> bool {
+        match message {
+            Message::Envelope(envelope) | Message::Mention(envelope) => {
+                self.caught_up.remove(&envelope.id)
+            }
+            _ => false,
+        }
+    }
+
+    fn broadcast_transfer(&self, transfer: Transfer) -> Result<(), ServerError> {
+        match self.transfer_room(transfer.id()) {
+            Some(room) => {
+                let envelope = self.envelope(Some(room.clone()), Message::Transfer(transfer));
+                self.broadcast(Audience::Room(room), Message::Envelope(envelope))
             }
+            None => Ok(()),
+        }
+    }
+
+    /// Wraps a message of the logged in user for relaying to other clients
+    fn envelope(&self, room: Option<String>, message: Message) -> Envelope {
+        Envelope {
+            id: Uuid::new_v4().to_string(),
+            sender: self.logged_user.as_ref().unwrap().name.clone(),
+            sent_at: unix_timestamp(),
+            room,
+            message: Box::new(message),
+        }
+    }
+
+    fn is_in_audience(&self, audience: &Audience) -> bool {
+        match (audience, &self.logged_user) {
+            (Audience::Room(room), Some(_)) => self.rooms.contains(room),
+            (Audience::User(user_id), Some(user)) => user.id == *user_id,
+            (Audience::Everyone, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+
+    /// The mention to send instead of the message, if it mentions the user of this session.
+    /// The text goes only to the audience of the message, like the message itself.
+    fn mention_of<'m>(&self, msg: &'m BroadcastMessage) -> Option<&'m Message> {
+        let user = self.logged_user.as_ref()?;
+        let mention = msg
+            .mention
+            .as_ref()
+            .filter(|mention| mention.user_ids.contains(&user.id))?;
+        match self.is_in_audience(&msg.audience) {
+            true => Some(&mention.message),
+            false => Some(&mention.notice),
         }
     }
 
+    fn broadcast(&self, audience: Audience, message: Message) -> Result<(), ServerError> {
+        self.send_broadcast(audience, message, None)
+    }
+
+    fn send_broadcast(
+        &self,
+        audience: Audience,
+        message: Message,
+        mention: Option<Mention>,
+    ) -> Result<(), ServerError> {
+        self.broadcaster
+            .send(Arc::new(BroadcastMessage {
+                from_addr: Some(self.socket_addr),
+                sent_at: unix_timestamp(),
+                audience,
+                message,
+                mention,
+            }))
+            .map(|_| ())
+            .map_err(|err| ServerError::GeneralError(err.to_string()))
+    }
+
     async fn send_text_reply(&mut self, text: &str) -> Result<(), ServerError> {
         let message = Message::Text(text.to_string());
-        self.tcp_stream
-            .send_message(&message)
-            .await
-            .map_err(|err| ServerError::from(err))
+        self.connection.send_message(&message).await
+    }
+}
+
+impl ServerError {
+    /// Whether the error just means that the client went away
+    fn is_disconnect(&self) -> bool {
+        match self {
+            // TLS clients which exit without sending close_notify end with UnexpectedEof
+            ServerError::TcpStreamError(MessageTcpStreamError::IOError(err)) => {
+                is_connection_reset(err) || err.kind() == std::io::ErrorKind::UnexpectedEof
+            }
+            ServerError::TcpStreamError(MessageTcpStreamError::ConnectionClosed) => true,
+            // Protocol errors include browsers going away without a close frame
+            ServerError::WebSocketError(err) => match **err {
+                WebSocketError::ConnectionClosed
+                | WebSocketError::AlreadyClosed
+                | WebSocketError::Protocol(_) => true,
+                WebSocketError::Io(ref err) => is_connection_reset(err),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+fn format_history_entry(entry: &HistoryEntry) -> String {
+    let sent_at = DateTime::from_timestamp(entry.sent_at_instant, 0)
+        .map(|sent_at| {
+            sent_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let id = match entry.edited_at {
+        Some(_) => format!("{}, edited", short_id(&entry.id)),
+        None => short_id(&entry.id).to_string(),
+    };
+    match (&entry.room, &entry.recipient_name) {
+        (_, Some(recipient)) => format!(
+            "[{}] {} -> {}: {} ({})",
+            sent_at, entry.author_name, recipient, entry.message, id
+        ),
+        (room, None) => format!(
+            "[{}] #{} {}: {} ({})",
+            sent_at,
+            room.as_deref().unwrap_or_default(),
+            entry.author_name,
+            entry.message,
+            id
+        ),
+    }
+}
+
+/// `for good` or when the ban or mute expires, e.g. `until 2024-01-31 18:00`
+fn format_sanction(sanction: &Sanction) -> String {
+    match sanction
+        .expires_at
+        .and_then(|expires_at| DateTime::from_timestamp(expires_at, 0))
+    {
+        Some(expires_at) => format!(
+            "until {}",
+            expires_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+        ),
+        None => "for good".to_string(),
+    }
+}
+
+/// Tells a user mentioned in a room they are not in about the mention, without the text
+fn mention_notice(sender: &str, room: &str) -> String {
+    format!(
+        "{} mentioned you in room {}, join it to read the message",
+        sender, room
+    )
+}
+
+/// Why the user may no longer chat, if they were banned or deactivated since logging in.
+/// Checked for every message, so that a session the admin notice never reached cannot go on.
+async fn revoked_access(
+    user_service: &UserService,
+    user: &User,
+) -> Result<Option<String>, ServerError> {
+    if let Some(ban) = user_service.get_sanction(user, SanctionKind::Ban).await? {
+        return Ok(Some(format!("You are banned {}", format_sanction(&ban))));
+    }
+    match user_service.get_user_by_id(&user.id).await {
+        Ok(current) if current.is_active => Ok(None),
+        Ok(_) | Err(UserError::NoSuchUser(_)) => {
+            Ok(Some("Your account has been deactivated".to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Id of the upload the relayed message starts
+fn transfer_started(message: &Message) -> Option<&str> {
+    match message {
+        Message::Envelope(envelope) => match envelope.message.as_ref() {
+            Message::Transfer(transfer @ Transfer::Start { .. }) => Some(transfer.id()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Messages that muted users may not send
+fn is_speech(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Text(_)
+            | Message::File(_, _)
+            | Message::Image(_)
+            | Message::DirectMessage(_, _)
+            | Message::Edit(_, _)
+            | Message::Transfer(Transfer::Start { .. })
+    )
+}
+
+fn format_idle(idle: Duration) -> String {
+    let secs = idle.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
     }
 }
 
+fn is_valid_room_name(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= MAX_ROOM_NAME_LEN
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Completes once the hub shuts down. Unlike `wait_for`, the future outputs nothing which isn't `Send`
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+}
+
+fn is_connection_reset(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe
+    )
+}
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error(transparent)]
@@ -195,8 +1525,154 @@ pub enum ServerError {
     UserError(#[from] UserError),
     #[error(transparent)]
     TcpStreamError(#[from] MessageTcpStreamError),
+    #[error(transparent)]
+    TransferStoreError(#[from] TransferStoreError),
+    #[error(transparent)]
+    WebSocketError(Box<WebSocketError>),
+    #[error(transparent)]
+    CodecError(#[from] CodecError),
+    #[error(transparent)]
+    SubscriptionError(#[from] SubscriptionError),
+    #[error("Message of {0} bytes exceeds the limit of {1} bytes")]
+    MessageTooLarge(usize, u32),
     #[error("Listen address {0} already in use")]
     AddressInUseError(SocketAddr),
     #[error("{0}")]
     GeneralError(String),
 }
+
+impl From<WebSocketError> for ServerError {
+    fn from(err: WebSocketError) -> Self {
+        ServerError::WebSocketError(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rocket::tokio;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::sync::broadcast::channel;
+    use tokio::sync::watch;
+    use tokio::time::timeout;
+    use uuid::Uuid;
+
+    use ex18_shared::message::Message;
+    use ex18_shared::message_tcp_stream::MessageTcpStream;
+    use ex18_shared::transfer::{Transfer, TransferKind};
+
+    use crate::passwords::{PasswordCost, PasswordOptions};
+    use crate::rate_limit::{RateLimiter, RateLimits};
+    use crate::server::{ChatHub, FrameLimits};
+    use crate::subscription::BroadcastLimits;
+    use crate::transfers::{TransferLimits, TransferStore};
+    use crate::users::{User, UserService};
+
+    fn hub() -> ChatHub {
+        let broadcast_limits = BroadcastLimits::default();
+        ChatHub {
+            broadcaster: channel(broadcast_limits.capacity).0,
+            broadcast_limits,
+            frame_limits: FrameLimits::default(),
+            presence: Arc::default(),
+            rate_limiter: Arc::new(RateLimiter::new(RateLimits::default())),
+            shutdown: watch::Sender::new(false),
+            active_sessions: watch::Sender::new(0),
+        }
+    }
+
+    /// Runs a session of the logged in user, returns the client end of its connection
+    fn connect(hub: &ChatHub, user: User, port: u16) -> MessageTcpStream<Message, DuplexStream> {
+        let (client, server) = duplex(64 * 1024);
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            let connection = MessageTcpStream::from_stream(server).unwrap();
+            let socket_addr = ([127, 0, 0, 1], port).into();
+            hub.run_session(socket_addr, connection, Some(user)).await
+        });
+        MessageTcpStream::from_stream(client).unwrap()
+    }
+
+    /// Asks to download the transfer from the start, returns the first frame of it the server sends
+    async fn resume_download(hub: &ChatHub, user: User, port: u16, id: &str) -> Transfer {
+        let mut client = connect(hub, user, port);
+        let resume = Transfer::ResumeDownload {
+            id: id.to_string(),
+            offset: 0,
+        };
+        client
+            .send_message(&Message::Transfer(resume))
+            .await
+            .unwrap();
+        loop {
+            let message = timeout(Duration::from_secs(5), client.read_next_message())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Some(Message::Transfer(transfer)) = message {
+                if transfer.id() == id {
+                    return transfer;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn download_is_resumed_only_by_members_of_the_room() {
+        let passwords = PasswordOptions {
+            cost: PasswordCost {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            ..PasswordOptions::default()
+        };
+        UserService::configure(passwords, "sqlite::memory:".to_string());
+        UserService::init().await.unwrap();
+        let upload_dir =
+            std::env::temp_dir().join(format!("ex18-uploads-{}", Uuid::new_v4().simple()));
+        TransferStore::configure(TransferLimits {
+            upload_dir: upload_dir.clone(),
+            ..TransferLimits::default()
+        });
+        TransferStore::init().unwrap();
+
+        let users = UserService::instance();
+        let alice = users.signup("alice", "battery staple").await.unwrap();
+        let bob = users.signup("bob", "battery staple").await.unwrap();
+        users.join_room(&alice, "secret").await.unwrap();
+        let store = TransferStore::instance();
+        store
+            .start(&alice, "secret", "abc123", TransferKind::File, "a.txt", 3)
+            .await
+            .unwrap();
+        store
+            .write_chunk(&alice, "abc123", 0, b"abc")
+            .await
+            .unwrap();
+
+        let hub = hub();
+        assert!(matches!(
+            resume_download(
+                &hub,
+                users.get_user_by_id(&bob.id).await.unwrap(),
+                1001,
+                "abc123"
+            )
+            .await,
+            Transfer::Abort { .. }
+        ));
+        users.join_room(&bob, "secret").await.unwrap();
+        assert_eq!(
+            Transfer::Chunk {
+                id: "abc123".to_string(),
+                offset: 0,
+                data: b"abc".to_vec(),
+            },
+            resume_download(&hub, bob, 1002, "abc123").await
+        );
+        std::fs::remove_dir_all(&upload_dir).unwrap();
+    }
+}
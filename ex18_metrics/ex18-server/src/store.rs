@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+
+use crate::migrations::Migration;
+use crate::store::postgres::PostgresStore;
+use crate::store::sqlite::SqliteStore;
+use crate::users::{
+    DbUser, DirectMessageView, HistoryCursor, HistoryEntry, MessageFilter, RoomView, SanctionView,
+    StoredMessage,
+};
+
+pub(crate) mod postgres;
+pub(crate) mod sqlite;
+
+/// Database of the server unless configured otherwise, `server.db` in the working directory
+pub const DEFAULT_DATABASE_URL: &str = "sqlite://server.db";
+
+pub(crate) type StoreResult<T> = Result<T, sqlx::Error>;
+
+/// Storage of users, messages, rooms and sanctions. It only keeps and queries them,
+/// the rules about them are up to `UserService`.
+#[rocket::async_trait]
+pub(crate) trait Store: Send + Sync {
+    async fn get_users(&self) -> StoreResult<Vec<DbUser>>;
+
+    async fn get_user_by_id(&self, id: &str) -> StoreResult<Option<DbUser>>;
+
+    async fn get_user_by_name(&self, name: &str) -> StoreResult<Option<DbUser>>;
+
+    /// Inserts the user as a member of the room, returns false if the name is taken
+    async fn insert_user(&self, user: &DbUser, room: &str, joined_at: i64) -> StoreResult<bool>;
+
+    /// Returns false if there is no such user
+    async fn update_user(&self, id: &str, is_admin: bool, is_active: bool) -> StoreResult<bool>;
+
+    /// Returns false if there is no such user
+    async fn set_password(
+        &self,
+        id: &str,
+        password_hash: &str,
+        must_change_password: bool,
+    ) -> StoreResult<bool>;
+
+    /// Returns false if there is no such user
+    async fn set_must_change_password(
+        &self,
+        id: &str,
+        must_change_password: bool,
+    ) -> StoreResult<bool>;
+
+    /// Messages matching the filter, the best matches first when searching for text,
+    /// otherwise the newest first
+    async fn search_messages(
+        &self,
+        filter: &MessageFilter<'_>,
+        limit: u32,
+        offset: u32,
+    ) -> StoreResult<Vec<HistoryEntry>>;
+
+    async fn get_direct_messages(&self) -> StoreResult<Vec<DirectMessageView>>;
+
+    /// Stores the message sent to a room, or directly to the recipient
+    async fn insert_message(
+        &self,
+        id: &str,
+        author_id: &str,
+        room: Option<&str>,
+        recipient_id: Option<&str>,
+        message: &str,
+        sent_at: i64,
+    ) -> StoreResult<()>;
+
+    /// Messages whose id starts with the prefix, which must not contain LIKE wildcards
+    async fn find_messages(&self, id_prefix: &str, limit: u32) -> StoreResult<Vec<StoredMessage>>;
+
+    async fn edit_message(&self, id: &str, text: &str, edited_at: i64) -> StoreResult<()>;
+
+    async fn delete_message(&self, id: &str) -> StoreResult<()>;
+
+    /// Keeps the message for the user, once
+    async fn add_to_inbox(&self, user_id: &str, message_id: &str) -> StoreResult<()>;
+
+    /// Removes the messages kept for the user and returns them, the oldest first
+    async fn take_inbox(&self, user_id: &str) -> StoreResult<Vec<HistoryEntry>>;
+
+    /// Messages in the user's rooms and the user's direct messages, the newest first.
+    /// Only messages older than `before` and not older than `since` are returned.
+    async fn get_history(
+        &self,
+        user_id: &str,
+        before: Option<&HistoryCursor>,
+        since: Option<i64>,
+        limit: u32,
+    ) -> StoreResult<Vec<HistoryEntry>>;
+
+    async fn get_last_logout(&self, user_id: &str) -> StoreResult<Option<i64>>;
+
+    async fn record_logout(&self, user_id: &str, logout_at: i64) -> StoreResult<()>;
+
+    /// Rooms the user is a member of, the most recently joined first
+    async fn get_user_rooms(&self, user_id: &str) -> StoreResult<Vec<String>>;
+
+    /// Joining a room again updates the time it was joined
+    async fn join_room(&self, user_id: &str, room: &str, joined_at: i64) -> StoreResult<()>;
+
+    /// Returns whether the user was a member of the room
+    async fn leave_room(&self, user_id: &str, room: &str) -> StoreResult<bool>;
+
+    /// Rooms having at least one member, by name
+    async fn get_rooms(&self) -> StoreResult<Vec<RoomView>>;
+
+    /// Replaces the sanction of the kind the user had
+    async fn set_sanction(
+        &self,
+        user_id: &str,
+        kind: &str,
+        expires_at: Option<i64>,
+    ) -> StoreResult<()>;
+
+    async fn delete_sanction(&self, user_id: &str, kind: &str) -> StoreResult<()>;
+
+    /// Expiry of the sanction of the kind unless it has expired by `now`,
+    /// `Some(None)` for a sanction for good
+    async fn get_sanction(
+        &self,
+        user_id: &str,
+        kind: &str,
+        now: i64,
+    ) -> StoreResult<Option<Option<i64>>>;
+
+    /// Sanctions that have not expired by `now`, by user name
+    async fn get_sanctions(&self, now: i64) -> StoreResult<Vec<SanctionView>>;
+
+    /// Unix time every recorded migration was applied at, by version,
+    /// `None` if the database does not record versions yet
+    async fn recorded_migrations(&self) -> StoreResult<Option<HashMap<i64, i64>>>;
+
+    /// Version of a database created before versions were recorded, 0 for a new database
+    async fn unrecorded_version(&self) -> StoreResult<i64> {
+        Ok(0)
+    }
+
+    /// Starts recording versions with the migrations the database already has
+    async fn record_migrations(&self, migrations: &[Migration]) -> StoreResult<()>;
+
+    /// Applies and records the migration in one transaction
+    async fn apply_migration(&self, migration: &Migration) -> StoreResult<()>;
+
+    /// Lets running queries and transactions finish, then closes all connections
+    async fn close(&self);
+}
+
+/// Opens the database at the `sqlite:` or `postgres:` URL. A missing SQLite database
+/// is created if asked to, PostgreSQL databases have to exist.
+pub(crate) async fn open_store(url: &str, create_if_missing: bool) -> StoreResult<Box<dyn Store>> {
+    if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+        Ok(Box::new(PostgresStore::open(url).await?))
+    } else if url.starts_with("sqlite:") {
+        Ok(Box::new(SqliteStore::open(url, create_if_missing).await?))
+    } else {
+        Err(sqlx::Error::Configuration(
+            "Database URL must start with sqlite: or postgres:".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rocket::tokio;
+    use uuid::Uuid;
+
+    use ex18_shared::message::{Envelope, Message};
+
+    use crate::migrations::migrate;
+    use crate::passwords::{hash_password, PasswordCost, PasswordOptions};
+    use crate::store::{open_store, Store};
+    use crate::users::{
+        DbUser, MessageFilter, SanctionKind, User, UserError, UserService, DEFAULT_ROOM,
+    };
+
+    /// Database the PostgreSQL test creates a schema of its own in
+    const POSTGRES_URL_VAR: &str = "EX18_TEST_POSTGRES_URL";
+
+    fn passwords() -> PasswordOptions {
+        PasswordOptions {
+            cost: PasswordCost {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            ..PasswordOptions::default()
+        }
+    }
+
+    async fn send(
+        users: &UserService,
+        author: &User,
+        room: Option<&str>,
+        recipient: Option<&User>,
+        text: &str,
+        sent_at: i64,
+    ) -> String {
+        let envelope = Envelope {
+            id: Uuid::new_v4().to_string(),
+            sender: author.name.clone(),
+            sent_at,
+            room: room.map(str::to_string),
+            message: Box::new(Message::Text(text.to_string())),
+        };
+        users
+            .save_user_message(author, recipient, &envelope)
+            .await
+            .unwrap();
+        envelope.id
+    }
+
+    /// Exercises the store through the service, the same way on every backend
+    async fn exercise(store: Box<dyn Store>) {
+        let users = UserService::with_store(store, passwords()).await.unwrap();
+
+        let admin = users.authenticate("admin", "admin").await.unwrap();
+        assert!(admin.is_admin && admin.must_change_password);
+        users
+            .change_password(&admin, "correct horse")
+            .await
+            .unwrap();
+        assert!(
+            !users
+                .authenticate("admin", "correct horse")
+                .await
+                .unwrap()
+                .must_change_password
+        );
+        assert!(matches!(
+            users.authenticate("admin", "admin").await,
+            Err(UserError::AuthenticationFailed)
+        ));
+
+        let alice = users.signup("alice", "battery staple").await.unwrap();
+        let bob = users.signup("bob", "battery staple").await.unwrap();
+        assert!(matches!(
+            users.signup("alice", "battery staple").await,
+            Err(UserError::UserAlreadyExists(_))
+        ));
+        assert_eq!(3, users.get_all_users().await.unwrap().len());
+        assert_eq!(bob.id, users.find_user_by_name("bob").await.unwrap().id);
+        users.update_user(&bob.id, false, false).await.unwrap();
+        assert!(!users.get_user_by_id(&bob.id).await.unwrap().is_active);
+        users.update_user(&bob.id, false, true).await.unwrap();
+
+        assert_eq!(
+            vec![DEFAULT_ROOM],
+            users.get_user_rooms(&alice).await.unwrap()
+        );
+        users.join_room(&alice, "rust").await.unwrap();
+        assert_eq!(
+            vec!["rust", DEFAULT_ROOM],
+            users.get_user_rooms(&alice).await.unwrap()
+        );
+        let rooms = users.get_rooms().await.unwrap();
+        assert_eq!(
+            vec![(DEFAULT_ROOM.to_string(), 3), ("rust".to_string(), 1)],
+            rooms
+                .into_iter()
+                .map(|room| (room.name, room.members))
+                .collect::<Vec<_>>()
+        );
+
+        let hello = send(
+            &users,
+            &bob,
+            Some(DEFAULT_ROOM),
+            None,
+            "hello everybody",
+            100,
+        )
+        .await;
+        send(&users, &alice, Some("rust"), None, "borrow checker", 101).await;
+        let direct = send(&users, &bob, None, Some(&alice), "hello alice", 102).await;
+        send(&users, &admin, None, Some(&bob), "hello bob", 103).await;
+
+        let history = users.get_history(&alice, None, None, 10).await.unwrap();
+        assert_eq!(
+            vec!["hello alice", "borrow checker", "hello everybody"],
+            history
+                .iter()
+                .map(|entry| entry.message.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some("alice"), history[0].recipient_name.as_deref());
+        let older = users
+            .get_history(&alice, Some(&history[0].cursor()), Some(101), 10)
+            .await
+            .unwrap();
+        assert_eq!(1, older.len());
+        assert_eq!("borrow checker", older[0].message);
+
+        let found = users
+            .search_messages(
+                &MessageFilter {
+                    text: Some("HELL"),
+                    reader: Some(&alice),
+                    ..MessageFilter::default()
+                },
+                10,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, found.len());
+        assert!(found.iter().all(|entry| entry.message.starts_with("hello")));
+        let by_bob = users
+            .search_messages(
+                &MessageFilter {
+                    author_name: Some("bob"),
+                    ..MessageFilter::default()
+                },
+                10,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![hello.clone()],
+            by_bob.into_iter().map(|entry| entry.id).collect::<Vec<_>>()
+        );
+        assert_eq!(2, users.get_direct_messages().await.unwrap().len());
+
+        let stored = users.find_message(&direct[..8]).await.unwrap();
+        assert_eq!(Some(alice.id.clone()), stored.recipient_id);
+        users
+            .edit_message(&hello, "goodbye everybody")
+            .await
+            .unwrap();
+        let found = users
+            .search_messages(
+                &MessageFilter {
+                    text: Some("goodbye"),
+                    ..MessageFilter::default()
+                },
+                10,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(1, found.len());
+        assert!(found[0].edited_at.is_some());
+
+        users.add_to_inbox(&alice, &hello).await.unwrap();
+        users.add_to_inbox(&alice, &hello).await.unwrap();
+        assert_eq!(1, users.take_inbox(&alice).await.unwrap().len());
+        assert!(users.take_inbox(&alice).await.unwrap().is_empty());
+        users.add_to_inbox(&alice, &hello).await.unwrap();
+        users.delete_message(&hello).await.unwrap();
+        assert!(users.take_inbox(&alice).await.unwrap().is_empty());
+        assert!(matches!(
+            users.find_message(&hello).await,
+            Err(UserError::NoSuchMessage(_))
+        ));
+
+        assert!(users.leave_room(&alice, "rust").await.unwrap());
+        assert!(!users.leave_room(&alice, "rust").await.unwrap());
+
+        assert_eq!(None, users.get_last_logout(&alice).await.unwrap());
+        users.record_logout(&alice).await.unwrap();
+        assert!(users.get_last_logout(&alice).await.unwrap().is_some());
+
+        users
+            .impose_sanction(&bob, SanctionKind::Ban, None)
+            .await
+            .unwrap();
+        users
+            .impose_sanction(&bob, SanctionKind::Mute, Some(3600))
+            .await
+            .unwrap();
+        assert!(matches!(
+            users.authenticate("bob", "battery staple").await,
+            Err(UserError::Banned(_))
+        ));
+        assert_eq!(2, users.get_sanctions().await.unwrap().len());
+        assert!(users.lift_sanction(&bob, SanctionKind::Ban).await.unwrap());
+        assert!(!users.lift_sanction(&bob, SanctionKind::Ban).await.unwrap());
+        assert!(users.authenticate("bob", "battery staple").await.is_ok());
+
+        // Hashed by earlier versions with SHA-256 and a separate salt
+        let salt = Uuid::new_v4().to_string();
+        let carol = DbUser {
+            id: Uuid::new_v4().to_string(),
+            name: "carol".to_string(),
+            active: true,
+            admin: false,
+            must_change_password: false,
+            password: sha256::digest(format!("battery staple{}", salt)),
+            salt,
+        };
+        users
+            .store()
+            .insert_user(&carol, DEFAULT_ROOM, 0)
+            .await
+            .unwrap();
+        assert!(users.authenticate("carol", "battery staple").await.is_ok());
+        let rehashed = users
+            .store()
+            .get_user_by_name("carol")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rehashed.password.starts_with("$argon2id$"));
+        assert!(users.authenticate("carol", "battery staple").await.is_ok());
+        assert!(matches!(
+            users.authenticate("carol", "battery stable").await,
+            Err(UserError::AuthenticationFailed)
+        ));
+        users.close().await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_in_memory_store() {
+        exercise(open_store("sqlite::memory:", true).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn password_set_before_the_policy_must_be_changed() {
+        let store = open_store("sqlite::memory:", true).await.unwrap();
+        migrate(store.as_ref()).await.unwrap();
+        let admin = DbUser {
+            id: Uuid::new_v4().to_string(),
+            name: "admin".to_string(),
+            active: true,
+            admin: true,
+            must_change_password: false,
+            password: hash_password("admin", &passwords().cost).unwrap(),
+            salt: String::new(),
+        };
+        store.insert_user(&admin, DEFAULT_ROOM, 0).await.unwrap();
+        let hash = admin.password.clone();
+        let users = UserService::with_store(store, passwords()).await.unwrap();
+
+        let admin = users.authenticate("admin", "admin").await.unwrap();
+        assert!(admin.must_change_password);
+        assert!(
+            users
+                .authenticate("admin", "admin")
+                .await
+                .unwrap()
+                .must_change_password
+        );
+        // The hash has the current cost already, only the flag is set
+        let stored = users.store().get_user_by_name("admin").await.unwrap();
+        assert_eq!(hash, stored.unwrap().password);
+        users
+            .change_password(&admin, "correct horse")
+            .await
+            .unwrap();
+        assert!(
+            !users
+                .authenticate("admin", "correct horse")
+                .await
+                .unwrap()
+                .must_change_password
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs EX18_TEST_POSTGRES_URL"]
+    async fn postgres_store() {
+        let url = env::var(POSTGRES_URL_VAR)
+            .unwrap_or_else(|_| panic!("{} is not set", POSTGRES_URL_VAR));
+        let schema = format!("ex18_test_{}", Uuid::new_v4().simple());
+        let pool = sqlx::PgPool::connect(&url).await.unwrap();
+        sqlx::query(&format!("create schema {}", schema))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let schema_url = format!("{}{}options[search_path]={}", url, separator, schema);
+        exercise(open_store(&schema_url, false).await.unwrap()).await;
+        sqlx::query(&format!("drop schema {} cascade", schema))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{PgConnection, Pool, Postgres, QueryBuilder};
+
+use crate::migrations::Migration;
+use crate::store::{Store, StoreResult};
+use crate::users::{
+    unix_timestamp, DbUser, DirectMessageView, HistoryCursor, HistoryEntry, MessageFilter,
+    RoomView, SanctionView, StoredMessage,
+};
+
+/// Messages are numbered by `seq` in the order of insertion, like in SQLite,
+/// and searched by the `search` column holding their text search vector
+pub(crate) struct PostgresStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresStore {
+    pub(crate) async fn open(url: &str) -> StoreResult<PostgresStore> {
+        let pool = PgPoolOptions::new().connect(url).await?;
+        Ok(PostgresStore { pool })
+    }
+}
+
+#[rocket::async_trait]
+impl Store for PostgresStore {
+    async fn get_users(&self) -> StoreResult<Vec<DbUser>> {
+        sqlx::query_as("select id,name,active,admin,must_change_password,password,salt from users")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_user_by_id(&self, id: &str) -> StoreResult<Option<DbUser>> {
+        sqlx::query_as(
+            "select id,name,active,admin,must_change_password,password,salt from users where id=$1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn get_user_by_name(&self, name: &str) -> StoreResult<Option<DbUser>> {
+        sqlx::query_as("select id,name,active,admin,must_change_password,password,salt from users where name=$1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn insert_user(&self, user: &DbUser, room: &str, joined_at: i64) -> StoreResult<bool> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(
+            r#"
+        insert into users(id, name, active, admin, must_change_password, salt, password)
+        values($1,$2,$3,$4,$5,$6,$7)
+        on conflict(name) do nothing"#,
+        )
+        .bind(&user.id)
+        .bind(&user.name)
+        .bind(user.active)
+        .bind(user.admin)
+        .bind(user.must_change_password)
+        .bind(&user.salt)
+        .bind(&user.password)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("insert into room_members(user_id, room, joined_at) values($1,$2,$3)")
+            .bind(&user.id)
+            .bind(room)
+            .bind(joined_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn update_user(&self, id: &str, is_admin: bool, is_active: bool) -> StoreResult<bool> {
+        let result = sqlx::query("update users set active=$1, admin=$2 where id=$3")
+            .bind(is_active)
+            .bind(is_admin)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_password(
+        &self,
+        id: &str,
+        password_hash: &str,
+        must_change_password: bool,
+    ) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "update users set password=$1, salt='', must_change_password=$2 where id=$3",
+        )
+        .bind(password_hash)
+        .bind(must_change_password)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_must_change_password(
+        &self,
+        id: &str,
+        must_change_password: bool,
+    ) -> StoreResult<bool> {
+        let result = sqlx::query("update users set must_change_password=$1 where id=$2")
+            .bind(must_change_password)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn search_messages(
+        &self,
+        filter: &MessageFilter<'_>,
+        limit: u32,
+        offset: u32,
+    ) -> StoreResult<Vec<HistoryEntry>> {
+        let mut query = QueryBuilder::<Postgres>::new(
+            r#"
+        select m.seq, m.id, a.name as author_name, m.room, r.name as recipient_name, m.message,
+               m.sent_at_instant, m.edited_at
+        from user_messages m
+                 join users a on a.id = m.author_id
+                 left join users r on r.id = m.recipient_id"#,
+        );
+        match filter.reader {
+            Some(reader) => query
+                .push(" where (m.recipient_id is null and m.room in (select room from room_members where user_id = ")
+                .push_bind(&reader.id)
+                .push(") or m.recipient_id = ")
+                .push_bind(&reader.id)
+                .push(" or m.recipient_id is not null and m.author_id = ")
+                .push_bind(&reader.id)
+                .push(")"),
+            None => query.push(" where m.recipient_id is null"),
+        };
+        let text = filter.text.and_then(ts_query);
+        if let Some(text) = &text {
+            query
+                .push(" and m.search @@ to_tsquery('simple', ")
+                .push_bind(text.clone())
+                .push(")");
+        }
+        if let Some(author_name) = filter.author_name {
+            query.push(" and a.name = ").push_bind(author_name);
+        }
+        if let Some(since) = filter.since {
+            query.push(" and m.sent_at_instant >= ").push_bind(since);
+        }
+        if let Some(until) = filter.until {
+            query.push(" and m.sent_at_instant < ").push_bind(until);
+        }
+        match text {
+            Some(text) => query
+                .push(" order by ts_rank(m.search, to_tsquery('simple', ")
+                .push_bind(text)
+                .push(")) desc, m.sent_at_instant desc, m.seq desc"),
+            None => query.push(" order by m.sent_at_instant desc, m.seq desc"),
+        };
+        query.push(" limit ").push_bind(i64::from(limit));
+        query.push(" offset ").push_bind(i64::from(offset));
+        query
+            .build_query_as::<HistoryEntry>()
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_direct_messages(&self) -> StoreResult<Vec<DirectMessageView>> {
+        sqlx::query_as(
+            r#"
+        select m.id, a.name as author_name, r.name as recipient_name, m.message, m.sent_at_instant,
+               m.edited_at
+        from user_messages m
+                 join users a on a.id = m.author_id
+                 join users r on r.id = m.recipient_id
+        order by m.sent_at_instant desc"#,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn insert_message(
+        &self,
+        id: &str,
+        author_id: &str,
+        room: Option<&str>,
+        recipient_id: Option<&str>,
+        message: &str,
+        sent_at: i64,
+    ) -> StoreResult<()> {
+        sqlx::query("insert into user_messages(id, author_id, room, recipient_id, message, sent_at_instant) values($1,$2,$3,$4,$5,$6)")
+            .bind(id)
+            .bind(author_id)
+            .bind(room)
+            .bind(recipient_id)
+            .bind(message)
+            .bind(sent_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_messages(&self, id_prefix: &str, limit: u32) -> StoreResult<Vec<StoredMessage>> {
+        sqlx::query_as(
+            "select id, author_id, room, recipient_id from user_messages where id like $1 limit $2",
+        )
+        .bind(format!("{}%", id_prefix))
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn edit_message(&self, id: &str, text: &str, edited_at: i64) -> StoreResult<()> {
+        sqlx::query("update user_messages set message=$1, edited_at=$2 where id=$3")
+            .bind(text)
+            .bind(edited_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, id: &str) -> StoreResult<()> {
+        sqlx::query("delete from user_messages where id=$1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_to_inbox(&self, user_id: &str, message_id: &str) -> StoreResult<()> {
+        sqlx::query("insert into inbox(user_id, message_id) values($1,$2) on conflict do nothing")
+            .bind(user_id)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_inbox(&self, user_id: &str) -> StoreResult<Vec<HistoryEntry>> {
+        let mut tx = self.pool.begin().await?;
+        let entries = sqlx::query_as(
+            r#"
+        select m.seq, m.id, a.name as author_name, m.room, null::text as recipient_name, m.message,
+               m.sent_at_instant, m.edited_at
+        from inbox i
+                 join user_messages m on m.id = i.message_id
+                 join users a on a.id = m.author_id
+        where i.user_id = $1
+        order by m.sent_at_instant, m.seq"#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("delete from inbox where user_id=$1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(entries)
+    }
+
+    async fn get_history(
+        &self,
+        user_id: &str,
+        before: Option<&HistoryCursor>,
+        since: Option<i64>,
+        limit: u32,
+    ) -> StoreResult<Vec<HistoryEntry>> {
+        let before_instant = before.map(|cursor| cursor.sent_at_instant);
+        let before_seq = before.map(|cursor| cursor.seq);
+        sqlx::query_as(
+            r#"
+        select m.seq, m.id, a.name as author_name, m.room, r.name as recipient_name, m.message,
+               m.sent_at_instant, m.edited_at
+        from user_messages m
+                 join users a on a.id = m.author_id
+                 left join users r on r.id = m.recipient_id
+        where (m.recipient_id is null and m.room in (select room from room_members where user_id = $1)
+            or m.recipient_id = $1
+            or m.recipient_id is not null and m.author_id = $1)
+          and ($2::bigint is null or m.sent_at_instant < $2 or m.sent_at_instant = $2 and m.seq < $3)
+          and ($4::bigint is null or m.sent_at_instant >= $4)
+        order by m.sent_at_instant desc, m.seq desc
+        limit $5"#,
+        )
+        .bind(user_id)
+        .bind(before_instant)
+        .bind(before_seq)
+        .bind(since)
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_last_logout(&self, user_id: &str) -> StoreResult<Option<i64>> {
+        sqlx::query_scalar("select last_logout_at from users where id=$1")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn record_logout(&self, user_id: &str, logout_at: i64) -> StoreResult<()> {
+        sqlx::query("update users set last_logout_at=$1 where id=$2")
+            .bind(logout_at)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_rooms(&self, user_id: &str) -> StoreResult<Vec<String>> {
+        sqlx::query_scalar(
+            "select room from room_members where user_id=$1 order by joined_at desc, seq desc",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn join_room(&self, user_id: &str, room: &str, joined_at: i64) -> StoreResult<()> {
+        sqlx::query(
+            r#"insert into room_members(user_id, room, joined_at) values($1,$2,$3)
+                on conflict(user_id, room) do update set joined_at=excluded.joined_at"#,
+        )
+        .bind(user_id)
+        .bind(room)
+        .bind(joined_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn leave_room(&self, user_id: &str, room: &str) -> StoreResult<bool> {
+        let result = sqlx::query("delete from room_members where user_id=$1 and room=$2")
+            .bind(user_id)
+            .bind(room)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_rooms(&self) -> StoreResult<Vec<RoomView>> {
+        sqlx::query_as(
+            "select room as name, count(*) as members from room_members group by room order by room",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn set_sanction(
+        &self,
+        user_id: &str,
+        kind: &str,
+        expires_at: Option<i64>,
+    ) -> StoreResult<()> {
+        sqlx::query(
+            r#"insert into sanctions(user_id, kind, expires_at) values($1,$2,$3)
+                on conflict(user_id, kind) do update set expires_at=excluded.expires_at"#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sanction(&self, user_id: &str, kind: &str) -> StoreResult<()> {
+        sqlx::query("delete from sanctions where user_id=$1 and kind=$2")
+            .bind(user_id)
+            .bind(kind)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_sanction(
+        &self,
+        user_id: &str,
+        kind: &str,
+        now: i64,
+    ) -> StoreResult<Option<Option<i64>>> {
+        sqlx::query_scalar(
+            r#"select expires_at from sanctions
+                where user_id=$1 and kind=$2 and (expires_at is null or expires_at > $3)"#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn get_sanctions(&self, now: i64) -> StoreResult<Vec<SanctionView>> {
+        sqlx::query_as(
+            r#"
+        select u.name as user_name, s.kind, s.expires_at
+        from sanctions s
+                 join users u on u.id = s.user_id
+        where s.expires_at is null or s.expires_at > $1
+        order by u.name, s.kind"#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn recorded_migrations(&self) -> StoreResult<Option<HashMap<i64, i64>>> {
+        let exists: bool = sqlx::query_scalar("select to_regclass('schema_version') is not null")
+            .fetch_one(&self.pool)
+            .await?;
+        if !exists {
+            return Ok(None);
+        }
+        let recorded: Vec<(i64, i64)> =
+            sqlx::query_as("select version, applied_at from schema_version")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(Some(recorded.into_iter().collect()))
+    }
+
+    async fn record_migrations(&self, migrations: &[Migration]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(CREATE_SCHEMA_VERSION).execute(&mut *tx).await?;
+        for migration in migrations {
+            record(&mut tx, migration).await?;
+        }
+        tx.commit().await
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for statement in migration.postgres {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        record(&mut tx, migration).await?;
+        tx.commit().await
+    }
+
+    async fn close(&self) {
+        self.pool.close().await
+    }
+}
+
+const CREATE_SCHEMA_VERSION: &str = r##"
+    create table if not exists schema_version (
+    version    BIGINT not null
+        constraint schema_version_pk
+            primary key,
+    applied_at BIGINT not null
+);
+"##;
+
+async fn record(connection: &mut PgConnection, migration: &Migration) -> StoreResult<()> {
+    sqlx::query("insert into schema_version(version, applied_at) values($1,$2)")
+        .bind(migration.version)
+        .bind(unix_timestamp())
+        .execute(connection)
+        .await
+        .map(|_| ())
+}
+
+/// Text search query matching messages with every word of the text, or a word starting with it.
+/// The words are quoted, so operators typed by users are searched for like other words.
+fn ts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("'{}':*", word.replace('\\', "\\\\").replace('\'', "''")))
+        .collect();
+    match words.is_empty() {
+        true => None,
+        false => Some(words.join(" & ")),
+    }
+}
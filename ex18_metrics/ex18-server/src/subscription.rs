@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::broadcast::Receiver;
+use thiserror::Error;
+
+/// How many broadcast messages wait for slow sessions and how slow a session may be
+#[derive(Debug, Clone, Copy)]
+pub struct BroadcastLimits {
+    /// Messages queued for the sessions, a session further behind misses the oldest ones
+    pub capacity: usize,
+    /// Times a session may fall behind within `lag_window` before it is disconnected
+    pub max_lags: usize,
+    pub lag_window: Duration,
+    /// Replays the missed messages from the database after falling behind
+    pub catch_up: bool,
+}
+
+impl Default for BroadcastLimits {
+    fn default() -> Self {
+        BroadcastLimits {
+            capacity: 20,
+            max_lags: 3,
+            lag_window: Duration::from_secs(60),
+            catch_up: false,
+        }
+    }
+}
+
+/// What a session got from the broadcast queue
+#[derive(Debug, PartialEq)]
+pub(crate) enum Received<T> {
+    Message(T),
+    /// The session fell behind and missed this many messages
+    Lagged(u64),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SubscriptionError {
+    #[error("Fell behind the broadcast queue {0} times within {1:?}")]
+    TooSlow(usize, Duration),
+    #[error("Broadcast queue closed")]
+    Closed,
+}
+
+/// Receiver of the broadcast queue that tells when a session fell behind
+/// and gives up on sessions falling behind too often
+pub(crate) struct Subscription<T> {
+    receiver: Receiver<T>,
+    limits: BroadcastLimits,
+    /// When the session fell behind within the last `lag_window`
+    lags: VecDeque<Instant>,
+}
+
+impl<T: Clone> Subscription<T> {
+    pub fn new(receiver: Receiver<T>, limits: BroadcastLimits) -> Subscription<T> {
+        Subscription {
+            receiver,
+            limits,
+            lags: VecDeque::new(),
+        }
+    }
+
+    /// Waits for the next message. Cancel safe.
+    pub async fn recv(&mut self) -> Result<Received<T>, SubscriptionError> {
+        match self.receiver.recv().await {
+            Ok(message) => Ok(Received::Message(message)),
+            Err(RecvError::Lagged(missed)) => {
+                let now = Instant::now();
+                while let Some(lag) = self.lags.front() {
+                    match now.duration_since(*lag) > self.limits.lag_window {
+                        true => self.lags.pop_front(),
+                        false => break,
+                    };
+                }
+                self.lags.push_back(now);
+                match self.lags.len() > self.limits.max_lags {
+                    true => Err(SubscriptionError::TooSlow(
+                        self.lags.len(),
+                        self.limits.lag_window,
+                    )),
+                    false => Ok(Received::Lagged(missed)),
+                }
+            }
+            Err(RecvError::Closed) => Err(SubscriptionError::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::time::Duration;
+
+    use rocket::tokio;
+    use rocket::tokio::sync::broadcast::{channel, Sender};
+
+    use crate::subscription::{BroadcastLimits, Received, Subscription, SubscriptionError};
+
+    fn limits(max_lags: usize, lag_window: Duration) -> BroadcastLimits {
+        BroadcastLimits {
+            capacity: 4,
+            max_lags,
+            lag_window,
+            catch_up: false,
+        }
+    }
+
+    fn send_all(sender: &Sender<u32>, messages: Range<u32>) {
+        for message in messages {
+            sender.send(message).unwrap();
+        }
+    }
+
+    /// Reads what is left of the ten messages of the round after missing six of them
+    async fn read_rest_of_round(subscription: &mut Subscription<u32>, round: u32) {
+        for message in round * 10 + 6..round * 10 + 10 {
+            assert_eq!(Ok(Received::Message(message)), subscription.recv().await);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_reader_is_told_how_many_messages_it_missed() {
+        let (sender, receiver) = channel(4);
+        let mut subscription = Subscription::new(receiver, limits(3, Duration::from_secs(60)));
+        send_all(&sender, 0..10);
+        assert_eq!(Ok(Received::Lagged(6)), subscription.recv().await);
+        read_rest_of_round(&mut subscription, 0).await;
+    }
+
+    #[tokio::test]
+    async fn reader_lagging_too_often_is_too_slow() {
+        let (sender, receiver) = channel(4);
+        let mut subscription = Subscription::new(receiver, limits(2, Duration::from_secs(60)));
+        for round in 0..2 {
+            send_all(&sender, round * 10..round * 10 + 10);
+            assert_eq!(Ok(Received::Lagged(6)), subscription.recv().await);
+            read_rest_of_round(&mut subscription, round).await;
+        }
+        send_all(&sender, 20..30);
+        assert_eq!(
+            Err(SubscriptionError::TooSlow(3, Duration::from_secs(60))),
+            subscription.recv().await
+        );
+    }
+
+    #[tokio::test]
+    async fn lags_outside_the_window_are_forgotten() {
+        let (sender, receiver) = channel(4);
+        let mut subscription = Subscription::new(receiver, limits(1, Duration::ZERO));
+        for round in 0..3 {
+            send_all(&sender, round * 10..round * 10 + 10);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert_eq!(Ok(Received::Lagged(6)), subscription.recv().await);
+            read_rest_of_round(&mut subscription, round).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_sender_closes_the_subscription() {
+        let (sender, receiver) = channel::<u32>(4);
+        let mut subscription = Subscription::new(receiver, limits(3, Duration::from_secs(60)));
+        drop(sender);
+        assert_eq!(Err(SubscriptionError::Closed), subscription.recv().await);
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rocket::tokio;
+use thiserror::Error;
+use tokio::fs::{remove_file, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::watch;
+
+use ex18_shared::transfer::{
+    IncomingTransfer, Transfer, TransferError, TransferKind, CHUNK_SIZE, PART_SUFFIX,
+};
+
+use crate::transfers::TransferStoreError::{
+    InvalidTransferId, QuotaExceeded, TooLarge, UnknownTransfer,
+};
+use crate::users::User;
+
+/// How long a finished upload stays available for receivers who want to resume,
+/// and how long an unfinished one may stall before the next upload of the user drops it
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+const MAX_ID_LEN: usize = 64;
+/// Upload files are named `<prefix><id><suffix>`, with `.part` appended until they are finished.
+/// Other files in the upload directory are never touched.
+const FILE_PREFIX: &str = "ex18-upload-";
+const FILE_SUFFIX: &str = ".upload";
+static INSTANCE: OnceLock<TransferStore> = OnceLock::new();
+static LIMITS: OnceLock<TransferLimits> = OnceLock::new();
+
+/// Where uploads are kept and how large they may be
+#[derive(Debug, Clone)]
+pub struct TransferLimits {
+    /// Directory of the uploads, upload files left over in it are deleted on start
+    pub upload_dir: PathBuf,
+    /// Largest upload in bytes
+    pub max_size: u64,
+    /// Bytes a user may have in unfinished uploads at once
+    pub max_in_flight: u64,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            upload_dir: PathBuf::from("uploads"),
+            max_size: 100 * 1024 * 1024,
+            max_in_flight: 250 * 1024 * 1024,
+        }
+    }
+}
+
+/// Keeps uploads passing through the server on disk, so that both the uploader
+/// and the receivers can resume them after a reconnect.
+pub struct TransferStore {
+    limits: TransferLimits,
+    transfers: Mutex<HashMap<String, Arc<StoredTransfer>>>,
+    /// Announced bytes of the unfinished uploads by uploader id
+    in_flight: Mutex<HashMap<String, u64>>,
+}
+
+struct StoredTransfer {
+    uploader_id: String,
+    /// Only members of the room may download the upload
+    room: String,
+    size: u64,
+    path: PathBuf,
+    part_path: PathBuf,
+    incoming: tokio::sync::Mutex<Option<IncomingTransfer>>,
+    progress: watch::Sender<Progress>,
+}
+
+#[derive(Clone)]
+struct Progress {
+    stored: u64,
+    sha256: Option<String>,
+    aborted: bool,
+    updated_at: Instant,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            stored: 0,
+            sha256: None,
+            aborted: false,
+            updated_at: Instant::now(),
+        }
+    }
+}
+
+/// Sends a stored upload to a single receiver, starting at the offset the receiver already has.
+/// Waits for more content if the upload is still in progress.
+pub struct ServedTransfer {
+    id: String,
+    file: File,
+    offset: u64,
+    progress: watch::Receiver<Progress>,
+    finished: bool,
+}
+
+impl TransferStore {
+    pub fn instance() -> &'static TransferStore {
+        INSTANCE.get_or_init(|| TransferStore::new().unwrap())
+    }
+
+    /// Prepares the upload directory unless `instance()` did already,
+    /// failing instead of panicking like `instance()`
+    pub fn init() -> Result<(), TransferStoreError> {
+        if INSTANCE.get().is_none() {
+            let _ = INSTANCE.set(TransferStore::new()?);
+        }
+        Ok(())
+    }
+
+    /// Sets the upload directory and the size limits, has no effect after the first `instance()`
+    pub fn configure(limits: TransferLimits) {
+        let _ = LIMITS.set(limits);
+    }
+
+    fn new() -> Result<TransferStore, TransferStoreError> {
+        TransferStore::open(LIMITS.get().cloned().unwrap_or_default())
+    }
+
+    fn open(limits: TransferLimits) -> Result<TransferStore, TransferStoreError> {
+        std::fs::create_dir_all(&limits.upload_dir)?;
+        // Transfers are tracked in memory only, leftovers of previous runs cannot be resumed
+        for entry in std::fs::read_dir(&limits.upload_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file()
+                && is_upload_file_name(&entry.file_name().to_string_lossy())
+            {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(TransferStore {
+            limits,
+            transfers: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers a new upload to the room. Returns the number of bytes already stored
+    /// if the uploader has started this transfer before.
+    /// Refuses uploads larger than `max_size` or exceeding the `max_in_flight` bytes of the uploader.
+    pub async fn start(
+        &self,
+        uploader: &User,
+        room: &str,
+        id: &str,
+        kind: TransferKind,
+        name: &str,
+        size: u64,
+    ) -> Result<Option<u64>, TransferStoreError> {
+        if !is_valid_id(id) {
+            return Err(InvalidTransferId(id.to_string()));
+        }
+        if self.get(id).is_some() {
+            return self.stored_offset(uploader, id).map(Some);
+        }
+        if size > self.limits.max_size {
+            return Err(TooLarge(size, self.limits.max_size));
+        }
+        self.abort_stalled(uploader).await;
+        self.reserve(uploader, size)?;
+        let path = Path::new(&self.limits.upload_dir).join(upload_file_name(id));
+        let incoming = match IncomingTransfer::create(path.clone(), kind, name, size).await {
+            Ok(incoming) => incoming,
+            Err(err) => {
+                self.release(&uploader.id, size);
+                return Err(err.into());
+            }
+        };
+        let stored = Arc::new(StoredTransfer {
+            uploader_id: uploader.id.clone(),
+            room: room.to_string(),
+            size,
+            path,
+            part_path: incoming.part_path().to_path_buf(),
+            incoming: tokio::sync::Mutex::new(Some(incoming)),
+            progress: watch::channel(Progress::default()).0,
+        });
+        self.transfers
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), stored);
+        Ok(None)
+    }
+
+    /// Stores a chunk of an upload, chunks which had been stored before are ignored
+    pub async fn write_chunk(
+        &self,
+        uploader: &User,
+        id: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), TransferStoreError> {
+        let stored = self.get_uploaded_by(uploader, id)?;
+        let mut incoming_guard = stored.incoming.lock().await;
+        let incoming = incoming_guard
+            .as_mut()
+            .ok_or_else(|| UnknownTransfer(id.to_string()))?;
+        incoming.write_chunk(offset, data).await?;
+        let received = incoming.received();
+        stored.progress.send_modify(|progress| {
+            progress.stored = received;
+            progress.updated_at = Instant::now();
+        });
+        Ok(())
+    }
+
+    /// Verifies the finished upload and keeps it available for `RETENTION`.
+    /// Returns `false` if the upload had already been finished with the same checksum.
+    pub async fn finish(
+        &self,
+        uploader: &User,
+        id: &str,
+        sha256: &str,
+    ) -> Result<bool, TransferStoreError> {
+        let stored = self.get_uploaded_by(uploader, id)?;
+        let Some(incoming) = stored.incoming.lock().await.take() else {
+            return match stored.progress.borrow().sha256 {
+                Some(ref finished_sha256) if finished_sha256 == sha256 => Ok(false),
+                _ => Err(UnknownTransfer(id.to_string())),
+            };
+        };
+        self.release(&stored.uploader_id, stored.size);
+        if let Err(err) = incoming.finish(sha256).await {
+            self.remove(id);
+            stored
+                .progress
+                .send_modify(|progress| progress.aborted = true);
+            return Err(err.into());
+        }
+        stored
+            .progress
+            .send_modify(|progress| progress.sha256 = Some(sha256.to_string()));
+        let id = id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(RETENTION).await;
+            if let Some(stored) = TransferStore::instance().remove(&id) {
+                if let Err(err) = remove_file(&stored.path).await {
+                    warn!("Could not remove {}: {}", stored.path.display(), err);
+                }
+            }
+        });
+        Ok(true)
+    }
+
+    pub async fn abort(&self, uploader: &User, id: &str) -> Result<(), TransferStoreError> {
+        let stored = self.get_uploaded_by(uploader, id)?;
+        self.remove(id);
+        if let Some(incoming) = stored.incoming.lock().await.take() {
+            self.release(&stored.uploader_id, stored.size);
+            incoming.abort().await;
+        }
+        stored
+            .progress
+            .send_modify(|progress| progress.aborted = true);
+        Ok(())
+    }
+
+    /// Number of bytes of the upload stored so far
+    pub fn stored_offset(&self, uploader: &User, id: &str) -> Result<u64, TransferStoreError> {
+        Ok(self.get_uploaded_by(uploader, id)?.progress.borrow().stored)
+    }
+
+    /// Opens the upload for a receiver who already has the first `offset` bytes.
+    /// Uploads to rooms other than the `rooms` of the receiver are unknown to them.
+    pub async fn serve(
+        &self,
+        id: &str,
+        offset: u64,
+        rooms: &[String],
+    ) -> Result<ServedTransfer, TransferStoreError> {
+        let stored = self
+            .get(id)
+            .filter(|stored| rooms.contains(&stored.room))
+            .ok_or_else(|| UnknownTransfer(id.to_string()))?;
+        if offset > stored.size {
+            return Err(TransferError::SizeMismatch(stored.size, offset).into());
+        }
+        // The upload may have been verified and moved in the meantime
+        let mut file = match File::open(&stored.part_path).await {
+            Ok(file) => file,
+            Err(_) => File::open(&stored.path).await?,
+        };
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        Ok(ServedTransfer {
+            id: id.to_string(),
+            file,
+            offset,
+            progress: stored.progress.subscribe(),
+            finished: false,
+        })
+    }
+
+    /// Drops the unfinished uploads of the user which got no chunk for `RETENTION`,
+    /// so that uploads abandoned by a client don't count against the user forever
+    async fn abort_stalled(&self, uploader: &User) {
+        let stalled: Vec<String> = self
+            .transfers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stored)| {
+                let progress = stored.progress.borrow();
+                stored.uploader_id == uploader.id
+                    && progress.sha256.is_none()
+                    && !progress.aborted
+                    && progress.updated_at.elapsed() > RETENTION
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stalled {
+            let _ = self.abort(uploader, &id).await;
+        }
+    }
+
+    /// Counts the announced size of an upload against the bytes the uploader may have in flight
+    fn reserve(&self, uploader: &User, size: u64) -> Result<(), TransferStoreError> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let uploading = in_flight.entry(uploader.id.clone()).or_default();
+        if *uploading + size > self.limits.max_in_flight {
+            return Err(QuotaExceeded(self.limits.max_in_flight));
+        }
+        *uploading += size;
+        Ok(())
+    }
+
+    fn release(&self, uploader_id: &str, size: u64) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(uploading) = in_flight.get_mut(uploader_id) {
+            *uploading = uploading.saturating_sub(size);
+            if *uploading == 0 {
+                in_flight.remove(uploader_id);
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<StoredTransfer>> {
+        self.transfers.lock().unwrap().get(id).cloned()
+    }
+
+    fn get_uploaded_by(
+        &self,
+        uploader: &User,
+        id: &str,
+    ) -> Result<Arc<StoredTransfer>, TransferStoreError> {
+        self.get(id)
+            .filter(|stored| stored.uploader_id == uploader.id)
+            .ok_or_else(|| UnknownTransfer(id.to_string()))
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<StoredTransfer>> {
+        self.transfers.lock().unwrap().remove(id)
+    }
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn upload_file_name(id: &str) -> String {
+    format!("{}{}{}", FILE_PREFIX, id, FILE_SUFFIX)
+}
+
+/// Whether the file is a finished or unfinished upload of the store
+fn is_upload_file_name(name: &str) -> bool {
+    let name = name.strip_suffix(PART_SUFFIX).unwrap_or(name);
+    name.strip_prefix(FILE_PREFIX)
+        .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+        .is_some_and(is_valid_id)
+}
+
+impl ServedTransfer {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Waits until `next_message` has something to send. Cancel safe.
+    pub async fn ready(&mut self) {
+        let offset = self.offset;
+        let _ = self
+            .progress
+            .wait_for(|progress| {
+                progress.stored > offset || progress.sha256.is_some() || progress.aborted
+            })
+            .await;
+    }
+
+    /// Returns the next frame for the receiver, `None` if the uploader hasn't sent more yet
+    pub async fn next_message(&mut self) -> Result<Option<Transfer>, TransferError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let progress = self.progress.borrow().clone();
+        if self.offset < progress.stored {
+            let chunk_len = CHUNK_SIZE.min((progress.stored - self.offset) as usize);
+            let mut data = vec![0u8; chunk_len];
+            self.file.read_exact(&mut data).await?;
+            let offset = self.offset;
+            self.offset += chunk_len as u64;
+            return Ok(Some(Transfer::Chunk {
+                id: self.id.clone(),
+                offset,
+                data,
+            }));
+        }
+        if let Some(sha256) = progress.sha256 {
+            self.finished = true;
+            return Ok(Some(Transfer::End {
+                id: self.id.clone(),
+                sha256,
+            }));
+        }
+        if progress.aborted || self.progress.has_changed().is_err() {
+            self.finished = true;
+            return Ok(Some(Transfer::Abort {
+                id: self.id.clone(),
+                reason: "Upload was aborted".to_string(),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransferStoreError {
+    #[error(transparent)]
+    TransferError(#[from] TransferError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("Unknown transfer {0}")]
+    UnknownTransfer(String),
+    #[error("Invalid transfer id {0}")]
+    InvalidTransferId(String),
+    #[error("Upload of {0} bytes exceeds the limit of {1} bytes")]
+    TooLarge(u64, u64),
+    #[error("Unfinished uploads may not exceed {0} bytes, wait for them to finish")]
+    QuotaExceeded(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rocket::tokio;
+    use uuid::Uuid;
+
+    use ex18_shared::transfer::TransferKind;
+
+    use crate::transfers::{TransferLimits, TransferStore, TransferStoreError};
+    use crate::users::User;
+
+    fn upload_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ex18-uploads-{}", Uuid::new_v4().simple()))
+    }
+
+    fn limits(upload_dir: PathBuf) -> TransferLimits {
+        TransferLimits {
+            upload_dir,
+            ..TransferLimits::default()
+        }
+    }
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: id.to_string(),
+            is_active: true,
+            is_admin: false,
+            must_change_password: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_leftover_uploads_are_deleted_on_start() {
+        let dir = upload_dir();
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["Dockerfile", "LICENSE", "abc123", "abc123.part"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let store = TransferStore::open(limits(dir.clone())).unwrap();
+        store
+            .start(
+                &user("alice"),
+                "general",
+                "abc123",
+                TransferKind::File,
+                "a.txt",
+                3,
+            )
+            .await
+            .unwrap();
+        assert_eq!(5, fs::read_dir(&dir).unwrap().count());
+
+        // Restart
+        TransferStore::open(limits(dir.clone())).unwrap();
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(vec!["Dockerfile", "LICENSE", "abc123", "abc123.part"], left);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_is_served_only_to_members_of_its_room() {
+        let dir = upload_dir();
+        let store = TransferStore::open(limits(dir.clone())).unwrap();
+        store
+            .start(
+                &user("alice"),
+                "secret",
+                "abc123",
+                TransferKind::File,
+                "a.txt",
+                3,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.serve("abc123", 0, &["general".to_string()]).await,
+            Err(TransferStoreError::UnknownTransfer(_))
+        ));
+        let rooms = ["general".to_string(), "secret".to_string()];
+        assert!(store.serve("abc123", 0, &rooms).await.is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
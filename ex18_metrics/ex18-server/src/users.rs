@@ -1,27 +1,34 @@
 use std::future::Future;
-use std::ops::Deref;
 use std::sync::OnceLock;
 use std::time::SystemTime;
 
-use lazy_static::lazy_static;
 use log::{info, warn};
+use rocket::tokio;
 use rocket::tokio::runtime::Handle;
 use serde_derive::Serialize;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
-use sqlx::{Acquire, Pool, Row, Sqlite, Transaction};
 use thiserror::Error;
 use uuid::Uuid;
 
-use ex18_shared::message::Message;
+use ex18_shared::message::{Envelope, Message};
+use ex18_shared::transfer::{Transfer, TransferKind};
 
 use crate::metrics::Metrics;
-use crate::users::UserError::{AuthenticationFailed, NoSuchUser, Sql, UserAlreadyExists};
+use crate::migrations::{migrate, MigrationError};
+use crate::passwords::{
+    hash_password, verify_password, PasswordError, PasswordOptions, Verification,
+};
+use crate::store::{open_store, Store, DEFAULT_DATABASE_URL};
+use crate::users::UserError::{
+    AmbiguousMessageId, AuthenticationFailed, Banned, NoSuchMessage, NoSuchUser, UserAlreadyExists,
+};
 
 pub type UserResult<T> = Result<T, UserError>;
 pub type UserResultVoid = UserResult<()>;
 
-const SQLITE_DB_FILE: &str = "server.db";
+/// Room every new user is a member of
+pub const DEFAULT_ROOM: &str = "general";
 static INSTANCE: OnceLock<UserService> = OnceLock::new();
+static OPTIONS: OnceLock<(PasswordOptions, String)> = OnceLock::new();
 
 #[derive(Serialize)]
 pub struct User {
@@ -29,23 +36,121 @@ pub struct User {
     pub name: String,
     pub is_active: bool,
     pub is_admin: bool,
+    /// Can only change their password until they do, e.g. the first admin
+    pub must_change_password: bool,
 }
 
 #[derive(sqlx::FromRow)]
-struct DbUser {
-    id: String,
-    name: String,
-    active: u8,
-    admin: u8,
-    password: String,
-    salt: String,
+pub(crate) struct DbUser {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) active: bool,
+    pub(crate) admin: bool,
+    pub(crate) must_change_password: bool,
+    /// PHC string, or a SHA-256 digest of earlier versions
+    pub(crate) password: String,
+    /// Salt of the SHA-256 digests, empty for PHC strings
+    pub(crate) salt: String,
 }
 
 #[derive(sqlx::FromRow, Serialize)]
-pub struct UserMessageView {
+pub struct DirectMessageView {
+    pub id: String,
     pub author_name: String,
+    pub recipient_name: String,
     pub message: String,
     pub sent_at_instant: i64,
+    pub edited_at: Option<i64>,
+}
+
+/// Where a stored message went and who wrote it, to edit or delete it
+#[derive(sqlx::FromRow)]
+pub struct StoredMessage {
+    pub id: String,
+    pub author_id: String,
+    pub room: Option<String>,
+    pub recipient_id: Option<String>,
+}
+
+/// Message replayed by `.history` or found by a search, either sent to a room or directly to a user
+#[derive(sqlx::FromRow, Serialize)]
+pub struct HistoryEntry {
+    /// Insertion order of the message
+    pub seq: i64,
+    pub id: String,
+    pub author_name: String,
+    pub room: Option<String>,
+    pub recipient_name: Option<String>,
+    pub message: String,
+    pub sent_at_instant: i64,
+    pub edited_at: Option<i64>,
+}
+
+impl HistoryEntry {
+    /// Position of the entry for fetching the page of older entries
+    pub fn cursor(&self) -> HistoryCursor {
+        HistoryCursor {
+            sent_at_instant: self.sent_at_instant,
+            seq: self.seq,
+        }
+    }
+}
+
+/// Which messages a search finds, every filter is optional
+#[derive(Default)]
+pub struct MessageFilter<'a> {
+    /// Words the message must contain, or start with
+    pub text: Option<&'a str>,
+    pub author_name: Option<&'a str>,
+    /// Unix timestamp of the earliest message
+    pub since: Option<i64>,
+    /// Unix timestamp the messages must be older than
+    pub until: Option<i64>,
+    /// Searches the rooms and direct messages of this user, `None` searches every room
+    pub reader: Option<&'a User>,
+}
+
+/// Keyset position in the history, messages sent in the same second are in insertion order
+pub struct HistoryCursor {
+    pub(crate) sent_at_instant: i64,
+    pub(crate) seq: i64,
+}
+
+/// Restriction an admin imposed on a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+impl SanctionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SanctionKind::Ban => "ban",
+            SanctionKind::Mute => "mute",
+        }
+    }
+}
+
+/// Ban or mute in force
+#[derive(Debug, Clone, Copy)]
+pub struct Sanction {
+    /// Unix timestamp in seconds, `None` for good
+    pub expires_at: Option<i64>,
+}
+
+/// Ban or mute in force as shown in the admin console
+#[derive(sqlx::FromRow, Serialize)]
+pub struct SanctionView {
+    pub user_name: String,
+    pub kind: String,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct RoomView {
+    pub name: String,
+    pub members: i64,
 }
 
 #[derive(Debug, Error)]
@@ -58,10 +163,24 @@ pub enum UserError {
     UserAlreadyExists(String),
     #[error("Authentication failed")]
     AuthenticationFailed,
+    #[error("User is banned")]
+    Banned(Sanction),
+    #[error("There is no message {0}")]
+    NoSuchMessage(String),
+    #[error("Message id {0} is ambiguous, type more of it")]
+    AmbiguousMessageId(String),
+    #[error(transparent)]
+    Password(#[from] PasswordError),
+    #[error(transparent)]
+    Migration(#[from] MigrationError),
 }
 
 pub struct UserService {
-    pool: Pool<Sqlite>,
+    store: Box<dyn Store>,
+    passwords: PasswordOptions,
+    /// Verified against when the user does not exist, so that unknown usernames take
+    /// as long to fail as wrong passwords and the time does not tell which users exist
+    dummy_hash: String,
 }
 
 impl UserService {
@@ -70,72 +189,118 @@ impl UserService {
             .get_or_init(|| Handle::current().block_on(async { UserService::new().await.unwrap() }))
     }
 
+    /// Opens and migrates the database unless `instance()` did already,
+    /// failing instead of panicking like `instance()`
+    pub async fn init() -> UserResultVoid {
+        if INSTANCE.get().is_none() {
+            let _ = INSTANCE.set(UserService::new().await?);
+        }
+        Ok(())
+    }
+
+    /// Sets the cost of new password hashes, the policy of new passwords and the database URL,
+    /// has no effect after the first `instance()`
+    pub fn configure(passwords: PasswordOptions, database_url: String) {
+        let _ = OPTIONS.set((passwords, database_url));
+    }
+
     pub async fn get_all_users(&self) -> UserResult<Vec<User>> {
-        Ok(UserService::run_sql_metered(
-            sqlx::query_as::<Sqlite, DbUser>("select * from users").fetch_all(&self.pool),
-        )
-        .await?
-        .into_iter()
-        .map(User::from)
-        .collect::<Vec<User>>())
+        Ok(UserService::run_sql_metered(self.store.get_users())
+            .await?
+            .into_iter()
+            .map(User::from)
+            .collect::<Vec<User>>())
     }
 
     pub async fn get_user_by_id(&self, id: &str) -> UserResult<User> {
-        UserService::run_sql_metered(
-            sqlx::query_as::<Sqlite, DbUser>(
-                "select id,name,active,admin,password,salt from users where id=?",
-            )
-            .bind(id)
-            .fetch_optional(&self.pool),
-        )
-        .await?
-        .map(User::from)
-        .ok_or(NoSuchUser(id.to_string()))
+        UserService::run_sql_metered(self.store.get_user_by_id(id))
+            .await?
+            .map(User::from)
+            .ok_or(NoSuchUser(id.to_string()))
     }
 
+    pub async fn find_user_by_name(&self, name: &str) -> UserResult<User> {
+        UserService::run_sql_metered(self.store.get_user_by_name(name))
+            .await?
+            .map(User::from)
+            .ok_or(NoSuchUser(name.to_string()))
+    }
+
+    /// Verifies the password, replacing a legacy or outdated hash of it with a current one
     pub async fn authenticate(&self, username: &str, password: &str) -> UserResult<User> {
-        let mut tx = self.pool.begin().await?;
-        match UserService::get_user_by_name(&mut tx, username).await? {
-            None => Err(AuthenticationFailed),
-            Some(db_user) => {
-                let expected_digest = UserService::get_passwd_digest(password, &db_user.salt);
-                if db_user.active == 1 && db_user.password == expected_digest {
-                    Ok(User::from(db_user))
-                } else {
-                    Err(AuthenticationFailed)
-                }
-            }
+        let db_user = UserService::run_sql_metered(self.store.get_user_by_name(username)).await?;
+        let Some(db_user) = db_user else {
+            self.verify_password(password, &self.dummy_hash, "").await?;
+            return Err(AuthenticationFailed);
+        };
+        let verification = self
+            .verify_password(password, &db_user.password, &db_user.salt)
+            .await?;
+        if !db_user.active || verification == Verification::Failed {
+            return Err(AuthenticationFailed);
+        }
+        let mut user = User::from(db_user);
+        // Passwords chosen before the policy, like admin/admin of an upgraded database,
+        // have to be changed at the next login
+        let weak = !user.must_change_password
+            && self.passwords.policy.check(&user.name, password).is_err();
+        if weak {
+            info!(
+                "The password of {} has to be changed to meet the policy",
+                user.name
+            );
+            UserService::run_sql_metered(self.store.set_must_change_password(&user.id, true))
+                .await?;
+            user.must_change_password = true;
+        }
+        if verification == Verification::Outdated {
+            info!("Rehashing the password of {}", user.name);
+            self.set_password(&user, password, user.must_change_password)
+                .await?;
+        }
+        match self.get_sanction(&user, SanctionKind::Ban).await? {
+            Some(ban) => Err(Banned(ban)),
+            None => Ok(user),
         }
     }
 
     pub async fn signup(&self, username: &str, password: &str) -> UserResult<User> {
-        let mut tx = self.pool.begin().await?;
-        match UserService::get_user_by_name(&mut tx, username).await? {
-            Some(_) => Err(UserAlreadyExists(username.to_string())),
-            None => {
-                let new_id = Uuid::new_v4().to_string();
-                let salt = Uuid::new_v4().to_string();
-                let passwd_digest = UserService::get_passwd_digest(password, &salt);
-                UserService::run_sql_metered(
-                    sqlx::query(
-                        "insert into users(id, name, active, salt, password) values(?,?,?,?,?)",
-                    )
-                    .bind(&new_id)
-                    .bind(username)
-                    .bind(1)
-                    .bind(salt)
-                    .bind(passwd_digest)
-                    .execute(&mut *tx),
-                )
-                .await?;
-                tx.commit().await?;
-                Ok(User {
-                    id: new_id,
-                    name: username.to_string(),
-                    is_active: true,
-                    is_admin: false,
-                })
-            }
+        self.passwords.policy.check(username, password)?;
+        self.create_user(username, password, false).await
+    }
+
+    /// Creates the user in the default room, the password is not checked against the policy
+    async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        must_change_password: bool,
+    ) -> UserResult<User> {
+        // Hashing takes a while, taken names are refused before
+        if UserService::run_sql_metered(self.store.get_user_by_name(username))
+            .await?
+            .is_some()
+        {
+            return Err(UserAlreadyExists(username.to_string()));
+        }
+        let db_user = DbUser {
+            id: Uuid::new_v4().to_string(),
+            name: username.to_string(),
+            active: true,
+            admin: false,
+            must_change_password,
+            password: self.hash_password(password).await?,
+            salt: String::new(),
+        };
+        let created = UserService::run_sql_metered(self.store.insert_user(
+            &db_user,
+            DEFAULT_ROOM,
+            unix_timestamp(),
+        ))
+        .await?;
+        match created {
+            true => Ok(User::from(db_user)),
+            false => Err(UserAlreadyExists(username.to_string())),
         }
     }
 
@@ -145,146 +310,290 @@ impl UserService {
         is_admin: bool,
         is_active: bool,
     ) -> UserResultVoid {
-        let mut tx = self.pool.begin().await?;
-        let result = UserService::run_sql_metered(
-            sqlx::query("update users set active=?, admin=? where id=?")
-                .bind(if is_active { 1 } else { 0 })
-                .bind(if is_admin { 1 } else { 0 })
-                .bind(user_id)
-                .execute(&mut *tx),
-        )
-        .await?;
-        if result.rows_affected() > 0 {
-            tx.commit().await?;
-            Ok(())
-        } else {
-            Err(NoSuchUser(user_id.to_string()))
+        match UserService::run_sql_metered(self.store.update_user(user_id, is_admin, is_active))
+            .await?
+        {
+            true => Ok(()),
+            false => Err(NoSuchUser(user_id.to_string())),
         }
     }
 
+    /// Sets a new password allowed by the policy, the user no longer has to change it
     pub async fn change_password(&self, user: &User, new_password: &str) -> UserResultVoid {
-        let mut tx = self.pool.begin().await?;
-        let new_salt = Uuid::new_v4().to_string();
-        let passwd_digest = UserService::get_passwd_digest(new_password, &new_salt);
-        let result = UserService::run_sql_metered(
-            sqlx::query("update users set password=?, salt=? where id=?")
-                .bind(passwd_digest)
-                .bind(new_salt)
-                .bind(&user.id)
-                .execute(&mut *tx),
-        )
-        .await?;
-        if result.rows_affected() == 1 {
-            tx.commit().await?;
-            Ok(())
-        } else {
-            Err(NoSuchUser(user.name.clone()))
+        self.passwords.policy.check(&user.name, new_password)?;
+        self.set_password(user, new_password, false).await
+    }
+
+    async fn set_password(
+        &self,
+        user: &User,
+        password: &str,
+        must_change_password: bool,
+    ) -> UserResultVoid {
+        let passwd_hash = self.hash_password(password).await?;
+        match UserService::run_sql_metered(self.store.set_password(
+            &user.id,
+            &passwd_hash,
+            must_change_password,
+        ))
+        .await?
+        {
+            true => Ok(()),
+            false => Err(NoSuchUser(user.name.clone())),
         }
     }
 
-    pub async fn get_user_messages(&self) -> UserResult<Vec<UserMessageView>> {
-        Ok(UserService::run_sql_metered(
-            sqlx::query_as::<Sqlite, UserMessageView>(
-                r#"
-        select u.name as author_name, m.message, m.sent_at_instant
-        from user_messages m
-                 join main.users u on u.id = m.author_id
-        order by m.sent_at_instant desc"#,
-            )
-            .fetch_all(&self.pool),
-        )
-        .await?)
+    /// Messages matching the filter, the best matches first when searching for text,
+    /// otherwise the newest first
+    pub async fn search_messages(
+        &self,
+        filter: &MessageFilter<'_>,
+        limit: u32,
+        offset: u32,
+    ) -> UserResult<Vec<HistoryEntry>> {
+        Ok(UserService::run_sql_metered(self.store.search_messages(filter, limit, offset)).await?)
     }
 
-    pub async fn save_user_message(&self, user: &User, message: &Message) -> UserResultVoid {
-        let mut tx = self.pool.begin().await?;
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let message_id = Uuid::new_v4().to_string();
-        let message_str = match message {
+    pub async fn get_direct_messages(&self) -> UserResult<Vec<DirectMessageView>> {
+        Ok(UserService::run_sql_metered(self.store.get_direct_messages()).await?)
+    }
+
+    /// Stores the message sent to a room, or directly to the `recipient`
+    pub async fn save_user_message(
+        &self,
+        author: &User,
+        recipient: Option<&User>,
+        envelope: &Envelope,
+    ) -> UserResultVoid {
+        let message_str = match envelope.message.as_ref() {
             Message::File(filename, _) => Some(format!("[Shared file {}]", filename)),
             Message::Image(_) => Some("[Shared an image]".to_string()),
             Message::Text(text) => Some(text.clone()),
+            Message::Transfer(Transfer::Start {
+                kind: TransferKind::File,
+                name,
+                ..
+            }) => Some(format!("[Shared file {}]", name)),
+            Message::Transfer(Transfer::Start {
+                kind: TransferKind::Image,
+                ..
+            }) => Some("[Shared an image]".to_string()),
             _ => None,
         };
         if let Some(message) = message_str {
-            UserService::run_sql_metered(
-            sqlx::query("insert into user_messages(id, author_id, message, sent_at_instant) values(?,?,?,?)")
-                .bind(&message_id)
-                .bind(&user.id)
-                .bind(message)
-                .bind(timestamp as i64)
-                .execute(&mut *tx)
-            ).await?;
-            tx.commit().await?;
+            UserService::run_sql_metered(self.store.insert_message(
+                &envelope.id,
+                &author.id,
+                envelope.room.as_deref(),
+                recipient.map(|recipient| recipient.id.as_str()),
+                &message,
+                envelope.sent_at,
+            ))
+            .await?;
         }
         Ok(())
     }
 
-    async fn get_user_by_name(
-        tx: &mut Transaction<'_, Sqlite>,
-        name: &str,
-    ) -> UserResult<Option<DbUser>> {
-        sqlx::query_as("select id,name,active,admin,password,salt from users where name=?")
-            .bind(name)
-            .fetch_optional(&mut **tx)
+    /// The message whose id starts with `id_prefix`, users see and type shortened ids
+    pub async fn find_message(&self, id_prefix: &str) -> UserResult<StoredMessage> {
+        // Keeps LIKE wildcards out of the pattern, ids are UUIDs
+        if id_prefix.is_empty() || !id_prefix.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(NoSuchMessage(id_prefix.to_string()));
+        }
+        let mut messages =
+            UserService::run_sql_metered(self.store.find_messages(&id_prefix.to_lowercase(), 2))
+                .await?;
+        match messages.len() {
+            0 => Err(NoSuchMessage(id_prefix.to_string())),
+            1 => Ok(messages.remove(0)),
+            _ => Err(AmbiguousMessageId(id_prefix.to_string())),
+        }
+    }
+
+    pub async fn edit_message(&self, id: &str, text: &str) -> UserResultVoid {
+        Ok(
+            UserService::run_sql_metered(self.store.edit_message(id, text, unix_timestamp()))
+                .await?,
+        )
+    }
+
+    pub async fn delete_message(&self, id: &str) -> UserResultVoid {
+        Ok(UserService::run_sql_metered(self.store.delete_message(id)).await?)
+    }
+
+    /// Keeps the mention of the user for their next login
+    pub async fn add_to_inbox(&self, user: &User, message_id: &str) -> UserResultVoid {
+        Ok(UserService::run_sql_metered(self.store.add_to_inbox(&user.id, message_id)).await?)
+    }
+
+    /// Removes the mentions kept for the user and returns their messages, the oldest first
+    pub async fn take_inbox(&self, user: &User) -> UserResult<Vec<HistoryEntry>> {
+        Ok(UserService::run_sql_metered(self.store.take_inbox(&user.id)).await?)
+    }
+
+    /// Messages in the user's rooms and the user's direct messages, the newest first.
+    /// Only messages older than `before` and not older than `since` are returned.
+    pub async fn get_history(
+        &self,
+        user: &User,
+        before: Option<&HistoryCursor>,
+        since: Option<i64>,
+        limit: u32,
+    ) -> UserResult<Vec<HistoryEntry>> {
+        Ok(
+            UserService::run_sql_metered(self.store.get_history(&user.id, before, since, limit))
+                .await?,
+        )
+    }
+
+    /// When the user's last session ended, `None` if the user has never logged out
+    pub async fn get_last_logout(&self, user: &User) -> UserResult<Option<i64>> {
+        Ok(UserService::run_sql_metered(self.store.get_last_logout(&user.id)).await?)
+    }
+
+    pub async fn record_logout(&self, user: &User) -> UserResultVoid {
+        Ok(
+            UserService::run_sql_metered(self.store.record_logout(&user.id, unix_timestamp()))
+                .await?,
+        )
+    }
+
+    /// Rooms the user is a member of, the most recently joined first
+    pub async fn get_user_rooms(&self, user: &User) -> UserResult<Vec<String>> {
+        Ok(UserService::run_sql_metered(self.store.get_user_rooms(&user.id)).await?)
+    }
+
+    /// Makes the user a member of the room, joining a room again makes it the most recent one
+    pub async fn join_room(&self, user: &User, room: &str) -> UserResultVoid {
+        Ok(
+            UserService::run_sql_metered(self.store.join_room(&user.id, room, unix_timestamp()))
+                .await?,
+        )
+    }
+
+    /// Returns whether the user was a member of the room
+    pub async fn leave_room(&self, user: &User, room: &str) -> UserResult<bool> {
+        Ok(UserService::run_sql_metered(self.store.leave_room(&user.id, room)).await?)
+    }
+
+    /// Rooms having at least one member, by name
+    pub async fn get_rooms(&self) -> UserResult<Vec<RoomView>> {
+        Ok(UserService::run_sql_metered(self.store.get_rooms()).await?)
+    }
+
+    /// Bans or mutes the user for `duration` seconds or for good, replacing the previous sanction
+    pub async fn impose_sanction(
+        &self,
+        user: &User,
+        kind: SanctionKind,
+        duration: Option<u64>,
+    ) -> UserResult<Sanction> {
+        let sanction = Sanction {
+            expires_at: duration.map(|duration| {
+                unix_timestamp().saturating_add(i64::try_from(duration).unwrap_or(i64::MAX))
+            }),
+        };
+        UserService::run_sql_metered(self.store.set_sanction(
+            &user.id,
+            kind.as_str(),
+            sanction.expires_at,
+        ))
+        .await?;
+        Ok(sanction)
+    }
+
+    /// Returns whether the sanction was in force
+    pub async fn lift_sanction(&self, user: &User, kind: SanctionKind) -> UserResult<bool> {
+        let lifted = self.get_sanction(user, kind).await?.is_some();
+        UserService::run_sql_metered(self.store.delete_sanction(&user.id, kind.as_str())).await?;
+        Ok(lifted)
+    }
+
+    /// The sanction of the kind unless it has expired
+    pub async fn get_sanction(
+        &self,
+        user: &User,
+        kind: SanctionKind,
+    ) -> UserResult<Option<Sanction>> {
+        let expires_at = UserService::run_sql_metered(self.store.get_sanction(
+            &user.id,
+            kind.as_str(),
+            unix_timestamp(),
+        ))
+        .await?;
+        Ok(expires_at.map(|expires_at| Sanction { expires_at }))
+    }
+
+    /// Bans and mutes that have not expired, by user name
+    pub async fn get_sanctions(&self) -> UserResult<Vec<SanctionView>> {
+        Ok(UserService::run_sql_metered(self.store.get_sanctions(unix_timestamp())).await?)
+    }
+
+    /// Lets running queries and transactions finish, then closes all connections
+    pub async fn close(&self) {
+        self.store.close().await
+    }
+
+    async fn verify_password(
+        &self,
+        password: &str,
+        stored: &str,
+        salt: &str,
+    ) -> UserResult<Verification> {
+        let (password, cost) = (password.to_string(), self.passwords.cost);
+        let (stored, salt) = (stored.to_string(), salt.to_string());
+        UserService::run_hashing(move || Ok(verify_password(&password, &stored, &salt, &cost)))
             .await
-            .map_err(UserError::from)
     }
 
-    fn get_passwd_digest(passwd: &str, salt: &str) -> String {
-        let passwd_with_salt = format!("{}{}", passwd, salt);
-        sha256::digest(passwd_with_salt)
+    async fn hash_password(&self, password: &str) -> UserResult<String> {
+        let (password, cost) = (password.to_string(), self.passwords.cost);
+        UserService::run_hashing(move || hash_password(&password, &cost)).await
+    }
+
+    /// Runs password hashing, which is slow on purpose, outside of the async runtime
+    async fn run_hashing<T, F>(hashing: F) -> UserResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, PasswordError> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(hashing)
+            .await
+            .map_err(|err| PasswordError::Hashing(err.to_string()))?
+            .map_err(UserError::from)
     }
 
     async fn new() -> Result<UserService, UserError> {
-        let connect_options = SqliteConnectOptions::new()
-            .filename(SQLITE_DB_FILE)
-            .create_if_missing(true);
-        let pool = SqlitePoolOptions::new()
-            .connect_with(connect_options)
-            .await?;
-        let inst = UserService { pool };
-        inst.ensure_schema_exists().await?;
-        Ok(inst)
+        let (passwords, database_url) = OPTIONS
+            .get()
+            .cloned()
+            .unwrap_or_else(|| (PasswordOptions::default(), DEFAULT_DATABASE_URL.to_string()));
+        UserService::with_store(open_store(&database_url, true).await?, passwords).await
     }
 
-    async fn ensure_schema_exists(&self) -> Result<(), UserError> {
-        let mut connection = self.pool.acquire().await?;
-        let mut tx = connection.begin().await?;
-        let result = UserService::run_sql_metered(
-            sqlx::query("select name from sqlite_master where type = 'table'").fetch_all(&mut *tx),
-        )
-        .await?
-        .iter()
-        .fold(0, |acc, elem| {
-            let tbl_name = elem.get::<String, usize>(0);
-            match tbl_name.as_str() {
-                "user_messages" | "users" => acc + 1,
-                _ => acc,
-            }
-        });
-        if result == 2 {
-            tx.commit().await?;
-            return Ok(());
-        }
-        info!("Creating a new database as it did not exist before.");
-        for sql in INIT_SQL.deref() {
-            let result = UserService::run_sql_metered(sqlx::query(sql).execute(&mut *tx))
-                .await
-                .map(|_| ())
-                .map_err(Sql);
-            if result.is_err() {
-                return result;
-            }
+    #[cfg(test)]
+    pub(crate) fn store(&self) -> &dyn Store {
+        self.store.as_ref()
+    }
+
+    /// Migrates the database of the store, creating the first admin in a new one
+    pub(crate) async fn with_store(
+        store: Box<dyn Store>,
+        passwords: PasswordOptions,
+    ) -> Result<UserService, UserError> {
+        let cost = passwords.cost;
+        let dummy_hash = UserService::run_hashing(move || hash_password("", &cost)).await?;
+        let inst = UserService {
+            store,
+            passwords,
+            dummy_hash,
+        };
+        if migrate(inst.store.as_ref()).await? == 0 {
+            let admin_user = inst.create_user("admin", "admin", true).await?;
+            inst.update_user(&admin_user.id, true, true).await?;
+            info!("Created first admin user: admin/admin, the password must be changed at the first login.");
         }
-        tx.commit().await?;
-        let admin_user = self.signup("admin", "admin").await?;
-        self.update_user(&admin_user.id, true, true).await?;
-        info!("Created first admin user: admin/admin don't forget to change the credentials.");
-        Ok(())
+        Ok(inst)
     }
 
     async fn run_sql_metered<F, R>(metered_fut: F) -> Result<R, sqlx::Error>
@@ -305,35 +614,11 @@ impl UserService {
     }
 }
 
-lazy_static! {
-    static ref INIT_SQL: Vec<&'static str> = vec![
-        r##"
-        create table main.users (
-        id       TEXT            not null
-            constraint users_pk
-                primary key,
-        name     TEXT,
-        active   INTEGER,
-        admin    INTEGER default 0,
-        password TEXT not null,
-        salt     TEXT not null
-    );
-    "##,
-        "create unique index uq_users_name ON users (name);",
-        r##"
-        create table main.user_messages (
-        id              TEXT    not null
-            constraint user_messages_pk
-                primary key,
-        author_id       TEXT    not null,
-        message         TEXT    not null,
-        sent_at_instant INTEGER not null,
-        foreign key (author_id) REFERENCES users (id)
-    );
-    "##,
-        "create index idx_user_messages_author_id on user_messages (author_id);",
-        "create index idx_user_messages_sent_at on user_messages (sent_at_instant desc);",
-    ];
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
 }
 
 impl From<DbUser> for User {
@@ -341,8 +626,9 @@ impl From<DbUser> for User {
         User {
             id: value.id,
             name: value.name,
-            is_active: value.active > 0,
-            is_admin: value.admin > 0,
+            is_active: value.active,
+            is_admin: value.admin,
+            must_change_password: value.must_change_password,
         }
     }
 }
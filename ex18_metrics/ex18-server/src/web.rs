@@ -1,73 +1,196 @@
-use std::fmt::Debug;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::metrics::Metrics;
+use chrono::{Days, Local, NaiveDate};
 use log::{error, info};
+use rocket::config::Shutdown;
 use rocket::form::Form;
 use rocket::fs::NamedFile;
 use rocket::http::{CookieJar, Status};
-use rocket::response::Redirect;
-use rocket::{get, post, routes, Config};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
+use rocket::{get, post, routes, tokio, Config, State};
 use rocket_dyn_templates::{context, Template};
-use thiserror::Error;
 
-use crate::users::{UserError, UserService};
-use crate::web_user::{LoggedUser, LoginForm, RegisterUserForm, UpdateUserForm};
+use crate::server::ChatHub;
+use crate::users::{MessageFilter, UserError, UserService};
+use crate::web_socket::chat_socket;
+use crate::web_user::{
+    AdminUser, ChangePasswordForm, LoggedUser, LoginForm, MessageSearch, ModerateForm,
+    ModerationAction, RegisterUserForm, SignedInUser, UpdateUserForm,
+};
+use ex18_shared::message::{parse_duration, Moderation};
 
 const ASSETS_DIR: &str = "ex18-server/public";
+const MESSAGES_PAGE_SIZE: u32 = 50;
 
-pub async fn serve_web(addr: SocketAddr) -> Result<(), rocket::Error> {
+/// Serves the web app until `shutdown` completes, then gives requests in flight `grace` to finish
+pub async fn serve_web(
+    addr: SocketAddr,
+    hub: ChatHub,
+    grace: Duration,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), rocket::Error> {
     info!("Web admin console listening on {}", &addr);
 
     let figment = Config::figment();
-    let mut config = Config::default();
-    config.address = addr.ip();
-    config.port = addr.port();
-    rocket::build()
+    let config = Config {
+        address: addr.ip(),
+        port: addr.port(),
+        // Signals are handled by the caller, which shuts the chat down first
+        shutdown: Shutdown {
+            ctrlc: false,
+            #[cfg(unix)]
+            signals: Default::default(),
+            grace: grace.as_secs() as u32,
+            mercy: 0,
+            ..Shutdown::default()
+        },
+        ..Config::default()
+    };
+    let rocket = rocket::build()
         .configure(figment.merge(config))
         .attach(Template::fairing())
+        .manage(hub)
         .mount(
             "/",
             routes![
                 index,
+                chat_redirect,
                 index_redirect,
+                chat,
+                chat_login_redirect,
+                online_users,
                 login,
                 login_execute,
                 login_redirect,
+                login_password_redirect,
+                change_password_page,
+                change_password_login_redirect,
+                change_password,
                 signup,
                 update_user,
+                moderate,
                 assets,
                 metrics,
+                chat_socket,
             ],
         )
-        .launch()
-        .await
-        .map(|_| ())
+        .ignite()
+        .await?;
+    let handle = rocket.shutdown();
+    tokio::spawn(async move {
+        shutdown.await;
+        handle.notify();
+    });
+    rocket.launch().await.map(|_| ())
 }
 
-#[get("/", rank = 1)]
-async fn index(_u: LoggedUser) -> Result<Template, Status> {
+#[get("/?<search..>", rank = 1)]
+async fn index(
+    _u: AdminUser,
+    hub: &State<ChatHub>,
+    search: MessageSearch,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<Template, Status> {
     let user_service = UserService::instance();
     let all_users = user_service.get_all_users().await?;
-    let all_messages = user_service.get_user_messages().await?;
+    let page = search.page.max(1);
+    let offset = (page - 1)
+        .checked_mul(MESSAGES_PAGE_SIZE)
+        .ok_or(Status::BadRequest)?;
+    let filter = MessageFilter {
+        text: non_empty(&search.q),
+        author_name: non_empty(&search.author),
+        since: local_midnight(&search.from, 0),
+        until: local_midnight(&search.to, 1),
+        reader: None,
+    };
+    // One more than shown tells whether there is a next page
+    let mut messages = user_service
+        .search_messages(&filter, MESSAGES_PAGE_SIZE + 1, offset)
+        .await?;
+    let has_next_page = messages.len() > MESSAGES_PAGE_SIZE as usize;
+    messages.truncate(MESSAGES_PAGE_SIZE as usize);
+    let direct_messages = user_service.get_direct_messages().await?;
+    let sanctions = user_service.get_sanctions().await?;
     Ok(Template::render(
         "index",
-        context! {users: all_users, messages: all_messages},
+        context! {
+            users: all_users,
+            sessions: hub.live_sessions(),
+            sanctions: sanctions,
+            messages: messages,
+            search: search,
+            page: page,
+            has_next_page: has_next_page,
+            direct_messages: direct_messages,
+            signup_error: flash.map(|flash| flash.message().to_string()),
+        },
     ))
 }
 
+fn non_empty(field: &str) -> Option<&str> {
+    Some(field.trim()).filter(|field| !field.is_empty())
+}
+
+/// Unix timestamp of the local midnight starting the day `days` after the date
+fn local_midnight(date: &str, days: u64) -> Option<i64> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()?
+        .checked_add_days(Days::new(days))?
+        .and_hms_opt(0, 0, 0)?
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|midnight| midnight.timestamp())
+}
+
 #[get("/", rank = 2)]
+fn chat_redirect(_u: LoggedUser) -> Redirect {
+    Redirect::to("/chat")
+}
+
+#[get("/", rank = 3)]
 fn index_redirect() -> Redirect {
     Redirect::to("/login")
 }
 
+#[get("/chat", rank = 1)]
+fn chat(user: LoggedUser, hub: &State<ChatHub>) -> Template {
+    Template::render(
+        "chat",
+        context! {
+            user_name: user.0.name,
+            max_message_size: hub.frame_limits().post_login,
+        },
+    )
+}
+
+#[get("/chat", rank = 2)]
+fn chat_login_redirect() -> Redirect {
+    Redirect::to("/login")
+}
+
+#[get("/chat/online")]
+fn online_users(_u: LoggedUser, hub: &State<ChatHub>) -> Json<Vec<String>> {
+    Json(hub.online_user_names())
+}
+
 #[get("/login", rank = 1)]
 fn login_redirect(_u: LoggedUser) -> Redirect {
     Redirect::to("/")
 }
 
 #[get("/login", rank = 2)]
+fn login_password_redirect(_u: SignedInUser) -> Redirect {
+    Redirect::to("/change-password")
+}
+
+#[get("/login", rank = 3)]
 fn login() -> Template {
     Template::render("login", context! {})
 }
@@ -76,46 +199,153 @@ fn login() -> Template {
 async fn login_execute(
     login_form: Form<LoginForm>,
     cookies: &CookieJar<'_>,
+    hub: &State<ChatHub>,
+    socket_addr: SocketAddr,
 ) -> Result<Redirect, Template> {
     let failed_login = || Template::render("login", context! {failed:true});
-    let user = UserService::instance()
+    let rate_limiter = hub.rate_limiter();
+    let ip = socket_addr.ip();
+    rate_limiter
+        .admit_login(ip, &login_form.login)
+        .map_err(|throttled| {
+            Template::render("login", context! {throttled: throttled.to_string()})
+        })?;
+    let user = match UserService::instance()
         .authenticate(&login_form.login, &login_form.password)
         .await
-        .map_err(|_| failed_login())?;
-    if user.is_admin {
-        LoggedUser::set_login_cookie(cookies, user.id);
-        Ok(Redirect::to("/"))
-    } else {
-        Err(failed_login())
+    {
+        Ok(user) => user,
+        Err(UserError::AuthenticationFailed) => {
+            rate_limiter.record_login_failure(ip, &login_form.login);
+            return Err(failed_login());
+        }
+        Err(_) => return Err(failed_login()),
+    };
+    rate_limiter.record_login_success(ip, &login_form.login);
+    let target = match user.must_change_password {
+        true => "/change-password",
+        false => "/",
+    };
+    LoggedUser::set_login_cookie(cookies, user.id);
+    Ok(Redirect::to(target))
+}
+
+#[get("/change-password", rank = 1)]
+fn change_password_page(user: SignedInUser) -> Template {
+    Template::render(
+        "change_password",
+        context! {required: user.0.must_change_password},
+    )
+}
+
+#[get("/change-password", rank = 2)]
+fn change_password_login_redirect() -> Redirect {
+    Redirect::to("/login")
+}
+
+/// Sets the new password if the policy allows it, lifting the need to change it
+#[post("/change-password", data = "<change_password_form>")]
+async fn change_password(
+    user: SignedInUser,
+    change_password_form: Form<ChangePasswordForm>,
+) -> Result<Redirect, Template> {
+    let failed = |error: String| {
+        Template::render(
+            "change_password",
+            context! {required: user.0.must_change_password, error: error},
+        )
+    };
+    if change_password_form.password != change_password_form.password_again {
+        return Err(failed("Passwords don't match".to_string()));
+    }
+    match UserService::instance()
+        .change_password(&user.0, &change_password_form.password)
+        .await
+    {
+        Ok(()) => Ok(Redirect::to("/")),
+        Err(UserError::Password(err)) => Err(failed(err.to_string())),
+        Err(err) => {
+            error!("Cannot change the password of {}: {}", user.0.name, err);
+            Err(failed("Password could not be changed".to_string()))
+        }
     }
 }
 
 #[post("/update-user", data = "<update_user_form>")]
 async fn update_user(
-    _user: LoggedUser,
+    _user: AdminUser,
     update_user_form: Form<UpdateUserForm>,
+    hub: &State<ChatHub>,
 ) -> Result<Redirect, Status> {
-    UserService::instance()
+    let user_service = UserService::instance();
+    user_service
         .update_user(
             &update_user_form.user_id,
             update_user_form.is_admin,
             update_user_form.is_active,
         )
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if !update_user_form.is_active {
+        let user = user_service
+            .get_user_by_id(&update_user_form.user_id)
+            .await?;
+        hub.notify_user(&user, "Your account has been deactivated".to_string(), true);
+    }
+    Ok(Redirect::to("/"))
+}
+
+#[post("/moderate", data = "<moderate_form>")]
+async fn moderate(
+    admin: AdminUser,
+    moderate_form: Form<ModerateForm>,
+    hub: &State<ChatHub>,
+) -> Result<Redirect, Status> {
+    let duration = match moderate_form.duration.trim() {
+        "" => None,
+        duration => Some(parse_duration(duration).ok_or(Status::BadRequest)?),
+    };
+    let moderation = match moderate_form.action {
+        ModerationAction::Kick => Moderation::Kick,
+        ModerationAction::Ban => Moderation::Ban(duration),
+        ModerationAction::Mute => Moderation::Mute(duration),
+        ModerationAction::Unban => Moderation::Unban,
+        ModerationAction::Unmute => Moderation::Unmute,
+    };
+    let user = UserService::instance()
+        .get_user_by_id(&moderate_form.user_id)
+        .await?;
+    if user.id == admin.0.id {
+        return Err(Status::BadRequest);
+    }
+    hub.moderate(&user, &moderation)
         .await
         .map_err(|_| Status::InternalServerError)
         .map(|_| Redirect::to("/"))
 }
 
+/// Registers the user, or tells the admin why not on the next page
 #[post("/signup", data = "<signup_form>")]
 async fn signup(
-    _user: LoggedUser,
+    _user: AdminUser,
     signup_form: Form<RegisterUserForm>,
-) -> Result<Redirect, Status> {
-    UserService::instance()
+) -> Result<Redirect, Flash<Redirect>> {
+    match UserService::instance()
         .signup(&signup_form.login, &signup_form.password)
         .await
-        .map_err(|_| Status::InternalServerError)
-        .map(|_| Redirect::to("/"))
+    {
+        Ok(_) => Ok(Redirect::to("/")),
+        Err(err @ (UserError::Password(_) | UserError::UserAlreadyExists(_))) => {
+            Err(Flash::error(Redirect::to("/"), err.to_string()))
+        }
+        Err(err) => {
+            error!("Cannot register {}: {}", signup_form.login, err);
+            Err(Flash::error(
+                Redirect::to("/"),
+                "User could not be registered",
+            ))
+        }
+    }
 }
 
 #[get("/static/<asset..>")]
@@ -133,12 +363,6 @@ async fn metrics() -> Result<String, Status> {
     })
 }
 
-#[derive(Debug, Error)]
-enum WebError {
-    #[error(transparent)]
-    User(#[from] UserError),
-}
-
 impl From<UserError> for Status {
     fn from(_: UserError) -> Self {
         Status::InternalServerError
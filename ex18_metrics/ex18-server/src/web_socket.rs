@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+
+use log::info;
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::{get, State};
+use rocket_ws::result::Error as WebSocketError;
+use rocket_ws::stream::DuplexStream;
+use rocket_ws::{Channel, Config, WebSocket};
+
+use ex18_shared::codec::{Codec, JsonCodec};
+use ex18_shared::message::Message;
+
+use crate::connection::ChatConnection;
+use crate::server::{ChatHub, ServerError};
+use crate::web_user::LoggedUser;
+
+/// Lets browsers join the chat.
+///
+/// Messages to the browser are JSON encoded `Message`s in text frames. The browser sends
+/// either the same JSON or command lines as typed into the console client, e.g. `.login user pass`.
+/// Browsers logged in to the web app join as that user right away. Other clients may only send
+/// messages up to the pre-login frame limit, even after `.login`, as the WebSocket buffers whole
+/// messages before the session sees them.
+#[get("/ws")]
+pub fn chat_socket(
+    ws: WebSocket,
+    hub: &State<ChatHub>,
+    socket_addr: SocketAddr,
+    user: Option<LoggedUser>,
+) -> Channel<'static> {
+    let hub = hub.inner().clone();
+    let max_message_size = match user {
+        Some(_) => hub.frame_limits().post_login,
+        None => hub.frame_limits().pre_login,
+    };
+    let config = Config {
+        max_message_size: Some(max_message_size as usize),
+        max_frame_size: Some(max_message_size as usize),
+        ..Config::default()
+    };
+    ws.config(config).channel(move |stream| {
+        Box::pin(async move {
+            info!("WebSocket client connected from {}", socket_addr);
+            let connection = WebSocketConnection::new(stream);
+            hub.run_session(socket_addr, connection, user.map(|user| user.0))
+                .await;
+            Ok(())
+        })
+    })
+}
+
+struct WebSocketConnection {
+    stream: DuplexStream,
+    max_frame_size: u32,
+}
+
+impl WebSocketConnection {
+    fn new(stream: DuplexStream) -> WebSocketConnection {
+        WebSocketConnection {
+            stream,
+            max_frame_size: u32::MAX,
+        }
+    }
+
+    /// Replies to malformed commands right away. A read cancelled while sending
+    /// such a reply loses only the reply, not a message.
+    async fn parse(&mut self, text: &str) -> Result<Option<Message>, ServerError> {
+        if let Ok(message) = JsonCodec.decode(text.as_bytes()) {
+            return Ok(Some(message));
+        }
+        match Message::from_str(text).await {
+            Ok(message) => Ok(Some(message)),
+            Err(err) => {
+                self.send_message(&Message::Text(err.to_string())).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl ChatConnection for WebSocketConnection {
+    async fn read_next_message(&mut self) -> Result<Option<Message>, ServerError> {
+        loop {
+            let text = match self.stream.next().await {
+                Some(Ok(rocket_ws::Message::Text(text))) => text,
+                Some(Ok(rocket_ws::Message::Binary(bytes))) => {
+                    String::from_utf8_lossy(&bytes).into_owned()
+                }
+                Some(Ok(rocket_ws::Message::Close(_))) | None => {
+                    return Err(WebSocketError::ConnectionClosed.into())
+                }
+                // Pings are answered by the stream itself
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.into()),
+            };
+            if text.len() > self.max_frame_size as usize {
+                return Err(ServerError::MessageTooLarge(
+                    text.len(),
+                    self.max_frame_size,
+                ));
+            }
+            return self.parse(&text).await;
+        }
+    }
+
+    async fn send_message(&mut self, message: &Message) -> Result<(), ServerError> {
+        let json = String::from_utf8(JsonCodec.encode(message)?)
+            .map_err(|err| ServerError::GeneralError(err.to_string()))?;
+        Ok(self.stream.send(rocket_ws::Message::Text(json)).await?)
+    }
+
+    fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+}
@@ -1,15 +1,24 @@
 use rocket::http::{Cookie, CookieJar, Status};
 use rocket::request::{FromRequest, Outcome};
-use rocket::{FromForm, Request};
+use rocket::{FromForm, FromFormField, Request};
+use serde_derive::Serialize;
 
-use crate::users::{User, UserError, UserService};
+use crate::users::{SanctionKind, User, UserError, UserService};
 
+/// Any active user logged in to the web app who is not banned,
+/// even if they have to change their password first
+pub struct SignedInUser(pub User);
+
+/// Signed in user who does not have to change their password first
 pub struct LoggedUser(pub User);
 
+/// Logged in user with admin rights
+pub struct AdminUser(pub User);
+
 const COOKIE_USER_ID: &str = "user_id";
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for LoggedUser {
+impl<'r> FromRequest<'r> for SignedInUser {
     type Error = UserError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
@@ -17,8 +26,18 @@ impl<'r> FromRequest<'r> for LoggedUser {
             None => return Outcome::Forward(Status::Unauthorized),
             Some(c) => c,
         };
-        match UserService::instance().get_user_by_id(cookie.value()).await {
-            Ok(user) if user.is_admin => Outcome::Success(LoggedUser(user)),
+        let user_service = UserService::instance();
+        match user_service.get_user_by_id(cookie.value()).await {
+            Ok(user) if user.is_active => {
+                match user_service.get_sanction(&user, SanctionKind::Ban).await {
+                    Ok(None) => Outcome::Success(SignedInUser(user)),
+                    Ok(Some(_)) => {
+                        request.cookies().remove_private(COOKIE_USER_ID);
+                        Outcome::Forward(Status::Unauthorized)
+                    }
+                    Err(e) => Outcome::Error((Status::InternalServerError, e)),
+                }
+            }
             Ok(_) => {
                 request.cookies().remove_private(COOKIE_USER_ID);
                 Outcome::Forward(Status::Unauthorized)
@@ -29,6 +48,38 @@ impl<'r> FromRequest<'r> for LoggedUser {
     }
 }
 
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoggedUser {
+    type Error = UserError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<SignedInUser>().await {
+            Outcome::Success(SignedInUser(user)) if !user.must_change_password => {
+                Outcome::Success(LoggedUser(user))
+            }
+            Outcome::Success(_) => Outcome::Forward(Status::Forbidden),
+            Outcome::Forward(status) => Outcome::Forward(status),
+            Outcome::Error(e) => Outcome::Error(e),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = UserError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<LoggedUser>().await {
+            Outcome::Success(LoggedUser(user)) if user.is_admin => {
+                Outcome::Success(AdminUser(user))
+            }
+            Outcome::Success(_) => Outcome::Forward(Status::Forbidden),
+            Outcome::Forward(status) => Outcome::Forward(status),
+            Outcome::Error(e) => Outcome::Error(e),
+        }
+    }
+}
+
 impl LoggedUser {
     pub fn set_login_cookie(cookie_jar: &CookieJar<'_>, user_id: String) {
         cookie_jar.add_private(Cookie::new(COOKIE_USER_ID, user_id));
@@ -53,3 +104,43 @@ pub struct RegisterUserForm {
     pub login: String,
     pub password: String,
 }
+
+#[derive(FromForm)]
+pub struct ChangePasswordForm {
+    pub password: String,
+    /// The same password once more, against typos
+    pub password_again: String,
+}
+
+#[derive(FromForm)]
+pub struct ModerateForm {
+    pub user_id: String,
+    pub action: ModerationAction,
+    /// Empty for good, otherwise e.g. `30m`, `2h` or `7d`
+    pub duration: String,
+}
+
+#[derive(FromFormField)]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Mute,
+    Unban,
+    Unmute,
+}
+
+/// Search of the messages in the admin console, empty fields don't filter
+#[derive(FromForm, Serialize)]
+pub struct MessageSearch {
+    #[field(default = String::new())]
+    pub q: String,
+    #[field(default = String::new())]
+    pub author: String,
+    /// First and last day as `YYYY-MM-DD`, in local time
+    #[field(default = String::new())]
+    pub from: String,
+    #[field(default = String::new())]
+    pub to: String,
+    #[field(default = 1)]
+    pub page: u32,
+}
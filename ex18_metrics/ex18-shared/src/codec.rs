@@ -0,0 +1,129 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Prefix of handshake features which offer a codec, e.g. `codec:json`
+pub const CODEC_FEATURE_PREFIX: &str = "codec:";
+
+/// Turns messages into frame payloads and back.
+///
+/// The handshake frames themselves are always bincode, the codec only applies to the messages
+/// sent after it.
+pub trait Codec<T>: Send + Sync {
+    fn encode(&self, message: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+pub struct BincodeCodec;
+
+pub struct JsonCodec;
+
+/// Encodes structs as maps with field names, so that the frames are readable without our types
+pub struct MessagePackCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for BincodeCodec {
+    fn encode(&self, message: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    fn encode(&self, message: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for MessagePackCodec {
+    fn encode(&self, message: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(message)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// Codecs which can be negotiated during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Json,
+    MessagePack,
+}
+
+impl CodecKind {
+    pub const ALL: [CodecKind; 3] = [CodecKind::Bincode, CodecKind::Json, CodecKind::MessagePack];
+
+    /// Handshake feature offering this codec
+    pub const fn feature(&self) -> &'static str {
+        match self {
+            CodecKind::Bincode => "codec:bincode",
+            CodecKind::Json => "codec:json",
+            CodecKind::MessagePack => "codec:msgpack",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        &self.feature()[CODEC_FEATURE_PREFIX.len()..]
+    }
+
+    /// Picks the first codec among negotiated features, bincode if there is none
+    pub fn from_features(features: &[String]) -> CodecKind {
+        features
+            .iter()
+            .filter_map(|feature| feature.strip_prefix(CODEC_FEATURE_PREFIX))
+            .find_map(|name| name.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn codec<T: Serialize + DeserializeOwned>(&self) -> Box<dyn Codec<T>> {
+        match self {
+            CodecKind::Bincode => Box::new(BincodeCodec),
+            CodecKind::Json => Box::new(JsonCodec),
+            CodecKind::MessagePack => Box::new(MessagePackCodec),
+        }
+    }
+}
+
+impl Display for CodecKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = CodecError;
+
+    fn from_str(name: &str) -> Result<CodecKind, CodecError> {
+        CodecKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| CodecError::UnknownCodec(name.to_string()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error(transparent)]
+    BincodeError(#[from] bincode::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    MessagePackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    MessagePackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Unknown codec {0}, expected one of bincode, json, msgpack")]
+    UnknownCodec(String),
+}
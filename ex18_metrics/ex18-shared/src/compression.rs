@@ -0,0 +1,102 @@
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::str::FromStr;
+
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use thiserror::Error;
+
+use crate::compression::CompressionError::{DecompressedFrameTooLarge, UnknownCompression};
+
+/// Prefix of handshake features which offer a compression, e.g. `compression:zstd`
+pub const COMPRESSION_FEATURE_PREFIX: &str = "compression:";
+/// Smaller frames are always sent as they are, compressing them wouldn't pay off
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compressions which can be negotiated during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionKind {
+    Zstd,
+    Deflate,
+}
+
+impl CompressionKind {
+    pub const ALL: [CompressionKind; 2] = [CompressionKind::Zstd, CompressionKind::Deflate];
+
+    /// Handshake feature offering this compression
+    pub const fn feature(&self) -> &'static str {
+        match self {
+            CompressionKind::Zstd => "compression:zstd",
+            CompressionKind::Deflate => "compression:deflate",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        &self.feature()[COMPRESSION_FEATURE_PREFIX.len()..]
+    }
+
+    /// Picks the first compression among negotiated features, `None` if there is none
+    pub fn from_features(features: &[String]) -> Option<CompressionKind> {
+        features
+            .iter()
+            .filter_map(|feature| feature.strip_prefix(COMPRESSION_FEATURE_PREFIX))
+            .find_map(|name| name.parse().ok())
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        match self {
+            CompressionKind::Zstd => Ok(zstd::encode_all(bytes, ZSTD_LEVEL)?),
+            CompressionKind::Deflate => {
+                let mut compressed = Vec::new();
+                DeflateEncoder::new(bytes, flate2::Compression::default())
+                    .read_to_end(&mut compressed)?;
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Fails without inflating further once the result would exceed `limit` bytes
+    pub fn decompress(&self, bytes: &[u8], limit: u32) -> Result<Vec<u8>, CompressionError> {
+        let decoder: Box<dyn Read + '_> = match self {
+            CompressionKind::Zstd => Box::new(zstd::Decoder::new(bytes)?),
+            CompressionKind::Deflate => Box::new(DeflateDecoder::new(bytes)),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > limit as usize {
+            return Err(DecompressedFrameTooLarge(limit));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl Display for CompressionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CompressionKind {
+    type Err = CompressionError;
+
+    fn from_str(name: &str) -> Result<CompressionKind, CompressionError> {
+        CompressionKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| UnknownCompression(name.to_string()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("Decompressed frame exceeds the limit of {0} bytes")]
+    DecompressedFrameTooLarge(u32),
+    #[error("Received a compressed frame, but no compression was negotiated")]
+    NotNegotiated,
+    #[error("Unknown compression {0}, expected one of zstd, deflate")]
+    UnknownCompression(String),
+}
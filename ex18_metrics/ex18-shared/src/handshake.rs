@@ -0,0 +1,45 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Magic bytes every handshake starts with, so that we can tell our peers from random traffic
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"EX18";
+/// Version of the wire protocol. Bump it whenever the `Message` enum or the framing changes.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// First frame sent by the client right after the TCP connection opens.
+///
+/// The layout of this struct must never change, otherwise peers with different versions
+/// would not be able to tell each other that they are incompatible.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub features: Vec<String>,
+}
+
+/// Server's answer to `Hello`. Same as `Hello`, the layout must never change.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloReply {
+    /// Contains the protocol version and the features both sides support
+    Accepted { version: u16, features: Vec<String> },
+    /// Contains a human-readable reason of the rejection
+    Rejected(String),
+}
+
+impl Hello {
+    pub fn new(features: &[&str]) -> Hello {
+        Hello {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// Returns features present both in `ours` and `theirs`, keeping the order of `theirs`
+pub fn common_features(ours: &[&str], theirs: &[String]) -> Vec<String> {
+    theirs
+        .iter()
+        .filter(|f| ours.contains(&f.as_str()))
+        .cloned()
+        .collect()
+}
@@ -1,2 +1,7 @@
+pub mod codec;
+pub mod compression;
+pub mod handshake;
 pub mod message;
 pub mod message_tcp_stream;
+pub mod tls;
+pub mod transfer;
@@ -1,21 +1,28 @@
 use std::fmt::Debug;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
-use rocket::tokio;
 use serde_derive::{Deserialize, Serialize};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 
 use crate::message::Message::Signup;
+use crate::transfer::{Transfer, TransferKind};
+
+/// Messages `.history` replays when no count is given
+pub const HISTORY_PAGE_SIZE: u32 = 20;
+/// Characters of a message id shown to users, enough to refer to the message
+pub const SHORT_ID_LEN: usize = 8;
 
 lazy_static! {
     static ref REGEX_COMPLEX: Regex = Regex::new(r"^\.(\S+) (\S+ )?(\S+)$").unwrap();
     static ref REGEX_SIMPLE: Regex = Regex::new(r"^\.(\S+)$").unwrap();
+    static ref REGEX_DIRECT: Regex = Regex::new(r"^\.msg (\S+) (.+)$").unwrap();
+    static ref REGEX_EDIT: Regex = Regex::new(r"^\.edit (\S+) (.+)$").unwrap();
+    static ref REGEX_SEARCH: Regex = Regex::new(r"^\.search (.+)$").unwrap();
+    static ref REGEX_MENTION: Regex = Regex::new(r"(?:^|\W)@([\w.-]*\w)").unwrap();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Message {
     File(String, Vec<u8>),
     Image(Vec<u8>),
@@ -23,21 +30,133 @@ pub enum Message {
     Login(String, String),
     Signup(String, String),
     Passwd(String),
+    /// Joins a room, or switches to it, messages go to the most recently joined room
+    Join(String),
+    Leave(String),
+    Rooms,
+    /// Lists the online users
+    Who,
+    /// Private text for the named user, delivered to them in an `Envelope` without a room
+    DirectMessage(String, String),
+    History(HistoryRange),
+    /// Searches the messages the user can read for these words, `None` shows more results
+    /// of the previous search
+    Search(Option<String>),
+    /// New text for the message with this id or id prefix
+    Edit(String, String),
+    /// Removes the message with this id or id prefix
+    Delete(String),
+    /// Admin command against the named user
+    Moderate(String, Moderation),
+    Transfer(Transfer),
+    /// Message of another user as relayed by the server
+    Envelope(Envelope),
+    /// Text of another user mentioning the recipient with `@name`, relayed instead of the
+    /// `Envelope`, or delivered at login if the recipient was offline
+    Mention(Envelope),
+    /// Local command asking the client to stream a file as a `Transfer`, never sent over the wire
+    Upload(TransferKind, String),
     Quit,
 }
 
+/// Attributes a relayed message to its sender. Edits and deletions relayed
+/// to other clients carry the id of the changed message.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: String,
+    pub sender: String,
+    /// Unix timestamp in seconds, as the server received the message
+    pub sent_at: i64,
+    /// `None` for direct messages
+    pub room: Option<String>,
+    pub message: Box<Message>,
+}
+
+impl Envelope {
+    pub fn short_id(&self) -> &str {
+        short_id(&self.id)
+    }
+}
+
+/// Beginning of the message id that users see and type
+pub fn short_id(id: &str) -> &str {
+    id.get(..SHORT_ID_LEN).unwrap_or(id)
+}
+
+/// Which messages `.history` replays
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum HistoryRange {
+    /// Up to this many messages older than those replayed so far
+    Older(u32),
+    /// Messages sent since the user logged out last time
+    SinceLogout,
+}
+
+/// What an admin does to a user with `.kick`, `.ban`, `.mute`, `.unban` and `.unmute`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Moderation {
+    /// Ends every session of the user
+    Kick,
+    /// Ends every session and refuses logins, for this many seconds or for good
+    Ban(Option<u64>),
+    /// Refuses messages and uploads of the user, for this many seconds or for good
+    Mute(Option<u64>),
+    Unban,
+    Unmute,
+}
+
+/// Names mentioned with `@name` in the text, in order and without repetitions
+pub fn mentioned_names(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for caps in REGEX_MENTION.captures_iter(text) {
+        let name = caps.get(1).unwrap().as_str();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Parses durations like `90`, `90s`, `15m`, `2h` or `7d` into seconds
+pub fn parse_duration(str: &str) -> Option<u64> {
+    let (count, unit) = match str.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => str.split_at(index),
+        None => (str, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    match count.parse::<u64>() {
+        Ok(count) if count > 0 => count.checked_mul(multiplier),
+        _ => None,
+    }
+}
+
 impl Message {
     /// Creates an instance of `Message` from provided `&str`
     pub async fn from_str(str: &str) -> Result<Message> {
+        if let Some(caps) = REGEX_DIRECT.captures(str) {
+            return Ok(Message::DirectMessage(
+                caps[1].to_string(),
+                caps[2].to_string(),
+            ));
+        }
+        if let Some(caps) = REGEX_EDIT.captures(str) {
+            return Ok(Message::Edit(caps[1].to_string(), caps[2].to_string()));
+        }
+        if let Some(caps) = REGEX_SEARCH.captures(str) {
+            return Ok(Message::Search(Some(caps[1].to_string())));
+        }
         if let Some(caps) = REGEX_COMPLEX.captures(str) {
             let arg = caps.get(3).unwrap().as_str();
             let optional_arg_option = caps.get(2).map(|m| m.as_str().trim());
             return match caps.get(1).unwrap().as_str() {
-                "file" => Ok(Message::File(
-                    arg.to_string(),
-                    Message::buf_from_file(arg).await?,
-                )),
-                "image" => Ok(Message::Image(Message::buf_from_file(arg).await?)),
+                "file" => Ok(Message::Upload(TransferKind::File, arg.to_string())),
+                "image" => Ok(Message::Upload(TransferKind::Image, arg.to_string())),
                 "login" => match optional_arg_option {
                     None => {
                         bail!("Login requires two arguments - username and password")
@@ -52,6 +171,54 @@ impl Message {
                     }
                     Some(optional_arg) => Ok(Signup(optional_arg.to_string(), arg.to_string())),
                 },
+                // Direct messages and edits with some text are matched by their regexes already
+                "msg" => bail!("Use .msg <username> <text>"),
+                "edit" => bail!("Use .edit <message id> <text>"),
+                "delete" => match optional_arg_option {
+                    Some(_) => bail!("Use .delete <message id>"),
+                    None => Ok(Message::Delete(arg.to_string())),
+                },
+                "join" | "leave" => match optional_arg_option {
+                    Some(_) => bail!("Use .join <room> or .leave <room>"),
+                    None if &caps[1] == "join" => Ok(Message::Join(arg.to_string())),
+                    None => Ok(Message::Leave(arg.to_string())),
+                },
+                "kick" | "unban" | "unmute" => match optional_arg_option {
+                    Some(_) => bail!("Use .{} <username>", &caps[1]),
+                    None => {
+                        let moderation = match &caps[1] {
+                            "kick" => Moderation::Kick,
+                            "unban" => Moderation::Unban,
+                            _ => Moderation::Unmute,
+                        };
+                        Ok(Message::Moderate(arg.to_string(), moderation))
+                    }
+                },
+                "ban" | "mute" => {
+                    let (name, duration) = match optional_arg_option {
+                        None => (arg, None),
+                        Some(name) => match parse_duration(arg) {
+                            Some(duration) => (name, Some(duration)),
+                            None => bail!(
+                                "Use .{} <username> [<duration>, e.g. 30m, 2h or 7d]",
+                                &caps[1]
+                            ),
+                        },
+                    };
+                    let moderation = match &caps[1] {
+                        "ban" => Moderation::Ban(duration),
+                        _ => Moderation::Mute(duration),
+                    };
+                    Ok(Message::Moderate(name.to_string(), moderation))
+                }
+                "history" => match (optional_arg_option, arg) {
+                    (None, "since") => Ok(Message::History(HistoryRange::SinceLogout)),
+                    (None, count) => match count.parse() {
+                        Ok(count) if count > 0 => Ok(Message::History(HistoryRange::Older(count))),
+                        _ => bail!("Use .history [<count>|since]"),
+                    },
+                    (Some(_), _) => bail!("Use .history [<count>|since]"),
+                },
                 "passwd" => match optional_arg_option {
                     None => {
                         bail!("Passwd requires the new password to be typed two times")
@@ -70,34 +237,24 @@ impl Message {
             let arg = caps.get(1).unwrap().as_str();
             return match arg {
                 "quit" => Ok(Message::Quit),
+                "rooms" => Ok(Message::Rooms),
+                "who" => Ok(Message::Who),
+                "search" => Ok(Message::Search(None)),
+                "history" => Ok(Message::History(HistoryRange::Older(HISTORY_PAGE_SIZE))),
                 _ => bail!("Unknown command"),
             };
         }
         Ok(Message::Text(str.to_string()))
     }
-
-    async fn buf_from_file(path_str: &str) -> Result<Vec<u8>> {
-        let mut file = File::open(path_str)
-            .await
-            .context(format!("Cannot open file {}", path_str))?;
-        let file_len = file
-            .metadata()
-            .await
-            .context(format!("Cannot get metadata for file {}", path_str))?
-            .len() as usize;
-        let mut buf = Vec::with_capacity(file_len);
-        file.read_to_end(&mut buf)
-            .await
-            .context(format!("Cannot read file {}", path_str))?;
-        Ok(buf)
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use rocket::tokio;
 
-    use crate::message::Message;
+    use crate::message::{
+        mentioned_names, parse_duration, HistoryRange, Message, Moderation, HISTORY_PAGE_SIZE,
+    };
 
     #[tokio::test]
     async fn from_str_creates_a_text_message() {
@@ -162,6 +319,136 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn from_str_creates_room_messages() {
+        assert_eq!(
+            Message::Join("dev".to_string()),
+            Message::from_str(".join dev").await.unwrap()
+        );
+        assert_eq!(
+            Message::Leave("dev".to_string()),
+            Message::from_str(".leave dev").await.unwrap()
+        );
+        assert_eq!(Message::Rooms, Message::from_str(".rooms").await.unwrap());
+        assert_eq!(Message::Who, Message::from_str(".who").await.unwrap());
+        assert!(Message::from_str(".join dev ops").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn from_str_creates_a_direct_message() {
+        let message = Message::from_str(".msg bob hello there").await.unwrap();
+
+        assert_eq!(
+            Message::DirectMessage("bob".to_string(), "hello there".to_string()),
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn from_str_creates_edit_and_delete_messages() {
+        assert_eq!(
+            Message::Edit("1a5b2a31".to_string(), "fixed typo".to_string()),
+            Message::from_str(".edit 1a5b2a31 fixed typo")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Message::Delete("1a5b2a31".to_string()),
+            Message::from_str(".delete 1a5b2a31").await.unwrap()
+        );
+        assert!(Message::from_str(".edit 1a5b2a31").await.is_err());
+        assert!(Message::from_str(".delete").await.is_err());
+    }
+
+    #[test]
+    fn mentioned_names_finds_each_name_once() {
+        assert_eq!(
+            vec!["bob", "carol.smith"],
+            mentioned_names("@bob, have you asked @carol.smith? @bob!")
+        );
+        assert_eq!(vec!["x"], mentioned_names("(@x)"));
+        assert!(mentioned_names("mail bob@example.com or @ me").is_empty());
+    }
+
+    #[tokio::test]
+    async fn from_str_creates_search_messages() {
+        assert_eq!(
+            Message::Search(Some("release date".to_string())),
+            Message::from_str(".search release date").await.unwrap()
+        );
+        assert_eq!(
+            Message::Search(Some("\"quoted\" words and more words".to_string())),
+            Message::from_str(".search \"quoted\" words and more words")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Message::Search(None),
+            Message::from_str(".search").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn from_str_creates_history_messages() {
+        assert_eq!(
+            Message::History(HistoryRange::Older(HISTORY_PAGE_SIZE)),
+            Message::from_str(".history").await.unwrap()
+        );
+        assert_eq!(
+            Message::History(HistoryRange::Older(50)),
+            Message::from_str(".history 50").await.unwrap()
+        );
+        assert_eq!(
+            Message::History(HistoryRange::SinceLogout),
+            Message::from_str(".history since").await.unwrap()
+        );
+        assert!(Message::from_str(".history 0").await.is_err());
+        assert!(Message::from_str(".history yesterday").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn from_str_creates_moderation_messages() {
+        let moderate = |moderation| Message::Moderate("bob".to_string(), moderation);
+
+        assert_eq!(
+            moderate(Moderation::Kick),
+            Message::from_str(".kick bob").await.unwrap()
+        );
+        assert_eq!(
+            moderate(Moderation::Ban(None)),
+            Message::from_str(".ban bob").await.unwrap()
+        );
+        assert_eq!(
+            moderate(Moderation::Ban(Some(7 * 24 * 3600))),
+            Message::from_str(".ban bob 7d").await.unwrap()
+        );
+        assert_eq!(
+            moderate(Moderation::Mute(Some(900))),
+            Message::from_str(".mute bob 15m").await.unwrap()
+        );
+        assert_eq!(
+            moderate(Moderation::Unban),
+            Message::from_str(".unban bob").await.unwrap()
+        );
+        assert_eq!(
+            moderate(Moderation::Unmute),
+            Message::from_str(".unmute bob").await.unwrap()
+        );
+        assert!(Message::from_str(".ban bob forever").await.is_err());
+        assert!(Message::from_str(".kick bob now").await.is_err());
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(Some(90), parse_duration("90"));
+        assert_eq!(Some(90), parse_duration("90s"));
+        assert_eq!(Some(2 * 3600), parse_duration("2h"));
+        assert_eq!(None, parse_duration("0m"));
+        assert_eq!(None, parse_duration("2w"));
+        assert_eq!(None, parse_duration("h"));
+        assert_eq!(None, parse_duration(""));
+    }
+
     #[tokio::test]
     async fn from_str_creates_a_password_message_if_passwords_match() {
         let pass = "pass";
@@ -187,6 +474,6 @@ mod tests {
 
         let message = Message::from_str(&text).await;
 
-        assert!(matches!(message, Err(_)));
+        assert!(message.is_err());
     }
 }
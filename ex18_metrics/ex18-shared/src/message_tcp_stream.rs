@@ -1,20 +1,49 @@
-use std::io::Cursor;
+use std::io;
 use std::marker::PhantomData;
 use std::mem;
 
 use bincode::{deserialize, serialize};
-use log::{debug, error};
+use log::debug;
 use rocket::tokio;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-use crate::message_tcp_stream::MessageTcpStreamError::IncorrectTransmitByteCountError;
+use crate::codec::{Codec, CodecError, CodecKind};
+use crate::compression::{CompressionError, CompressionKind, COMPRESSION_THRESHOLD};
+use crate::handshake::{common_features, Hello, HelloReply, PROTOCOL_MAGIC, PROTOCOL_VERSION};
+use crate::message_tcp_stream::MessageTcpStreamError::{
+    ConnectionClosed, FrameTooLarge, HandshakeError, HandshakeRejected,
+    IncorrectTransmitByteCountError, VersionMismatch,
+};
 
-pub struct MessageTcpStream<T> {
-    tcp_stream: TcpStream,
+/// Frame size limit used unless `set_max_frame_size` says otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+/// Bit of the frame header marking a compressed payload, the remaining bits are the payload size
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+const FRAME_HEADER_SIZE: usize = mem::size_of::<u32>();
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Any stream a `MessageTcpStream` can run over, e.g. a plain `TcpStream` or a TLS stream
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+/// Stream type for code that accepts both plain and TLS connections
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+pub struct MessageTcpStream<T, S = TcpStream> {
+    stream: S,
+    features: Vec<String>,
+    codec_kind: CodecKind,
+    codec: Box<dyn Codec<T>>,
+    compression: Option<CompressionKind>,
+    /// Bytes compression saved since the last `take_compression_savings`
+    compression_savings: u64,
+    max_frame_size: u32,
+    read_buf: Vec<u8>,
     _phantom: PhantomData<T>,
 }
 
@@ -22,65 +51,248 @@ impl<T: Serialize + DeserializeOwned> MessageTcpStream<T> {
     pub fn from_tcp_stream(
         tcp_stream: TcpStream,
     ) -> Result<MessageTcpStream<T>, MessageTcpStreamError> {
+        MessageTcpStream::from_stream(tcp_stream)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, S: AsyncRead + AsyncWrite + Unpin> MessageTcpStream<T, S> {
+    pub fn from_stream(stream: S) -> Result<MessageTcpStream<T, S>, MessageTcpStreamError> {
         Ok(MessageTcpStream {
-            tcp_stream,
+            stream,
+            features: Vec::new(),
+            codec_kind: CodecKind::default(),
+            codec: CodecKind::default().codec(),
+            compression: None,
+            compression_savings: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_buf: Vec::new(),
             _phantom: PhantomData,
         })
     }
 
-    pub async fn read_next_message(&mut self) -> Result<Option<T>, MessageTcpStreamError> {
-        let read_fn = async {
-            let mut size_buf = [0u8; 4];
-            let expected_read = 4 * mem::size_of::<u8>();
-            let bytes_read = self.tcp_stream.read(&mut size_buf).await?;
-            if bytes_read != expected_read {
-                return Err(IncorrectTransmitByteCountError(expected_read, bytes_read));
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// Sets the largest frame the peer is allowed to send us, both before and after decompression.
+    /// Larger frames are rejected as soon as their header arrives, before any buffer is allocated.
+    /// The limit can't exceed 2 GiB, the highest bit of the frame header is `COMPRESSED_FLAG`.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size.min(!COMPRESSED_FLAG);
+    }
+
+    /// Features negotiated during the handshake, empty until the handshake is done
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Codec of the messages, chosen during the handshake
+    pub fn codec(&self) -> CodecKind {
+        self.codec_kind
+    }
+
+    /// Compression of large frames, chosen during the handshake
+    pub fn compression(&self) -> Option<CompressionKind> {
+        self.compression
+    }
+
+    /// Returns the number of bytes compression saved, sent and received,
+    /// since the last call and resets the count
+    pub fn take_compression_savings(&mut self) -> u64 {
+        mem::take(&mut self.compression_savings)
+    }
+
+    fn set_features(&mut self, features: Vec<String>) {
+        self.codec_kind = CodecKind::from_features(&features);
+        self.codec = self.codec_kind.codec();
+        self.compression = CompressionKind::from_features(&features);
+        self.features = features;
+    }
+
+    /// Sends `Hello` to the server and waits for its reply.
+    /// Must be called before any other message is sent over the stream.
+    pub async fn handshake_as_client(
+        &mut self,
+        features: &[&str],
+    ) -> Result<&[String], MessageTcpStreamError> {
+        self.send_frame(&serialize(&Hello::new(features))?, false)
+            .await?;
+        match self.read_handshake_frame::<HelloReply>().await {
+            Ok(Some(HelloReply::Accepted { version, features })) => {
+                if version != PROTOCOL_VERSION {
+                    return Err(VersionMismatch(PROTOCOL_VERSION, version));
+                }
+                self.set_features(features);
+                Ok(&self.features)
             }
-            let message_size = u32::from_le_bytes(size_buf);
-            if message_size == 0 {
-                return Ok::<Option<Vec<u8>>, MessageTcpStreamError>(None);
+            Ok(Some(HelloReply::Rejected(reason))) => Err(HandshakeRejected(reason)),
+            Ok(None) | Err(MessageTcpStreamError::SerdeError(_)) => Err(HandshakeError(
+                "Peer did not answer the handshake, it probably runs an older protocol".to_string(),
+            )),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Waits for `Hello` from the client and accepts it if the client speaks our protocol version.
+    /// Peers that don't are sent a rejection with the reason and an error is returned.
+    pub async fn handshake_as_server(
+        &mut self,
+        features: &[&str],
+    ) -> Result<&[String], MessageTcpStreamError> {
+        let hello = match self.read_handshake_frame::<Hello>().await {
+            Ok(Some(hello)) if hello.magic == PROTOCOL_MAGIC => hello,
+            Ok(_) | Err(MessageTcpStreamError::SerdeError(_)) => {
+                let reason = "Expected a protocol handshake".to_string();
+                self.send_frame(&serialize(&HelloReply::Rejected(reason.clone()))?, false)
+                    .await?;
+                return Err(HandshakeError(reason));
             }
-            Ok(Some(self.read_next_n_bytes(message_size as usize).await?))
+            Err(err) => return Err(err),
         };
-        match read_fn.await {
-            Ok(Some(message_bytes)) => {
-                debug!("Read binary message: {:?}", message_bytes);
-                Ok(Some(deserialize(&message_bytes[..])?))
+        if hello.version != PROTOCOL_VERSION {
+            let err = VersionMismatch(PROTOCOL_VERSION, hello.version);
+            self.send_frame(&serialize(&HelloReply::Rejected(err.to_string()))?, false)
+                .await?;
+            return Err(err);
+        }
+        let features = common_features(features, &hello.features);
+        self.send_frame(
+            &serialize(&HelloReply::Accepted {
+                version: PROTOCOL_VERSION,
+                features: features.clone(),
+            })?,
+            false,
+        )
+        .await?;
+        self.set_features(features);
+        Ok(&self.features)
+    }
+
+    pub async fn read_next_message(&mut self) -> Result<Option<T>, MessageTcpStreamError> {
+        match self.read_frame().await? {
+            Some(message_bytes) => Ok(Some(self.codec.decode(&message_bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn send_message(&mut self, message: &T) -> Result<(), MessageTcpStreamError> {
+        let message_bytes = self.codec.encode(message)?;
+        if let Some(compression) = self.compression {
+            if message_bytes.len() >= COMPRESSION_THRESHOLD {
+                let compressed = compression.compress(&message_bytes)?;
+                if compressed.len() < message_bytes.len() {
+                    self.compression_savings += (message_bytes.len() - compressed.len()) as u64;
+                    return self.send_frame(&compressed, true).await;
+                }
+            }
+        }
+        self.send_frame(&message_bytes, false).await
+    }
+
+    /// Handshake frames are always bincode, whatever codec is negotiated
+    async fn read_handshake_frame<M: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<M>, MessageTcpStreamError> {
+        match self.read_frame().await? {
+            Some(message_bytes) => Ok(Some(deserialize(&message_bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the payload of the next frame. Cancel safe - a partially received frame stays
+    /// buffered and the next call continues where the cancelled one stopped.
+    async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, MessageTcpStreamError> {
+        loop {
+            match self.take_buffered_frame() {
+                Ok(Some((_, message_bytes))) if message_bytes.is_empty() => return Ok(None),
+                Ok(Some((false, message_bytes))) => {
+                    debug!("Read binary message: {:?}", message_bytes);
+                    return Ok(Some(message_bytes));
+                }
+                Ok(Some((true, compressed))) => {
+                    let compression = self.compression.ok_or(CompressionError::NotNegotiated)?;
+                    let message_bytes = compression.decompress(&compressed, self.max_frame_size)?;
+                    debug!("Read compressed binary message: {:?}", message_bytes);
+                    self.compression_savings +=
+                        message_bytes.len().saturating_sub(compressed.len()) as u64;
+                    return Ok(Some(message_bytes));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let _ = self.stream.shutdown().await;
+                    return Err(err);
+                }
             }
-            Err(MessageTcpStreamError::IOError(io_err)) if io_err.raw_os_error() == Some(35) => {
-                Ok(None)
+            self.read_buf.reserve(READ_CHUNK_SIZE);
+            match self.stream.read_buf(&mut self.read_buf).await {
+                Ok(0) if self.read_buf.is_empty() => return Err(ConnectionClosed),
+                Ok(0) => {
+                    return Err(IncorrectTransmitByteCountError(
+                        self.buffered_frame_len(),
+                        self.read_buf.len(),
+                    ))
+                }
+                Ok(_) => {}
+                Err(io_err) if io_err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(io_err) => return Err(io_err.into()),
             }
-            Err(e) => Err(e),
-            Ok(None) => Ok(None),
         }
     }
 
-    pub async fn send_message(&mut self, message: &T) -> Result<(), MessageTcpStreamError> {
-        let vec = serialize(message)?;
-        debug!("Serialized data: {:?}", vec);
-        let size = vec.len() as u32;
-        let size_byte_slice = u32::to_le_bytes(size);
-        let expected_bytes_written = mem::size_of::<u32>() + vec.len() * mem::size_of::<u8>();
-        let bytes_written =
-            self.tcp_stream.write(&size_byte_slice).await? + self.tcp_stream.write(&vec).await?;
-        self.tcp_stream.flush().await?;
-        if bytes_written == expected_bytes_written {
-            Ok(())
-        } else {
-            Err(IncorrectTransmitByteCountError(
-                expected_bytes_written,
-                bytes_written,
-            ))
+    /// Removes the first complete frame from the read buffer
+    /// and returns whether it is compressed along with its payload
+    fn take_buffered_frame(&mut self) -> Result<Option<(bool, Vec<u8>)>, MessageTcpStreamError> {
+        if self.read_buf.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
         }
+        let mut size_buf = [0u8; FRAME_HEADER_SIZE];
+        size_buf.copy_from_slice(&self.read_buf[..FRAME_HEADER_SIZE]);
+        let header = u32::from_le_bytes(size_buf);
+        let compressed = header & COMPRESSED_FLAG != 0;
+        let message_size = header & !COMPRESSED_FLAG;
+        if message_size > self.max_frame_size {
+            return Err(FrameTooLarge(message_size, self.max_frame_size));
+        }
+        let frame_len = FRAME_HEADER_SIZE + message_size as usize;
+        if self.read_buf.len() < frame_len {
+            return Ok(None);
+        }
+        let frame = self.read_buf[FRAME_HEADER_SIZE..frame_len].to_vec();
+        self.read_buf.drain(..frame_len);
+        Ok(Some((compressed, frame)))
     }
 
-    async fn read_next_n_bytes(&mut self, n: usize) -> Result<Vec<u8>, MessageTcpStreamError> {
-        let mut cursor = Cursor::new(vec![0u8; n]);
-        let mut total_bytes = 0usize;
-        while total_bytes < n {
-            total_bytes += self.tcp_stream.read(&mut cursor.get_mut()).await?;
+    fn buffered_frame_len(&self) -> usize {
+        if self.read_buf.len() < FRAME_HEADER_SIZE {
+            return FRAME_HEADER_SIZE;
         }
-        Ok(cursor.into_inner())
+        let mut size_buf = [0u8; FRAME_HEADER_SIZE];
+        size_buf.copy_from_slice(&self.read_buf[..FRAME_HEADER_SIZE]);
+        FRAME_HEADER_SIZE + (u32::from_le_bytes(size_buf) & !COMPRESSED_FLAG) as usize
+    }
+
+    async fn send_frame(
+        &mut self,
+        vec: &[u8],
+        compressed: bool,
+    ) -> Result<(), MessageTcpStreamError> {
+        debug!("Serialized data: {:?}", vec);
+        let size = u32::try_from(vec.len())
+            .ok()
+            .filter(|size| size & COMPRESSED_FLAG == 0)
+            .ok_or_else(|| {
+                let size = u32::try_from(vec.len()).unwrap_or(u32::MAX);
+                FrameTooLarge(size, self.max_frame_size)
+            })?;
+        let header = if compressed {
+            size | COMPRESSED_FLAG
+        } else {
+            size
+        };
+        self.stream.write_all(&u32::to_le_bytes(header)).await?;
+        self.stream.write_all(vec).await?;
+        self.stream.flush().await?;
+        Ok(())
     }
 }
 
@@ -89,7 +301,21 @@ pub enum MessageTcpStreamError {
     #[error(transparent)]
     SerdeError(#[from] bincode::Error),
     #[error(transparent)]
+    CodecError(#[from] CodecError),
+    #[error(transparent)]
+    CompressionError(#[from] CompressionError),
+    #[error(transparent)]
     IOError(#[from] std::io::Error),
     #[error("Expected to read {0} bytes, actually read {1} bytes")]
     IncorrectTransmitByteCountError(usize, usize),
+    #[error("Handshake failed: {0}")]
+    HandshakeError(String),
+    #[error("Handshake rejected by the server: {0}")]
+    HandshakeRejected(String),
+    #[error("Frame of {0} bytes exceeds the limit of {1} bytes")]
+    FrameTooLarge(u32, u32),
+    #[error("Connection closed by the peer")]
+    ConnectionClosed,
+    #[error("Protocol version mismatch: we speak version {0}, the peer speaks version {1}")]
+    VersionMismatch(u16, u16),
 }
@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rocket::tokio;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, InvalidDnsNameError, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error as RustlsError, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::tls::TlsError::{InvalidPin, MissingCertificate, MissingPrivateKey};
+
+/// How the client decides whether to trust the server's certificate
+pub enum ServerTrust<'a> {
+    /// Certificate must be signed by a CA from this PEM file
+    CaFile(&'a Path),
+    /// Certificate's SHA-256 fingerprint must match, see `certificate_fingerprint`
+    Pin(&'a str),
+}
+
+/// Client side TLS settings, kept by the client to reconnect with
+#[derive(Clone)]
+pub struct ClientTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// `server_name` is the DNS name or IP address the server certificate must be issued for
+    pub fn new(trust: ServerTrust, server_name: &str) -> Result<ClientTls, TlsError> {
+        Ok(ClientTls {
+            connector: client_connector(trust)?,
+            server_name: ServerName::try_from(server_name.to_string())?,
+        })
+    }
+
+    pub async fn connect(&self, tcp_stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.connector
+            .connect(self.server_name.clone(), tcp_stream)
+            .await
+    }
+}
+
+/// Creates an acceptor for the server from PEM files with the certificate chain and the private key
+pub fn server_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = read_certificates(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| MissingPrivateKey(key_path.display().to_string()))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Creates a connector for the client
+pub fn client_connector(trust: ServerTrust) -> Result<TlsConnector, TlsError> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let config = match trust {
+        ServerTrust::CaFile(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certificates(ca_path)? {
+                roots.add(cert)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        ServerTrust::Pin(pin) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(pin)?))
+            .with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// SHA-256 fingerprint of the first certificate in a PEM file, the value clients can pin
+pub fn certificate_fingerprint(cert_path: &Path) -> Result<String, TlsError> {
+    let certs = read_certificates(cert_path)?;
+    Ok(fingerprint(&certs[0]))
+}
+
+fn fingerprint(cert: &CertificateDer) -> String {
+    format!("{:x}", Sha256::digest(cert.as_ref()))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(MissingCertificate(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Trusts exactly one certificate, identified by its SHA-256 fingerprint.
+/// Useful with self-signed certificates, the server name is not checked.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(pin: &str) -> Result<PinnedCertVerifier, TlsError> {
+        let fingerprint = pin.replace(':', "").to_lowercase();
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(InvalidPin(pin.to_string()));
+        }
+        Ok(PinnedCertVerifier {
+            fingerprint,
+            provider: provider(),
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(RustlsError::General(
+                "Server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    RustlsError(#[from] RustlsError),
+    #[error("No certificate found in {0}")]
+    MissingCertificate(String),
+    #[error("No private key found in {0}")]
+    MissingPrivateKey(String),
+    #[error("Invalid certificate pin {0}, expected a SHA-256 fingerprint in hex")]
+    InvalidPin(String),
+    #[error(transparent)]
+    InvalidServerName(#[from] InvalidDnsNameError),
+}
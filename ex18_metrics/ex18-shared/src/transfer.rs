@@ -0,0 +1,307 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use rand::random;
+use rocket::tokio;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs::{remove_file, rename, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::transfer::TransferError::{ChecksumMismatch, SizeMismatch, UnexpectedOffset};
+
+/// Maximum number of bytes carried by a single `Transfer::Chunk`
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Suffix of files which are still being received
+pub const PART_SUFFIX: &str = ".part";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TransferKind {
+    File,
+    Image,
+}
+
+/// Frames of the file transfer sub-protocol.
+///
+/// A transfer is `Start`, followed by any number of `Chunk`s and finished either by `End`,
+/// which carries a SHA-256 checksum of the whole content, or by `Abort`.
+/// Frames of different transfers and other messages can be interleaved freely.
+///
+/// The server confirms every stored piece of an upload with `Ack`. After a reconnect, the uploader
+/// asks where to continue with `ResumeUpload` and receivers ask for the rest of the content
+/// with `ResumeDownload`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Transfer {
+    Start {
+        id: String,
+        kind: TransferKind,
+        name: String,
+        size: u64,
+    },
+    Chunk {
+        id: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    End {
+        id: String,
+        sha256: String,
+    },
+    Abort {
+        id: String,
+        reason: String,
+    },
+    Ack {
+        id: String,
+        offset: u64,
+    },
+    ResumeUpload {
+        id: String,
+    },
+    ResumeDownload {
+        id: String,
+        offset: u64,
+    },
+}
+
+impl Transfer {
+    pub fn id(&self) -> &str {
+        match self {
+            Transfer::Start { id, .. }
+            | Transfer::Chunk { id, .. }
+            | Transfer::End { id, .. }
+            | Transfer::Abort { id, .. }
+            | Transfer::Ack { id, .. }
+            | Transfer::ResumeUpload { id }
+            | Transfer::ResumeDownload { id, .. } => id,
+        }
+    }
+}
+
+/// Reads a local file and turns it into `Transfer` frames, one frame at a time
+pub struct OutgoingTransfer {
+    id: String,
+    kind: TransferKind,
+    name: String,
+    file: File,
+    size: u64,
+    offset: u64,
+    hasher: Sha256,
+    started: bool,
+    finished: bool,
+}
+
+impl OutgoingTransfer {
+    pub async fn open(path: &str, kind: TransferKind) -> Result<OutgoingTransfer, TransferError> {
+        let file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| TransferError::InvalidPath(path.to_string()))?
+            .to_string();
+        Ok(OutgoingTransfer {
+            id: format!("{:032x}", random::<u128>()),
+            kind,
+            name,
+            file,
+            size,
+            offset: 0,
+            hasher: Sha256::new(),
+            started: false,
+            finished: false,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Starts over, the next frame will be `Start` again
+    pub async fn restart(&mut self) -> Result<(), TransferError> {
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.hasher = Sha256::new();
+        self.offset = 0;
+        self.started = false;
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Continues after the first `offset` bytes which the other side already has
+    pub async fn resume_from(&mut self, offset: u64) -> Result<(), TransferError> {
+        if offset > self.size {
+            return Err(SizeMismatch(self.size, offset));
+        }
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.hasher = Sha256::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut hashed = 0u64;
+        while hashed < offset {
+            let len = CHUNK_SIZE.min((offset - hashed) as usize);
+            self.file.read_exact(&mut buf[..len]).await?;
+            self.hasher.update(&buf[..len]);
+            hashed += len as u64;
+        }
+        self.offset = offset;
+        self.started = true;
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Returns the next frame of this transfer or `None` once `End` has been returned
+    pub async fn next_message(&mut self) -> Result<Option<Transfer>, TransferError> {
+        if !self.started {
+            self.started = true;
+            return Ok(Some(Transfer::Start {
+                id: self.id.clone(),
+                kind: self.kind,
+                name: self.name.clone(),
+                size: self.size,
+            }));
+        }
+        if self.offset < self.size {
+            let chunk_len = CHUNK_SIZE.min((self.size - self.offset) as usize);
+            let mut data = vec![0u8; chunk_len];
+            self.file.read_exact(&mut data).await?;
+            self.hasher.update(&data);
+            let offset = self.offset;
+            self.offset += chunk_len as u64;
+            return Ok(Some(Transfer::Chunk {
+                id: self.id.clone(),
+                offset,
+                data,
+            }));
+        }
+        if !self.finished {
+            self.finished = true;
+            return Ok(Some(Transfer::End {
+                id: self.id.clone(),
+                sha256: format!("{:x}", self.hasher.clone().finalize()),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// Writes received `Transfer` chunks to a local file and verifies the result.
+/// The content is kept in a `.part` file until it is verified.
+pub struct IncomingTransfer {
+    kind: TransferKind,
+    name: String,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    hasher: Sha256,
+}
+
+impl IncomingTransfer {
+    pub async fn create(
+        path: PathBuf,
+        kind: TransferKind,
+        name: &str,
+        size: u64,
+    ) -> Result<IncomingTransfer, TransferError> {
+        let mut part_path = path.clone().into_os_string();
+        part_path.push(PART_SUFFIX);
+        let part_path = PathBuf::from(part_path);
+        let file = File::create(&part_path).await?;
+        Ok(IncomingTransfer {
+            kind,
+            name: name.to_string(),
+            path,
+            part_path,
+            file,
+            size,
+            received: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    pub fn kind(&self) -> TransferKind {
+        self.kind
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of bytes written so far
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Path of the file holding the content received so far
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    /// Appends a chunk. Chunks must arrive in order and must not exceed the announced size.
+    /// Chunks which were already received, e.g. when resending after a reconnect, are ignored.
+    pub async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), TransferError> {
+        if offset + data.len() as u64 <= self.received {
+            return Ok(());
+        }
+        if offset != self.received {
+            return Err(UnexpectedOffset(self.received, offset));
+        }
+        if self.received + data.len() as u64 > self.size {
+            return Err(SizeMismatch(self.size, self.received + data.len() as u64));
+        }
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Verifies size and checksum of the received content and moves it from the `.part` file
+    /// to its final path. The `.part` file is deleted if they don't match.
+    pub async fn finish(mut self, sha256: &str) -> Result<PathBuf, TransferError> {
+        self.file.flush().await?;
+        let actual = format!("{:x}", self.hasher.clone().finalize());
+        let result = if self.received != self.size {
+            Err(SizeMismatch(self.size, self.received))
+        } else if actual != sha256 {
+            Err(ChecksumMismatch(self.name.clone()))
+        } else {
+            rename(&self.part_path, &self.path).await?;
+            return Ok(self.path);
+        };
+        self.abort().await;
+        result
+    }
+
+    /// Deletes the partially received file
+    pub async fn abort(self) {
+        drop(self.file);
+        if let Err(err) = remove_file(&self.part_path).await {
+            warn!("Could not remove {}: {}", self.part_path.display(), err);
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid file path {0}")]
+    InvalidPath(String),
+    #[error("Expected a chunk at offset {0}, received offset {1}")]
+    UnexpectedOffset(u64, u64),
+    #[error("Expected {0} bytes, received {1} bytes")]
+    SizeMismatch(u64, u64),
+    #[error("Checksum of {0} does not match")]
+    ChecksumMismatch(String),
+}
@@ -1,11 +1,23 @@
+use std::path::PathBuf;
+
 use anyhow::Error;
 use lazy_static::lazy_static;
 use rand::random;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
 use rocket::tokio;
 use tokio::fs::{remove_file, File};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 
-use ex18_shared::message::Message;
+use ex18_shared::codec::{CodecKind, CODEC_FEATURE_PREFIX};
+use ex18_shared::compression::{CompressionError, CompressionKind, COMPRESSION_THRESHOLD};
+use ex18_shared::handshake::{Hello, HelloReply, PROTOCOL_VERSION};
+use ex18_shared::message::{Envelope, HistoryRange, Message, Moderation};
+use ex18_shared::message_tcp_stream::{MessageTcpStream, MessageTcpStreamError, COMPRESSED_FLAG};
+use ex18_shared::tls::{certificate_fingerprint, server_acceptor, ClientTls, ServerTrust};
+use ex18_shared::transfer::{
+    IncomingTransfer, OutgoingTransfer, Transfer, TransferError, TransferKind, CHUNK_SIZE,
+};
 
 lazy_static! {
     static ref CONTENT: Vec<u8> = vec![1, 2, 3, 4, 5];
@@ -15,20 +27,122 @@ async fn delete_test_file(file_name: &str) {
     let _ = remove_file(file_name).await;
 }
 
-async fn create_test_file(file_name: &str) -> Result<String, Error> {
+fn test_file_path(file_name: &str) -> String {
     let rand = random::<u32>();
-    let path = format!(
+    format!(
         "{}/{}{}",
         std::env::temp_dir().as_os_str().to_str().unwrap(),
         rand,
         file_name
-    );
+    )
+}
+
+async fn create_test_file(file_name: &str) -> Result<String, Error> {
+    let path = test_file_path(file_name);
     delete_test_file(&path).await;
     let mut file = File::create(&path).await?;
     let _ = file.write(&CONTENT).await?;
     Ok(path)
 }
 
+async fn connected_pair() -> Result<(TcpStream, TcpStream), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client = TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+    Ok((client, server))
+}
+
+async fn send_raw_frame(tcp_stream: &mut TcpStream, bytes: &[u8]) -> Result<(), Error> {
+    tcp_stream
+        .write_all(&(bytes.len() as u32).to_le_bytes())
+        .await?;
+    tcp_stream.write_all(bytes).await?;
+    Ok(())
+}
+
+/// Paths of PEM files with a test CA and a server certificate for `localhost` signed by it
+struct TestCertificates {
+    ca_cert: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+}
+
+async fn create_test_certificates() -> Result<TestCertificates, Error> {
+    let mut ca_params = CertificateParams::new(Vec::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate()?;
+    let ca_cert = ca_params.self_signed(&ca_key)?;
+    let server_key = KeyPair::generate()?;
+    let server_cert = CertificateParams::new(vec!["localhost".to_string()])?.signed_by(
+        &server_key,
+        &ca_cert,
+        &ca_key,
+    )?;
+
+    let dir = std::env::temp_dir().join(format!("ex18-tls-{}", random::<u32>()));
+    tokio::fs::create_dir_all(&dir).await?;
+    let certificates = TestCertificates {
+        ca_cert: dir.join("ca.pem"),
+        server_cert: dir.join("server.pem"),
+        server_key: dir.join("server.key"),
+    };
+    tokio::fs::write(&certificates.ca_cert, ca_cert.pem()).await?;
+    tokio::fs::write(&certificates.server_cert, server_cert.pem()).await?;
+    tokio::fs::write(&certificates.server_key, server_key.serialize_pem()).await?;
+    Ok(certificates)
+}
+
+/// Connects over TLS, runs the protocol handshake and exchanges one message each way
+async fn tls_handshake(
+    certificates: &TestCertificates,
+    client_tls: ClientTls,
+) -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let acceptor = server_acceptor(&certificates.server_cert, &certificates.server_key)?;
+    let server_task = tokio::spawn(async move {
+        let mut server =
+            MessageTcpStream::<Message, _>::from_stream(acceptor.accept(server).await?)?;
+        server.handshake_as_server(&[]).await?;
+        let message = server.read_next_message().await?;
+        server
+            .send_message(&Message::Text("pong".to_string()))
+            .await?;
+        Ok::<_, Error>(message)
+    });
+    let mut client =
+        MessageTcpStream::<Message, _>::from_stream(client_tls.connect(client).await?)?;
+    client.handshake_as_client(&[]).await?;
+    client
+        .send_message(&Message::Text("ping".to_string()))
+        .await?;
+
+    assert!(matches!(
+        client.read_next_message().await?,
+        Some(Message::Text(text)) if text == "pong"
+    ));
+    assert!(matches!(
+        server_task.await??,
+        Some(Message::Text(text)) if text == "ping"
+    ));
+    Ok(())
+}
+
+/// Returns the raw header and the payload of the next frame
+async fn read_raw_frame(tcp_stream: &mut TcpStream) -> Result<(u32, Vec<u8>), Error> {
+    let header = tcp_stream.read_u32_le().await?;
+    let mut payload = vec![0u8; (header & !COMPRESSED_FLAG) as usize];
+    tcp_stream.read_exact(&mut payload).await?;
+    Ok((header, payload))
+}
+
+/// Well compressible payload above `COMPRESSION_THRESHOLD`
+fn compressible_message() -> Message {
+    Message::File(
+        "chat.log".to_string(),
+        "Hello, hello, hello!\n".repeat(1000).into_bytes(),
+    )
+}
+
 #[tokio::test]
 async fn test_image() -> Result<(), Error> {
     let name = "image.png";
@@ -37,8 +151,8 @@ async fn test_image() -> Result<(), Error> {
         .await
         .unwrap();
     match message {
-        Message::Image(vec) => {
-            assert_eq!(CONTENT.as_ref(), vec);
+        Message::Upload(TransferKind::Image, path) => {
+            assert_eq!(full_path, path);
         }
         _ => {
             panic!("Wrong type: {:?}", message);
@@ -55,9 +169,8 @@ async fn test_file() -> Result<(), Error> {
         .await
         .unwrap();
     match message {
-        Message::File(file_name, vec) => {
-            assert_eq!(full_path, file_name);
-            assert_eq!(CONTENT.as_ref(), vec);
+        Message::Upload(TransferKind::File, path) => {
+            assert_eq!(full_path, path);
         }
         _ => {
             panic!("Wrong type: {:?}", message);
@@ -65,3 +178,635 @@ async fn test_file() -> Result<(), Error> {
     }
     Ok(())
 }
+
+async fn create_large_test_file(file_name: &str, len: usize) -> Result<(String, Vec<u8>), Error> {
+    let path = test_file_path(file_name);
+    let content = (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    tokio::fs::write(&path, &content).await?;
+    Ok((path, content))
+}
+
+#[tokio::test]
+async fn test_transfer_round_trip() -> Result<(), Error> {
+    let (path, content) = create_large_test_file("upload.bin", 2 * CHUNK_SIZE + 10).await?;
+    let target = PathBuf::from(format!("{}.received", path));
+    let mut upload = OutgoingTransfer::open(&path, TransferKind::File).await?;
+    let mut download = None;
+    let mut received_path = None;
+    let mut chunks = 0;
+
+    while let Some(transfer) = upload.next_message().await? {
+        match transfer {
+            Transfer::Start {
+                kind, name, size, ..
+            } => {
+                download = Some(IncomingTransfer::create(target.clone(), kind, &name, size).await?);
+            }
+            Transfer::Chunk { offset, data, .. } => {
+                chunks += 1;
+                download
+                    .as_mut()
+                    .unwrap()
+                    .write_chunk(offset, &data)
+                    .await?;
+            }
+            Transfer::End { sha256, .. } => {
+                received_path = Some(download.take().unwrap().finish(&sha256).await?);
+            }
+            other => panic!("Unexpected frame {:?}", other),
+        }
+    }
+
+    assert_eq!(3, chunks);
+    assert_eq!(Some(target.clone()), received_path);
+    assert_eq!(content, tokio::fs::read(&target).await?);
+    delete_test_file(&path).await;
+    delete_test_file(target.to_str().unwrap()).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_resumes_from_acknowledged_offset() -> Result<(), Error> {
+    let (path, content) = create_large_test_file("resumed.bin", 2 * CHUNK_SIZE + 10).await?;
+    let target = PathBuf::from(format!("{}.received", path));
+    let mut upload = OutgoingTransfer::open(&path, TransferKind::File).await?;
+    let Some(Transfer::Start {
+        kind, name, size, ..
+    }) = upload.next_message().await?
+    else {
+        panic!("Transfer must begin with Start");
+    };
+    let mut download = IncomingTransfer::create(target.clone(), kind, &name, size).await?;
+    let Some(Transfer::Chunk { offset, data, .. }) = upload.next_message().await? else {
+        panic!("Start must be followed by a Chunk");
+    };
+    download.write_chunk(offset, &data).await?;
+    // The connection drops here, the second chunk never arrives
+    upload.next_message().await?;
+
+    assert!(!target.exists());
+    assert!(download.part_path().exists());
+    upload.resume_from(download.received()).await?;
+    while let Some(transfer) = upload.next_message().await? {
+        match transfer {
+            Transfer::Chunk { offset, data, .. } => download.write_chunk(offset, &data).await?,
+            Transfer::End { sha256, .. } => {
+                download.finish(&sha256).await?;
+                break;
+            }
+            other => panic!("Unexpected frame {:?}", other),
+        }
+    }
+
+    assert_eq!(content, tokio::fs::read(&target).await?);
+    delete_test_file(&path).await;
+    delete_test_file(target.to_str().unwrap()).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_ignores_repeated_chunk() -> Result<(), Error> {
+    let path = create_test_file("repeated.bin").await?;
+    let target = PathBuf::from(format!("{}.received", path));
+    let mut download =
+        IncomingTransfer::create(target.clone(), TransferKind::File, "repeated.bin", 5).await?;
+
+    download.write_chunk(0, &CONTENT[..3]).await?;
+    download.write_chunk(0, &CONTENT[..3]).await?;
+    download.write_chunk(3, &CONTENT[3..]).await?;
+
+    assert_eq!(5, download.received());
+    download.abort().await;
+    delete_test_file(&path).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_wrong_checksum_is_deleted() -> Result<(), Error> {
+    let path = create_test_file("corrupted.bin").await?;
+    let target = PathBuf::from(format!("{}.received", path));
+    let mut download =
+        IncomingTransfer::create(target.clone(), TransferKind::File, "corrupted.bin", 5).await?;
+    download.write_chunk(0, &CONTENT).await?;
+
+    let result = download.finish("not a checksum").await;
+
+    assert!(matches!(result, Err(TransferError::ChecksumMismatch(_))));
+    assert!(!target.exists());
+    delete_test_file(&path).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_rejects_out_of_order_chunk() -> Result<(), Error> {
+    let path = create_test_file("out_of_order.bin").await?;
+    let target = PathBuf::from(format!("{}.received", path));
+    let mut download =
+        IncomingTransfer::create(target.clone(), TransferKind::File, "out_of_order.bin", 5).await?;
+
+    let result = download.write_chunk(2, &CONTENT[2..]).await;
+
+    assert!(matches!(result, Err(TransferError::UnexpectedOffset(0, 2))));
+    download.abort().await;
+    delete_test_file(&path).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_negotiates_common_features() -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+
+    let server_task =
+        tokio::spawn(async move { server.handshake_as_server(&["a", "b"]).await.map(Vec::from) });
+    let client_features = client.handshake_as_client(&["b", "c"]).await?.to_vec();
+
+    assert_eq!(vec!["b".to_string()], client_features);
+    assert_eq!(vec!["b".to_string()], server_task.await??);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_rejects_other_protocol_version() -> Result<(), Error> {
+    let (mut client, server) = connected_pair().await?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    let mut hello = Hello::new(&[]);
+    hello.version = PROTOCOL_VERSION + 1;
+    send_raw_frame(&mut client, &bincode::serialize(&hello)?).await?;
+
+    let result = server.handshake_as_server(&[]).await;
+
+    assert!(matches!(
+        result,
+        Err(MessageTcpStreamError::VersionMismatch(ours, theirs))
+            if ours == PROTOCOL_VERSION && theirs == PROTOCOL_VERSION + 1
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_rejects_peer_without_handshake() -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+
+    client
+        .send_message(&Message::Login("user".to_string(), "password".to_string()))
+        .await?;
+    let result = server.handshake_as_server(&[]).await;
+
+    assert!(matches!(
+        result,
+        Err(MessageTcpStreamError::HandshakeError(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frame_over_limit_is_rejected_before_payload_arrives() -> Result<(), Error> {
+    let (mut client, server) = connected_pair().await?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    server.set_max_frame_size(16);
+    client.write_all(&u32::MAX.to_le_bytes()).await?;
+
+    let result = server.read_next_message().await;
+
+    assert!(matches!(
+        result,
+        Err(MessageTcpStreamError::FrameTooLarge(size, 16)) if size == !COMPRESSED_FLAG
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frame_over_limit_closes_connection() -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    server.set_max_frame_size(16);
+
+    client
+        .send_message(&Message::Text(
+            "this text is longer than 16 bytes".to_string(),
+        ))
+        .await?;
+    let server_result = server.read_next_message().await;
+    let client_result = client.read_next_message().await;
+
+    assert!(matches!(
+        server_result,
+        Err(MessageTcpStreamError::FrameTooLarge(_, 16))
+    ));
+    assert!(matches!(
+        client_result,
+        Err(MessageTcpStreamError::ConnectionClosed)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frame_within_limit_is_accepted() -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    let text = "exactly fits".to_string();
+    let frame_size = bincode::serialize(&Message::Text(text.clone()))?.len() as u32;
+    server.set_max_frame_size(frame_size);
+
+    client.send_message(&Message::Text(text.clone())).await?;
+
+    assert!(matches!(
+        server.read_next_message().await?,
+        Some(Message::Text(received)) if received == text
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancelled_read_keeps_partial_frame() -> Result<(), Error> {
+    let (mut client, server) = connected_pair().await?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    let text = "split in two".to_string();
+    let payload = bincode::serialize(&Message::Text(text.clone()))?;
+    client
+        .write_all(&(payload.len() as u32).to_le_bytes())
+        .await?;
+    client.write_all(&payload[..3]).await?;
+
+    let cancelled = tokio::time::timeout(
+        std::time::Duration::from_millis(50),
+        server.read_next_message(),
+    )
+    .await;
+    client.write_all(&payload[3..]).await?;
+
+    assert!(cancelled.is_err());
+    assert!(matches!(
+        server.read_next_message().await?,
+        Some(Message::Text(received)) if received == text
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_with_ca_file() -> Result<(), Error> {
+    let certificates = create_test_certificates().await?;
+    let client_tls = ClientTls::new(ServerTrust::CaFile(&certificates.ca_cert), "localhost")?;
+
+    tls_handshake(&certificates, client_tls).await
+}
+
+#[tokio::test]
+async fn test_tls_rejects_wrong_server_name() -> Result<(), Error> {
+    let certificates = create_test_certificates().await?;
+    let client_tls = ClientTls::new(ServerTrust::CaFile(&certificates.ca_cert), "example.com")?;
+
+    assert!(tls_handshake(&certificates, client_tls).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_with_pinned_certificate() -> Result<(), Error> {
+    let certificates = create_test_certificates().await?;
+    let pin = certificate_fingerprint(&certificates.server_cert)?;
+    // The pin alone is enough, no CA and no matching server name needed
+    let client_tls = ClientTls::new(ServerTrust::Pin(&pin.to_uppercase()), "127.0.0.1")?;
+
+    tls_handshake(&certificates, client_tls).await
+}
+
+#[tokio::test]
+async fn test_tls_rejects_wrong_pin() -> Result<(), Error> {
+    let certificates = create_test_certificates().await?;
+    let pin = certificate_fingerprint(&certificates.ca_cert)?;
+    let client_tls = ClientTls::new(ServerTrust::Pin(&pin), "localhost")?;
+
+    assert!(tls_handshake(&certificates, client_tls).await.is_err());
+    Ok(())
+}
+
+/// One instance of every `Message` and `Transfer` variant
+fn all_messages() -> Vec<Message> {
+    let id = "0123456789abcdef".to_string();
+    let messages = vec![
+        Message::File("file.bin".to_string(), vec![0, 1, 2, 255]),
+        Message::Image(vec![137, 80, 78, 71]),
+        Message::Text("Hello, \"world\" ✓".to_string()),
+        Message::Login("user".to_string(), "password".to_string()),
+        Message::Signup("user".to_string(), "password".to_string()),
+        Message::Passwd("new password".to_string()),
+        Message::Join("room".to_string()),
+        Message::Leave("room".to_string()),
+        Message::Rooms,
+        Message::Who,
+        Message::DirectMessage("bob".to_string(), "Hello, bob".to_string()),
+        Message::History(HistoryRange::Older(20)),
+        Message::History(HistoryRange::SinceLogout),
+        Message::Search(Some("release date".to_string())),
+        Message::Search(None),
+        Message::Edit("1b4e28ba".to_string(), "Hello again".to_string()),
+        Message::Delete("1b4e28ba".to_string()),
+        Message::Moderate("bob".to_string(), Moderation::Kick),
+        Message::Moderate("bob".to_string(), Moderation::Ban(Some(3600))),
+        Message::Moderate("bob".to_string(), Moderation::Mute(None)),
+        Message::Moderate("bob".to_string(), Moderation::Unban),
+        Message::Moderate("bob".to_string(), Moderation::Unmute),
+        Message::Transfer(Transfer::Start {
+            id: id.clone(),
+            kind: TransferKind::Image,
+            name: "image.png".to_string(),
+            size: u64::MAX,
+        }),
+        Message::Transfer(Transfer::Chunk {
+            id: id.clone(),
+            offset: 64,
+            data: vec![7; 100],
+        }),
+        Message::Transfer(Transfer::End {
+            id: id.clone(),
+            sha256: "ab".repeat(32),
+        }),
+        Message::Transfer(Transfer::Abort {
+            id: id.clone(),
+            reason: "cancelled".to_string(),
+        }),
+        Message::Transfer(Transfer::Ack {
+            id: id.clone(),
+            offset: 128,
+        }),
+        Message::Transfer(Transfer::ResumeUpload { id: id.clone() }),
+        Message::Transfer(Transfer::ResumeDownload { id, offset: 0 }),
+        Message::Envelope(Envelope {
+            id: "1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string(),
+            sender: "alice".to_string(),
+            sent_at: 1_700_000_000,
+            room: Some("general".to_string()),
+            message: Box::new(Message::Text("Hello".to_string())),
+        }),
+        Message::Envelope(Envelope {
+            id: "1b4e28ba-2fa1-11d2-883f-0016d3cca428".to_string(),
+            sender: "alice".to_string(),
+            sent_at: 1_700_000_001,
+            room: None,
+            message: Box::new(Message::Image(vec![1, 2, 3])),
+        }),
+        Message::Envelope(Envelope {
+            id: "1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string(),
+            sender: "admin".to_string(),
+            sent_at: 1_700_000_002,
+            room: Some("general".to_string()),
+            message: Box::new(Message::Delete(
+                "1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string(),
+            )),
+        }),
+        Message::Mention(Envelope {
+            id: "6fa459ea-ee8a-3ca4-894e-db77e160355e".to_string(),
+            sender: "alice".to_string(),
+            sent_at: 1_700_000_003,
+            room: Some("general".to_string()),
+            message: Box::new(Message::Text("@bob, lunch?".to_string())),
+        }),
+        Message::Upload(TransferKind::File, "/tmp/file.bin".to_string()),
+        Message::Quit,
+    ];
+    // Fails to compile when a variant is added, so that it gets added above as well
+    for message in &messages {
+        match message {
+            Message::File(..)
+            | Message::Image(..)
+            | Message::Text(..)
+            | Message::Login(..)
+            | Message::Signup(..)
+            | Message::Passwd(..)
+            | Message::Join(..)
+            | Message::Leave(..)
+            | Message::Rooms
+            | Message::Who
+            | Message::DirectMessage(..)
+            | Message::History(..)
+            | Message::Search(..)
+            | Message::Edit(..)
+            | Message::Delete(..)
+            | Message::Envelope(..)
+            | Message::Mention(..)
+            | Message::Upload(..)
+            | Message::Quit => {}
+            Message::Transfer(transfer) => match transfer {
+                Transfer::Start { .. }
+                | Transfer::Chunk { .. }
+                | Transfer::End { .. }
+                | Transfer::Abort { .. }
+                | Transfer::Ack { .. }
+                | Transfer::ResumeUpload { .. }
+                | Transfer::ResumeDownload { .. } => {}
+            },
+            Message::Moderate(_, moderation) => match moderation {
+                Moderation::Kick
+                | Moderation::Ban(_)
+                | Moderation::Mute(_)
+                | Moderation::Unban
+                | Moderation::Unmute => {}
+            },
+        }
+    }
+    messages
+}
+
+#[test]
+fn test_codecs_round_trip_every_message() -> Result<(), Error> {
+    for kind in CodecKind::ALL {
+        let codec = kind.codec::<Message>();
+        for message in all_messages() {
+            let decoded = codec.decode(&codec.encode(&message)?)?;
+            assert_eq!(message, decoded, "codec {}", kind);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_codec_names() -> Result<(), Error> {
+    for kind in CodecKind::ALL {
+        assert_eq!(kind, kind.name().parse()?);
+        assert_eq!(kind.feature(), format!("{}{}", CODEC_FEATURE_PREFIX, kind));
+    }
+    assert!("yaml".parse::<CodecKind>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_json_codec_is_plain_json() -> Result<(), Error> {
+    let codec = CodecKind::Json.codec::<Message>();
+    let encoded = codec.encode(&Message::Text("hi".to_string()))?;
+
+    assert_eq!(r#"{"Text":"hi"}"#, String::from_utf8(encoded)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_negotiates_codec() -> Result<(), Error> {
+    let server_features = CodecKind::ALL.map(|kind| kind.feature());
+    for kind in CodecKind::ALL {
+        let (client, server) = connected_pair().await?;
+        let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+        let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+
+        let server_task = tokio::spawn(async move {
+            server.handshake_as_server(&server_features).await?;
+            let mut received = Vec::new();
+            for _ in all_messages() {
+                received.push(server.read_next_message().await?.unwrap());
+            }
+            Ok::<_, Error>((server.codec(), received))
+        });
+        client.handshake_as_client(&[kind.feature()]).await?;
+        for message in all_messages() {
+            client.send_message(&message).await?;
+        }
+        let (server_codec, received) = server_task.await??;
+
+        assert_eq!(kind, client.codec());
+        assert_eq!(kind, server_codec);
+        assert_eq!(all_messages(), received);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_falls_back_to_bincode() -> Result<(), Error> {
+    let (client, server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+
+    let server_task = tokio::spawn(async move {
+        server
+            .handshake_as_server(&[CodecKind::Json.feature()])
+            .await?;
+        Ok::<_, Error>(server.codec())
+    });
+    client
+        .handshake_as_client(&[CodecKind::MessagePack.feature()])
+        .await?;
+
+    assert_eq!(CodecKind::Bincode, client.codec());
+    assert_eq!(CodecKind::Bincode, server_task.await??);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compression_round_trip() -> Result<(), Error> {
+    for kind in CompressionKind::ALL {
+        let (client, server) = connected_pair().await?;
+        let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+        let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+
+        let server_task = tokio::spawn(async move {
+            server
+                .handshake_as_server(&CompressionKind::ALL.map(|kind| kind.feature()))
+                .await?;
+            let large = server.read_next_message().await?;
+            let small = server.read_next_message().await?;
+            Ok::<_, Error>((server.compression(), large, small, server))
+        });
+        client.handshake_as_client(&[kind.feature()]).await?;
+        client.send_message(&compressible_message()).await?;
+        client
+            .send_message(&Message::Text("hi".to_string()))
+            .await?;
+        let (server_compression, large, small, mut server) = server_task.await??;
+
+        assert_eq!(Some(kind), client.compression());
+        assert_eq!(Some(kind), server_compression);
+        assert_eq!(Some(compressible_message()), large);
+        assert_eq!(Some(Message::Text("hi".to_string())), small);
+        let saved = client.take_compression_savings();
+        assert!(saved > 0);
+        assert_eq!(saved, server.take_compression_savings());
+        assert_eq!(0, client.take_compression_savings());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_frames_are_flagged() -> Result<(), Error> {
+    let (client, mut server) = connected_pair().await?;
+    let mut client = MessageTcpStream::<Message>::from_tcp_stream(client)?;
+
+    let client_task = tokio::spawn(async move {
+        client
+            .handshake_as_client(&[CompressionKind::Zstd.feature()])
+            .await?;
+        client.send_message(&compressible_message()).await?;
+        client
+            .send_message(&Message::Text("hi".to_string()))
+            .await?;
+        Ok::<_, Error>(())
+    });
+    read_raw_frame(&mut server).await?;
+    let reply = HelloReply::Accepted {
+        version: PROTOCOL_VERSION,
+        features: vec![CompressionKind::Zstd.feature().to_string()],
+    };
+    send_raw_frame(&mut server, &bincode::serialize(&reply)?).await?;
+    let (large_header, large_payload) = read_raw_frame(&mut server).await?;
+    let (small_header, small_payload) = read_raw_frame(&mut server).await?;
+    client_task.await??;
+
+    assert_ne!(0, large_header & COMPRESSED_FLAG);
+    assert!(large_payload.len() < bincode::serialize(&compressible_message())?.len());
+    let decompressed = CompressionKind::Zstd.decompress(&large_payload, u32::MAX >> 1)?;
+    assert_eq!(compressible_message(), bincode::deserialize(&decompressed)?);
+    assert_eq!(0, small_header & COMPRESSED_FLAG);
+    assert!(small_payload.len() < COMPRESSION_THRESHOLD);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_frame_without_negotiation_is_rejected() -> Result<(), Error> {
+    let (mut client, server) = connected_pair().await?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    let payload = CompressionKind::Zstd.compress(&bincode::serialize(&compressible_message())?)?;
+    client
+        .write_all(&(payload.len() as u32 | COMPRESSED_FLAG).to_le_bytes())
+        .await?;
+    client.write_all(&payload).await?;
+
+    assert!(matches!(
+        server.read_next_message().await,
+        Err(MessageTcpStreamError::CompressionError(
+            CompressionError::NotNegotiated
+        ))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_decompressed_frame_over_limit_is_rejected() -> Result<(), Error> {
+    let (mut client, server) = connected_pair().await?;
+    let mut server = MessageTcpStream::<Message>::from_tcp_stream(server)?;
+    server.set_max_frame_size(64 * 1024);
+    let server_task = tokio::spawn(async move {
+        server
+            .handshake_as_server(&[CompressionKind::Deflate.feature()])
+            .await?;
+        Ok::<_, Error>(server.read_next_message().await)
+    });
+
+    let hello = Hello::new(&[CompressionKind::Deflate.feature()]);
+    send_raw_frame(&mut client, &bincode::serialize(&hello)?).await?;
+    read_raw_frame(&mut client).await?;
+    let bomb = CompressionKind::Deflate.compress(&vec![0u8; 1024 * 1024])?;
+    client
+        .write_all(&(bomb.len() as u32 | COMPRESSED_FLAG).to_le_bytes())
+        .await?;
+    client.write_all(&bomb).await?;
+
+    assert!(matches!(
+        server_task.await??,
+        Err(MessageTcpStreamError::CompressionError(
+            CompressionError::DecompressedFrameTooLarge(_)
+        ))
+    ));
+    Ok(())
+}